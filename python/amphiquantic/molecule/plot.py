@@ -16,7 +16,7 @@ def plot_molecule(filename, explicit_bonds=True):
     print(len(atom_types))
     if not explicit_bonds:
         print("Ignoring explicit bonds and determining bonds automatically.")
-        bonds, _, _ = pdb_file.determine_bonds()
+        bonds = pdb_file.determine_bonds().bonds
     else:
         bonds = pdb_file.bonds
         print(bonds)
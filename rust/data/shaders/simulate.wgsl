@@ -1,42 +1,198 @@
 struct SimulationParams {
-    step_size: f32;
-    max_steps: u32;
-    process_type: u32;
+    step_size: f32,
+    atom_count: u32,
+    process_type: u32,
+    periodic: u32,
+    // Columns are the box vectors; both matrices are unused unless `periodic` is set
+    cell_matrix: mat3x3<f32>,
+    cell_inverse: mat3x3<f32>,
+    term_count: u32,
+    cutoff: f32,
+    // 0 for reaction field, 1 for shifted force
+    electrostatics: u32,
+    // 0 for Lorentz-Berthelot, 1 for geometric
+    mixing: u32,
+    k_rf: f32,
+    c_rf: f32,
+};
+
+// One bond (kind 0), angle (kind 1) or torsion (kind 2); see `BondedTerm` in forcefield.rs
+struct BondedTerm {
+    kind: u32,
+    atoms: array<u32, 4>,
+    k: f32,
+    equilibrium: f32,
+    periodicity: f32,
 };
 
 
-@group(0) @binding(0) var<storage, read_write> coords: array<vec3<f32>>;
+// Positions at the start of the step in xyz; w is padding
+@group(0) @binding(0) var<storage, read> coords: array<vec4<f32>>;
 @group(0) @binding(1) var<uniform> params: SimulationParams;
-@group(0) @binding(2) var<storage, read> atom_types: array<u32>;
-@group(0) @binding(3) var<storage, read> bond_indices: array<u32>;
+// Atomic numbers, or 0 for an unknown element
+@group(0) @binding(2) var<storage, read> element_ids: array<u32>;
+// Offsets for each atom followed by the `4 · term + slot` of its bonded terms they point into
+@group(0) @binding(3) var<storage, read> atom_terms: array<u32>;
+@group(0) @binding(4) var<storage, read> bonded_terms: array<BondedTerm>;
+// Offsets for each atom followed by the excluded partners they point into
+@group(0) @binding(5) var<storage, read> exclusions: array<u32>;
+// Sigma, epsilon, charge and mass of each atom
+@group(0) @binding(6) var<storage, read> atom_params: array<vec4<f32>>;
+// Force on each atom in kcal/(mol·Å) at `coords`, in xyz
+@group(0) @binding(8) var<storage, read_write> forces: array<vec4<f32>>;
+// Positions at the end of the step, which become `coords` for the next one
+@group(0) @binding(9) var<storage, read_write> next_coords: array<vec4<f32>>;
+
+// Velocity of each atom in Å/ps, in xyz
+@group(0) @binding(7) var<storage, read_write> velocities: array<vec4<f32>>;
+
+const COULOMB: f32 = 332.0637;
+// amu·Å²/ps² per kcal/mol; see `MD_UNITS` in dynamics.rs
+const MD_UNITS: f32 = 418.4;
+
+fn minimum_image(d: vec3<f32>) -> vec3<f32> {
+    if (params.periodic == 0u) {
+        return d;
+    }
+    let f = params.cell_inverse * d;
+    return params.cell_matrix * (f - round(f));
+}
+
+// Mirrors `PairPotential::energy_force` in forcefield.rs
+fn pair_force(index: u32, other: u32) -> vec3<f32> {
+    let d = minimum_image(coords[index].xyz - coords[other].xyz);
+    let r2 = dot(d, d);
+    let rc = params.cutoff;
+    if (r2 > rc * rc) {
+        return vec3<f32>(0.0, 0.0, 0.0);
+    }
+    let a = atom_params[index];
+    let b = atom_params[other];
+    let sigma = select((a.x + b.x) * 0.5, sqrt(a.x * b.x), params.mixing == 1u);
+    let epsilon = sqrt(a.y * b.y);
+    let s2 = sigma * sigma / r2;
+    let s6 = s2 * s2 * s2;
+    var force = 24.0 * epsilon * (2.0 * s6 * s6 - s6) / r2;
+
+    let qq = COULOMB * a.z * b.z;
+    if (qq != 0.0) {
+        let r = sqrt(r2);
+        if (params.electrostatics == 0u) {
+            force = force + qq * (1.0 / (r2 * r) - 2.0 * params.k_rf);
+        } else {
+            force = force + qq * (1.0 / r2 - 1.0 / (rc * rc)) / r;
+        }
+    }
+    return force * d;
+}
+
+fn is_excluded(index: u32, other: u32) -> bool {
+    for (var e: u32 = exclusions[index]; e < exclusions[index + 1u]; e = e + 1u) {
+        if (exclusions[e] == other) {
+            return true;
+        }
+    }
+    return false;
+}
+
+// Forces on the atoms of one term, in the order of its atom slots
+fn term_forces(t: BondedTerm) -> array<vec3<f32>, 4> {
+    var f: array<vec3<f32>, 4>;
+    if (t.kind == 0u) {
+        let d = minimum_image(coords[t.atoms[1]].xyz - coords[t.atoms[0]].xyz);
+        let r = length(d);
+        f[0] = d * (2.0 * t.k * (r - t.equilibrium) / r);
+        f[1] = -f[0];
+    } else if (t.kind == 1u) {
+        let a = minimum_image(coords[t.atoms[0]].xyz - coords[t.atoms[1]].xyz);
+        let b = minimum_image(coords[t.atoms[2]].xyz - coords[t.atoms[1]].xyz);
+        let ra = length(a);
+        let rb = length(b);
+        let c = clamp(dot(a, b) / (ra * rb), -1.0, 1.0);
+        let s = max(sqrt(1.0 - c * c), 1e-6);
+        let de = 2.0 * t.k * (acos(c) - t.equilibrium);
+        f[0] = (b / rb - a * (c / ra)) * (de / (ra * s));
+        f[2] = (a / ra - b * (c / rb)) * (de / (rb * s));
+        f[1] = -(f[0] + f[2]);
+    } else {
+        let r_ij = minimum_image(coords[t.atoms[0]].xyz - coords[t.atoms[1]].xyz);
+        let r_kj = minimum_image(coords[t.atoms[2]].xyz - coords[t.atoms[1]].xyz);
+        let r_kl = minimum_image(coords[t.atoms[2]].xyz - coords[t.atoms[3]].xyz);
+        let m = cross(r_ij, r_kj);
+        let n = cross(r_kj, r_kl);
+        let iprm = dot(m, m);
+        let iprn = dot(n, n);
+        if (iprm < 1e-12 || iprn < 1e-12) {
+            return f;
+        }
+        var phi = acos(clamp(dot(m, n) / sqrt(iprm * iprn), -1.0, 1.0));
+        if (dot(r_ij, n) < 0.0) {
+            phi = -phi;
+        }
+        let ddphi = -t.k * t.periodicity * sin(t.periodicity * phi - t.equilibrium);
+        let nrkj2 = dot(r_kj, r_kj);
+        let nrkj = sqrt(nrkj2);
+        f[0] = m * (-ddphi * nrkj / iprm);
+        f[3] = n * (ddphi * nrkj / iprn);
+        let s = f[0] * (dot(r_ij, r_kj) / nrkj2) - f[3] * (dot(r_kl, r_kj) / nrkj2);
+        f[1] = s - f[0];
+        f[2] = -(f[3] + s);
+    }
+    return f;
+}
+
+// Mirrors `bonded_forces` in forcefield.rs, keeping only the force on `index`
+fn bonded_forces(index: u32) -> vec3<f32> {
+    var force = vec3<f32>(0.0, 0.0, 0.0);
+    for (var e: u32 = atom_terms[index]; e < atom_terms[index + 1u]; e = e + 1u) {
+        var f = term_forces(bonded_terms[atom_terms[e] / 4u]);
+        force = force + f[atom_terms[e] % 4u];
+    }
+    return force;
+}
 
-fn lennard_jones_force(pos1: vec3<f32>, pos2: vec3<f32>) -> vec3<f32> {
-    let r = distance(pos1, pos2);
-    let r_inv = 1.0 / r;
-    let r_inv6 = r_inv * r_inv * r_inv * r_inv * r_inv * r_inv;
-    let r_inv12 = r_inv6 * r_inv6;
-    let force_scalar = 24.0 * (2.0 * r_inv12 - r_inv6) * r_inv;
-    return force_scalar * (pos1 - pos2);
+fn total_force(index: u32) -> vec3<f32> {
+    var force: vec3<f32> = vec3<f32>(0.0, 0.0, 0.0);
+    for (var i: u32 = 0u; i < params.atom_count; i = i + 1u) {
+        if (i != index && !is_excluded(index, i)) {
+            force = force + pair_force(index, i);
+        }
+    }
+    return force + bonded_forces(index);
 }
 
+// The host dispatches each kernel over every atom in turn, so every force in a step
+// sees the same snapshot of the coordinates.
 @compute @workgroup_size(64)
-fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+fn compute_forces(@builtin(global_invocation_id) id: vec3<u32>) {
     let index = id.x;
-    if (index >= arrayLength(&coords)) {
+    if (index >= params.atom_count) {
         return;
     }
+    forces[index] = vec4<f32>(total_force(index), 0.0);
+}
 
-    var step: u32 = 0u;
-    while (step < params.max_steps) {
-        var force: vec3<f32> = vec3<f32>(0.0, 0.0, 0.0);
-
-        for (var i: u32 = 0u; i < arrayLength(&coords); i = i + 1u) {
-            if (i != index) {
-                force = force + lennard_jones_force(coords[index], coords[i]);
-            }
-        }
+// Velocity Verlet with a time step of `step_size` ps: `advance` kicks for half a step
+// and drifts, then `settle` kicks for the other half with the forces at the new positions.
+@compute @workgroup_size(64)
+fn advance(@builtin(global_invocation_id) id: vec3<u32>) {
+    let index = id.x;
+    if (index >= params.atom_count) {
+        return;
+    }
+    let dt = params.step_size;
+    let acceleration = forces[index].xyz * (MD_UNITS / atom_params[index].w);
+    let velocity = velocities[index].xyz + acceleration * (0.5 * dt);
+    velocities[index] = vec4<f32>(velocity, 0.0);
+    next_coords[index] = vec4<f32>(coords[index].xyz + velocity * dt, 0.0);
+}
 
-        coords[index] = coords[index] + force * params.step_size;
-        step = step + 1u;
+@compute @workgroup_size(64)
+fn settle(@builtin(global_invocation_id) id: vec3<u32>) {
+    let index = id.x;
+    if (index >= params.atom_count) {
+        return;
     }
+    let acceleration = forces[index].xyz * (MD_UNITS / atom_params[index].w);
+    velocities[index] = vec4<f32>(velocities[index].xyz + acceleration * (0.5 * params.step_size), 0.0);
 }
@@ -1,6 +1,88 @@
+use std::ops::Range;
 
+use pyo3::prelude::*;
 
+/// The fixed-column fields of an ATOM/HETATM record, apart from the coordinates and element,
+/// which `PdbFilePy` keeps in `coords` and `atom_types`.
+#[pyclass]
+#[derive(Clone, Debug, PartialEq)]
+pub struct AtomRecord {
+    #[pyo3(get, set)]
+    pub hetatm: bool,
+    #[pyo3(get, set)]
+    pub serial: usize,
+    #[pyo3(get, set)]
+    pub name: String,
+    #[pyo3(get, set)]
+    pub alt_loc: String,
+    #[pyo3(get, set)]
+    pub res_name: String,
+    #[pyo3(get, set)]
+    pub chain_id: String,
+    #[pyo3(get, set)]
+    pub res_seq: i32,
+    #[pyo3(get, set)]
+    pub i_code: String,
+    #[pyo3(get, set)]
+    pub occupancy: f64,
+    #[pyo3(get, set)]
+    pub temp_factor: f64,
+    #[pyo3(get, set)]
+    pub segment_id: String,
+    #[pyo3(get, set)]
+    pub charge: String,
+}
 
+#[pymethods]
+impl AtomRecord {
+    #[new]
+    #[pyo3(signature = (serial, name, res_name="MOL", res_seq=1, chain_id="", hetatm=false))]
+    pub fn new(serial: usize, name: &str, res_name: &str, res_seq: i32, chain_id: &str, hetatm: bool) -> Self {
+        AtomRecord {
+            hetatm,
+            serial,
+            name: name.to_string(),
+            alt_loc: String::new(),
+            res_name: res_name.to_string(),
+            chain_id: chain_id.to_string(),
+            res_seq,
+            i_code: String::new(),
+            occupancy: 1.0,
+            temp_factor: 0.0,
+            segment_id: String::new(),
+            charge: String::new(),
+        }
+    }
 
+    fn __repr__(&self) -> String {
+        format!(
+            "AtomRecord({} {} {} {}{}{})",
+            if self.hetatm { "HETATM" } else { "ATOM" },
+            self.serial,
+            self.name,
+            self.res_name,
+            self.chain_id,
+            self.res_seq
+        )
+    }
+}
 
+impl AtomRecord {
+    /// The record written for an atom that only has an element, as every atom was before records were kept.
+    pub fn placeholder(serial: usize, element: &str) -> Self {
+        AtomRecord::new(serial, element, "MOL", 1, "", false)
+    }
+}
 
+/// Runs of consecutive atoms sharing a chain, residue number, insertion code and residue name.
+pub(crate) fn residue_ranges(atoms: &[AtomRecord]) -> Vec<Range<usize>> {
+    let key = |a: &AtomRecord| (a.chain_id.clone(), a.res_seq, a.i_code.clone(), a.res_name.clone());
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    for (i, atom) in atoms.iter().enumerate() {
+        match ranges.last_mut() {
+            Some(range) if key(&atoms[range.start]) == key(atom) => range.end = i + 1,
+            _ => ranges.push(i..i + 1),
+        }
+    }
+    ranges
+}
@@ -1,42 +1,277 @@
 use std::collections::{HashMap, HashSet};
 
-use crate::utilities::bonds::{BOND_DISTANCES, AVG_BOND_DISTANCES};
+use pyo3::prelude::*;
 
+use crate::atom::{residue_ranges, AtomRecord};
+use crate::neighbors::{self, CellList};
+use crate::perception::Bond;
+use crate::unit_cell::UnitCell;
+use crate::utilities::atom::ElementIds;
+use crate::utilities::bonds::{bond_table, pair_entry, PairTable};
+use crate::utilities::element::periodic_table;
+use crate::utilities::residues::{residue_templates, ResidueTemplate};
+use crate::utilities::DataError;
 
+// Atoms closer than this, in Å, overlap rather than bond
+const MIN_COVALENT_DISTANCE: f64 = 0.4;
 
-pub(crate) fn determine_bonds(coords: Vec<(f64, f64, f64)>, atom_types: Vec<String>) -> (Vec<(usize, usize)>, Vec<(usize, usize)>, HashSet<(String, String)>) {
-    let bond_distances = BOND_DISTANCES.iter().map(|(k, v)| (k.clone(), v.clone())).collect::<HashMap<String, (f64, f64)>>();
-    let avg_bond_distances = AVG_BOND_DISTANCES.iter().map(|(k, v)| (k.clone(), *v)).collect::<HashMap<String, f64>>();
+/// What made a pair of atoms count as bonded.
+#[pyclass(eq, eq_int)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BondRule {
+    /// Within the range `bond_distances.yml` gives the element pair.
+    Table,
+    /// An untabulated pair closer than the sum of their covalent radii plus the tolerance.
+    Covalent,
+    /// A bond of the residue's template in `residues.yml`.
+    Template,
+    /// A peptide, phosphodiester or disulfide bond between residues.
+    Link,
+}
+
+/// Bonds with the rule that found each, pairs near the average tabulated bond length, and
+/// element pairs that neither rule covers. `typed_bonds` follows `bonds`, with the orders
+/// templates give; bonds found by distance are single.
+#[pyclass]
+#[derive(Clone, Default)]
+pub struct BondSearch {
+    #[pyo3(get)]
+    pub bonds: Vec<(usize, usize)>,
+    #[pyo3(get)]
+    pub rules: Vec<BondRule>,
+    #[pyo3(get)]
+    pub typed_bonds: Vec<Bond>,
+    #[pyo3(get)]
+    pub near_bonds: Vec<(usize, usize)>,
+    #[pyo3(get)]
+    pub missing: HashSet<(String, String)>,
+}
+
+#[pymethods]
+impl BondSearch {
+    fn __repr__(&self) -> String {
+        let count = |rule| self.rules.iter().filter(|&&r| r == rule).count();
+        format!(
+            "BondSearch(bonds={}, templated={}, covalent={}, missing={})",
+            self.bonds.len(),
+            count(BondRule::Template) + count(BondRule::Link),
+            count(BondRule::Covalent),
+            self.missing.len()
+        )
+    }
+}
+
+impl BondSearch {
+    fn push(&mut self, bond: Bond, rule: BondRule) {
+        self.bonds.push(bond.atoms);
+        self.rules.push(rule);
+        self.typed_bonds.push(bond);
+    }
+}
+
+/// Element pairs in `bond_distances.yml` bond within their tabulated range. Any other pair
+/// of known elements bonds closer than the sum of their covalent radii plus `tolerance` Å.
+/// Only pairs within the longest bond either rule allows are considered, so `missing` lists
+/// the pairs of unknown elements that came within that distance of each other. With a
+/// `cell`, bonds across the periodic boundaries are found too.
+pub(crate) fn determine_bonds(coords: Vec<(f64, f64, f64)>, atom_types: Vec<String>, cell: Option<UnitCell>, tolerance: f64) -> Result<BondSearch, DataError> {
+    let (table, elements_table) = (bond_table()?, periodic_table()?);
+    let elements = ElementIds::new(&atom_types);
+    let bond_distances = PairTable::new(&elements.symbols, |a, b| pair_entry(&table.distances, a, b).copied());
+    let avg_bond_distances = PairTable::new(&elements.symbols, |a, b| pair_entry(&table.averages, a, b).copied());
+    let covalent_distances = PairTable::new(&elements.symbols, |a, b| {
+        Some(elements_table.get(a)?.covalent_radius + elements_table.get(b)?.covalent_radius + tolerance)
+    });
+    let max_covalent = elements.symbols.iter().filter_map(|s| elements_table.get(s)).map(|e| 2.0 * e.covalent_radius + tolerance).fold(0.0, f64::max);
 
     let threshold = 0.2;
-    let mut bonds_vec = Vec::new();
-    let mut missing = HashSet::new();
-    let mut near_bonds = Vec::new();
-    let num_atoms = coords.len();
-
-    for i in 0..num_atoms {
-        for j in i + 1..num_atoms {
-            let pair = format!("{}-{}", atom_types[i], atom_types[j]);
-            let dist = ((coords[i].0 - coords[j].0).powi(2) +
-                        (coords[i].1 - coords[j].1).powi(2) +
-                        (coords[i].2 - coords[j].2).powi(2)).sqrt();
-    
-            if let Some((min_dist, max_dist)) = bond_distances.get(&pair).or_else(|| bond_distances.get(&format!("{}-{}", atom_types[j], atom_types[i]))) {
-                if *min_dist <= dist && dist <= *max_dist {
-                    bonds_vec.push((i, j));
+    let mut search = BondSearch::default();
+
+    let cells = CellList::new(&coords, table.max_distance.max(max_covalent), cell);
+    cells.for_each_pair(&coords, |i, j, dist| {
+        let (a, b) = (elements.ids[i], elements.ids[j]);
+        if let Some((min_dist, max_dist)) = bond_distances.get(a, b) {
+            if min_dist <= dist && dist <= max_dist {
+                search.push(Bond::new((i, j), 1, false), BondRule::Table);
+            }
+        } else if let Some(typ) = avg_bond_distances.get(a, b) {
+            if (dist - typ).abs() <= threshold {
+                search.near_bonds.push((i, j));
+            }
+        } else if let Some(max_dist) = covalent_distances.get(a, b) {
+            if MIN_COVALENT_DISTANCE <= dist && dist < max_dist {
+                search.push(Bond::new((i, j), 1, false), BondRule::Covalent);
+            }
+        } else if dist <= table.max_distance {
+            search.missing.insert((atom_types[i].clone(), atom_types[j].clone()));
+        }
+    });
+
+    Ok(search)
+}
+
+// Atom indices of a residue by atom name, several for alternate locations
+type NameIndex<'a> = HashMap<&'a str, Vec<usize>>;
+
+// Alternate locations only bond among themselves and to atoms that have none
+fn same_conformer(a: &AtomRecord, b: &AtomRecord) -> bool {
+    a.alt_loc.is_empty() || b.alt_loc.is_empty() || a.alt_loc == b.alt_loc
+}
+
+/// Bond every residue that `residues.yml` has a template for by its atom names, and join
+/// consecutive polymer residues of a chain by peptide or phosphodiester bonds, and
+/// cysteines by disulfides, where the atoms are within covalent reach. Atoms of other
+/// residues, of residues whose template has no bonds such as ions, and atoms a template
+/// does not name, are left to `determine_bonds`.
+pub(crate) fn determine_template_bonds(
+    coords: &[(f64, f64, f64)],
+    atom_types: &[String],
+    atoms: &[AtomRecord],
+    cell: Option<UnitCell>,
+    tolerance: f64,
+) -> Result<BondSearch, DataError> {
+    if atoms.len() != coords.len() {
+        return determine_bonds(coords.to_vec(), atom_types.to_vec(), cell, tolerance);
+    }
+    let (templates, elements_table) = (residue_templates()?, periodic_table()?);
+    let in_reach = |i: usize, j: usize| {
+        let dist = match cell {
+            Some(cell) => cell.distance(coords[i], coords[j]),
+            None => neighbors::distance(coords[i], coords[j]),
+        };
+        let radius = |k: usize| elements_table.get(&atom_types[k]).map(|e| e.covalent_radius);
+        matches!((radius(i), radius(j)), (Some(a), Some(b)) if MIN_COVALENT_DISTANCE <= dist && dist < a + b + tolerance)
+    };
+    let mut search = BondSearch::default();
+    let link = |search: &mut BondSearch, first: &[usize], second: &[usize]| {
+        for &i in first {
+            for &j in second {
+                if same_conformer(&atoms[i], &atoms[j]) && in_reach(i, j) {
+                    search.push(Bond::new((i.min(j), i.max(j)), 1, false), BondRule::Link);
                 }
+            }
+        }
+    };
+
+    let mut untemplated = vec![false; coords.len()];
+    let mut previous: Option<(&ResidueTemplate, &str, NameIndex)> = None;
+    let mut sulfurs = Vec::new();
+    for range in residue_ranges(atoms) {
+        let first = &atoms[range.start];
+        // Ions have no template bonds, but bond to the residues they coordinate by distance
+        let Some(template) = templates.get(&first.res_name).filter(|t| !t.bonds.is_empty()) else {
+            untemplated[range].fill(true);
+            previous = None;
+            continue;
+        };
+        let mut names = NameIndex::new();
+        for i in range {
+            if template.atoms.contains(&atoms[i].name) {
+                names.entry(atoms[i].name.as_str()).or_default().push(i);
             } else {
-                let typical_dist_key = avg_bond_distances.get(&pair).or_else(|| avg_bond_distances.get(&format!("{}-{}", atom_types[j], atom_types[i])));
-                if let Some(&typ) = typical_dist_key {
-                    if (dist - typ).abs() <= threshold {
-                        near_bonds.push((i, j));
+                untemplated[i] = true;
+            }
+        }
+        let named = |names: &NameIndex, name: &str| names.get(name).cloned().unwrap_or_default();
+
+        for bond in &template.bonds {
+            for i in named(&names, &bond.atoms.0) {
+                for j in named(&names, &bond.atoms.1) {
+                    if same_conformer(&atoms[i], &atoms[j]) {
+                        search.push(Bond::new((i.min(j), i.max(j)), bond.order, bond.aromatic), BondRule::Template);
                     }
-                } else {
-                    missing.insert((atom_types[i].clone(), atom_types[j].clone()));
                 }
             }
         }
+        if let Some((last, chain, last_names)) = &previous {
+            if let (true, Some(kind), Some(next_kind)) = (*chain == first.chain_id, last.linking, template.linking) {
+                if kind == next_kind {
+                    let (a, b) = kind.atoms();
+                    link(&mut search, &named(last_names, a), &named(&names, b));
+                }
+            }
+        }
+        if first.res_name == "CYS" {
+            sulfurs.extend(named(&names, "SG"));
+        }
+        previous = Some((template, &first.chain_id, names));
+    }
+    for (k, &i) in sulfurs.iter().enumerate() {
+        link(&mut search, &[i], &sulfurs[k + 1..]);
+    }
+
+    if untemplated.iter().any(|&u| u) {
+        let by_distance = determine_bonds(coords.to_vec(), atom_types.to_vec(), cell, tolerance)?;
+        for (bond, rule) in by_distance.typed_bonds.into_iter().zip(by_distance.rules) {
+            if untemplated[bond.atoms.0] || untemplated[bond.atoms.1] {
+                search.push(bond, rule);
+            }
+        }
+        search.near_bonds = by_distance.near_bonds.into_iter().filter(|&(i, j)| untemplated[i] || untemplated[j]).collect();
+        search.missing = by_distance.missing;
+    }
+    Ok(search)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn untabulated_pairs_bond_by_covalent_radii() {
+        // A phosphate oxygen 1.6 Å from P, a methyl C-H and a pair of unknown atoms
+        let coords = vec![(0.0, 0.0, 0.0), (1.6, 0.0, 0.0), (5.0, 0.0, 0.0), (6.09, 0.0, 0.0), (9.0, 0.0, 0.0), (10.0, 0.0, 0.0)];
+        let atom_types: Vec<String> = ["P", "O", "C", "H", "Xx", "Xx"].iter().map(|t| t.to_string()).collect();
+        let search = determine_bonds(coords, atom_types, None, 0.45).unwrap();
+        let mut found: Vec<_> = search.bonds.iter().copied().zip(search.rules.iter().copied()).collect();
+        found.sort_by_key(|&(pair, _)| pair);
+        assert_eq!(found, vec![((0, 1), BondRule::Covalent), ((2, 3), BondRule::Table)]);
+        assert!(search.missing.contains(&("Xx".to_string(), "Xx".to_string())));
+    }
+
+    #[test]
+    fn residues_bond_by_template_and_ligands_by_distance() {
+        let sites = [
+            ("N", "N", "GLY", 1, (0.0, 0.0, 0.0)),
+            ("CA", "C", "GLY", 1, (1.46, 0.0, 0.0)),
+            ("C", "C", "GLY", 1, (2.0, 1.42, 0.0)),
+            ("O", "O", "GLY", 1, (1.25, 2.39, 0.0)),
+            ("N", "N", "GLY", 2, (3.33, 1.55, 0.0)),
+            ("CA", "C", "GLY", 2, (3.95, 2.86, 0.0)),
+            ("C", "C", "GLY", 2, (5.45, 2.80, 0.0)),
+            ("O", "O", "GLY", 2, (6.1, 1.76, 0.0)),
+            ("OXT", "O", "GLY", 2, (6.0, 3.95, 0.0)),
+            ("C1", "C", "LIG", 3, (20.0, 0.0, 0.0)),
+            ("O1", "O", "LIG", 3, (21.43, 0.0, 0.0)),
+            ("O", "O", "HOH", 4, (30.0, 0.0, 0.0)),
+            ("H1", "H", "HOH", 4, (30.96, 0.0, 0.0)),
+            ("H2", "H", "HOH", 4, (29.76, 0.93, 0.0)),
+            ("CB", "C", "CYS", 5, (40.0, 0.0, 0.0)),
+            ("SG", "S", "CYS", 5, (41.81, 0.0, 0.0)),
+            ("ZN", "Zn", "ZN", 6, (42.6, 2.2, 0.0)),
+        ];
+        let coords: Vec<_> = sites.iter().map(|s| s.4).collect();
+        let atom_types: Vec<String> = sites.iter().map(|s| s.1.to_string()).collect();
+        let atoms: Vec<AtomRecord> = sites.iter().enumerate().map(|(i, s)| AtomRecord::new(i + 1, s.0, s.2, s.3, "A", !["GLY", "CYS"].contains(&s.2))).collect();
+
+        let search = determine_template_bonds(&coords, &atom_types, &atoms, None, 0.45).unwrap();
+        let mut found: Vec<_> = search.typed_bonds.iter().map(|b| (b.atoms, b.order)).zip(search.rules.iter().copied()).collect();
+        found.sort_by_key(|&(bond, _)| bond);
+        let expected = vec![
+            (((0, 1), 1), BondRule::Template),
+            (((1, 2), 1), BondRule::Template),
+            (((2, 3), 2), BondRule::Template),
+            (((2, 4), 1), BondRule::Link),
+            (((4, 5), 1), BondRule::Template),
+            (((5, 6), 1), BondRule::Template),
+            (((6, 7), 2), BondRule::Template),
+            (((6, 8), 1), BondRule::Template),
+            (((9, 10), 1), BondRule::Table),
+            (((11, 12), 1), BondRule::Template),
+            (((11, 13), 1), BondRule::Template),
+            (((14, 15), 1), BondRule::Template),
+            (((15, 16), 1), BondRule::Covalent),
+        ];
+        assert_eq!(found, expected);
     }
-    
-    (bonds_vec, near_bonds, missing)
 }
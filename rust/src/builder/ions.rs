@@ -1,69 +1,434 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use rand::seq::SliceRandom;
 use rand::Rng;
-use std::collections::HashMap;
+use rayon::prelude::*;
 
-use crate::utilities::bonds::{BOND_DISTANCES, AVG_BOND_DISTANCES};
+use crate::atom::AtomRecord;
+use crate::charges::partial_charges;
+use crate::compute_pipeline::forcefield::COULOMB;
+use crate::neighbors::{distance, CellList};
+use crate::pdb::PdbFilePy;
+use crate::rng::seeded;
+use crate::unit_cell::UnitCell;
+use crate::utilities::forcefield::force_field;
+use crate::utilities::DataError;
 
-/// Add ions to a structure to neutralize the system or achieve a specific concentration.
-pub fn add_ions(
-    coords: &mut Vec<(f64, f64, f64)>,
-    atom_types: &mut Vec<String>,
-    ion: &str,
-    number: usize,
-) {
-    let mut rng = rand::thread_rng();
-    let min_x = coords.iter().map(|c| c.0).min_by(|a, b| a.partial_cmp(b).unwrap()).unwrap();
-    let min_y = coords.iter().map(|c| c.1).min_by(|a, b| a.partial_cmp(b).unwrap()).unwrap();
-    let min_z = coords.iter().map(|c| c.2).min_by(|a, b| a.partial_cmp(b).unwrap()).unwrap();
-    let max_x = coords.iter().map(|c| c.0).max_by(|a, b| a.partial_cmp(b).unwrap()).unwrap();
-    let max_y = coords.iter().map(|c| c.1).max_by(|a, b| a.partial_cmp(b).unwrap()).unwrap();
-    let max_z = coords.iter().map(|c| c.2).max_by(|a, b| a.partial_cmp(b).unwrap()).unwrap();
+type Points = Vec<(f64, f64, f64)>;
+
+/// Sites with the electrostatic potential at each.
+pub type IonSites = Vec<((f64, f64, f64), f64)>;
+
+/// Residue names ions may replace.
+const WATER_RESIDUES: [&str; 5] = ["HOH", "WAT", "SOL", "TIP3", "SPC"];
 
-    for _ in 0..number {
-        let x = rng.gen_range(min_x..max_x);
-        let y = rng.gen_range(min_y..max_y);
-        let z = rng.gen_range(min_z..max_z);
-        coords.push((x, y, z));
-        atom_types.push(ion.to_string());
+// Random points tried for each ion once there are no waters left to replace
+const PLACEMENT_ATTEMPTS: usize = 1000;
+
+/// Formula units per litre per Å³, i.e. Avogadro's number times 10⁻²⁷ L/Å³.
+const MOLAR: f64 = 6.02214076e-4;
+
+#[derive(Debug)]
+pub enum IonError {
+    /// The ion has no charge in `forcefield.yml`.
+    Uncharged(String),
+    /// A cation with a negative charge, or an anion with a positive one.
+    WrongSign(String),
+    /// A concentration needs a box volume.
+    ConcentrationWithoutCell,
+    /// Only this many ions could be placed.
+    NoRoom(usize),
+    InvalidSpacing(f64),
+    /// Atom types, records or charges for a different number of atoms than the coordinates.
+    LengthMismatch { what: &'static str, len: usize, atoms: usize },
+    Data(DataError),
+}
+
+impl fmt::Display for IonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IonError::Uncharged(ion) => write!(f, "Ion '{}' has no charge in forcefield.yml", ion),
+            IonError::WrongSign(ion) => write!(f, "Ion '{}' has the wrong charge for its role", ion),
+            IonError::ConcentrationWithoutCell => write!(f, "A salt concentration needs a periodic cell"),
+            IonError::NoRoom(placed) => write!(f, "Only found room for {} ions", placed),
+            IonError::InvalidSpacing(spacing) => write!(f, "Invalid grid spacing {} Å: it must be positive", spacing),
+            IonError::LengthMismatch { what, len, atoms } => write!(f, "Got {} {} for {} atoms", len, what, atoms),
+            IonError::Data(e) => write!(f, "{}", e),
+        }
     }
 }
 
+impl std::error::Error for IonError {}
 
-// Find realistic locations for ions in a structure.
+impl From<DataError> for IonError {
+    fn from(err: DataError) -> IonError {
+        IonError::Data(err)
+    }
+}
 
-pub fn find_possible_ion_locations(
-    coords: &Vec<(f64, f64, f64)>,
-    atom_types: &mut Vec<String>,
-) -> Vec<(f64, f64, f64)> {
-   // don't randomly place, use bond data to find possible locations
-    let _bond_distances = BOND_DISTANCES.iter().map(|(k, v)| (k.clone(), v.clone())).collect::<HashMap<String, (f64, f64)>>();
-    let avg_bond_distances = AVG_BOND_DISTANCES.iter().map(|(k, v)| (k.clone(), *v)).collect::<HashMap<String, f64>>();
-
-    let threshold = 0.2;
-    let mut possible_locations = vec![];
-    let num_atoms = coords.len();
-
-    for i in 0..num_atoms {
-        for j in 0..num_atoms {
-            if i == j {
-                continue;
+impl From<IonError> for PyErr {
+    fn from(err: IonError) -> PyErr {
+        match err {
+            IonError::Data(e) => e.into(),
+            _ => PyValueError::new_err(err.to_string()),
+        }
+    }
+}
+
+/// An ionized structure, and what it took.
+#[pyclass]
+#[derive(Clone)]
+pub struct IonizationResult {
+    #[pyo3(get)]
+    pub structure: PdbFilePy,
+    #[pyo3(get)]
+    pub cations: usize,
+    #[pyo3(get)]
+    pub anions: usize,
+    /// The net charge of the structure before any ions were added, in e.
+    #[pyo3(get)]
+    pub net_charge: f64,
+    /// The net charge left once the ions are in, in e. Whole charges are left when the
+    /// counter-ions' charges do not divide the structure's, such as one e with Ca²⁺.
+    #[pyo3(get)]
+    pub residual_charge: f64,
+}
+
+#[pymethods]
+impl IonizationResult {
+    fn __repr__(&self) -> String {
+        format!(
+            "IonizationResult(cations={}, anions={}, net_charge={:.3}, residual_charge={:.3})",
+            self.cations, self.anions, self.net_charge, self.residual_charge
+        )
+    }
+}
+
+// The integer charge of an ion in the force field.
+fn ion_charge(ion: &str) -> Result<i64, IonError> {
+    match force_field()?.nonbonded(ion).map_or(0, |p| p.charge.round() as i64) {
+        0 => Err(IonError::Uncharged(ion.to_string())),
+        z => Ok(z),
+    }
+}
+
+// Every per-atom input describes as many atoms as there are coordinates.
+fn check_lengths(atoms: usize, atom_types: &[String], records: Option<&[AtomRecord]>, charges: Option<&[f64]>) -> Result<(), IonError> {
+    let lengths = [("atom types", Some(atom_types.len())), ("atom records", records.map(<[_]>::len)), ("charges", charges.map(<[_]>::len))];
+    match lengths.into_iter().find(|&(_, len)| len.is_some_and(|len| len != atoms)) {
+        Some((what, Some(len))) => Err(IonError::LengthMismatch { what, len, atoms }),
+        _ => Ok(()),
+    }
+}
+
+// The given charges, or the partial charges of the residues in `atoms`.
+fn atom_charges(atom_types: &[String], atoms: Option<&[AtomRecord]>, charges: Option<&[f64]>) -> Result<Vec<f64>, DataError> {
+    match charges {
+        Some(charges) => Ok(charges.to_vec()),
+        None => partial_charges(atom_types, atoms),
+    }
+}
+
+// The lower and upper corners of the bounding box of `coords`.
+fn bounds(coords: &[(f64, f64, f64)]) -> ([f64; 3], [f64; 3]) {
+    coords.iter().fold(([f64::INFINITY; 3], [f64::NEG_INFINITY; 3]), |(lo, hi), c| {
+        let p = [c.0, c.1, c.2];
+        ([0, 1, 2].map(|k| lo[k].min(p[k])), [0, 1, 2].map(|k| hi[k].max(p[k])))
+    })
+}
+
+fn gcd(a: i64, b: i64) -> i64 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+/// How many cations and anions of charges `cation` and `anion` e bring a structure of
+/// charge `net_charge` e to zero, plus `units` neutral formula units of salt.
+fn ion_counts(net_charge: f64, cation: i64, anion: i64, neutralize: bool, units: usize) -> (usize, usize) {
+    let (mut cations, mut anions) = (0, 0);
+    if neutralize && net_charge > 0.0 {
+        anions = (net_charge / -anion as f64).round() as usize;
+    } else if neutralize && net_charge < 0.0 {
+        cations = (-net_charge / cation as f64).round() as usize;
+    }
+    let g = gcd(cation, -anion);
+    (cations + units * (-anion / g) as usize, anions + units * (cation / g) as usize)
+}
+
+// The atoms of each water residue, given as consecutive records with the same residue.
+fn water_residues(atoms: &[AtomRecord]) -> Vec<Vec<usize>> {
+    let mut residues: Vec<Vec<usize>> = Vec::new();
+    for (i, atom) in atoms.iter().enumerate() {
+        if !WATER_RESIDUES.contains(&atom.res_name.as_str()) {
+            continue;
+        }
+        let continues = residues.last().is_some_and(|last| {
+            let prev = last[last.len() - 1];
+            prev + 1 == i && atoms[prev].res_seq == atom.res_seq && atoms[prev].chain_id == atom.chain_id
+        });
+        match residues.last_mut() {
+            Some(last) if continues => last.push(i),
+            _ => residues.push(vec![i]),
+        }
+    }
+    residues
+}
+
+/// Add ions to a structure to neutralize it and bring it to a salt concentration.
+///
+/// The net charge comes from `charges`, or without them from the partial charges of the
+/// residues `atoms` names, as `PdbFile.partial_charges` gives them. Counter-ions cancel it, and then neutral formula units of `cation` and
+/// `anion` are added for `concentration` mol/L over the volume of `cell`. A net charge the
+/// counter-ions cannot cancel exactly is logged and left in `residual_charge`.
+///
+/// Each ion replaces a random water residue (HOH, WAT, SOL, TIP3 or SPC) whose oxygen is at
+/// least `min_distance` Å from every other atom that is not water and from the ions
+/// before it. Once there are none left, ions go at random points of the cell, or of the
+/// bounding box of the structure without one, at least `min_distance` Å from every atom.
+/// The same `seed` places the same ions in the same places.
+#[allow(clippy::too_many_arguments)]
+pub fn add_ions(
+    coords: &[(f64, f64, f64)],
+    atom_types: &[String],
+    atoms: Option<Vec<AtomRecord>>,
+    bonds: &[(usize, usize)],
+    charges: Option<&[f64]>,
+    cell: Option<UnitCell>,
+    cation: &str,
+    anion: &str,
+    concentration: f64,
+    neutralize: bool,
+    min_distance: f64,
+    seed: Option<u64>,
+) -> Result<IonizationResult, IonError> {
+    check_lengths(coords.len(), atom_types, atoms.as_deref(), charges)?;
+    let (z_cation, z_anion) = (ion_charge(cation)?, ion_charge(anion)?);
+    if z_cation < 0 {
+        return Err(IonError::WrongSign(cation.to_string()));
+    }
+    if z_anion > 0 {
+        return Err(IonError::WrongSign(anion.to_string()));
+    }
+    let net_charge: f64 = atom_charges(atom_types, atoms.as_deref(), charges)?.iter().sum();
+    let units = match cell {
+        Some(cell) => (concentration * cell.volume() * MOLAR).round() as usize,
+        None if concentration > 0.0 => return Err(IonError::ConcentrationWithoutCell),
+        None => 0,
+    };
+    let (cations, anions) = ion_counts(net_charge, z_cation, z_anion, neutralize, units);
+    let residual_charge = net_charge + (cations as i64 * z_cation + anions as i64 * z_anion) as f64;
+    if neutralize && residual_charge.abs() >= 0.5 {
+        log::warn!("{} and {} ions cannot neutralize a net charge of {:.3} e: {:.3} e is left", cation, anion, net_charge, residual_charge);
+    }
+    let mut ions: Vec<&str> = [vec![cation; cations], vec![anion; anions]].concat();
+
+    let atoms = atoms.unwrap_or_else(|| atom_types.iter().enumerate().map(|(i, t)| AtomRecord::placeholder(i + 1, t)).collect());
+    let mut waters = water_residues(&atoms);
+    let in_water: HashSet<usize> = waters.iter().flatten().copied().collect();
+    let mut rng = seeded(seed);
+    waters.shuffle(&mut rng);
+    ions.shuffle(&mut rng);
+
+    // Everything an ion keeps its distance from: the atoms that are not water, and the ions
+    let mut blockers: Vec<(f64, f64, f64)> = (0..coords.len()).filter(|i| !in_water.contains(i)).map(|i| coords[i]).collect();
+    let mut blocked = CellList::new(&blockers, min_distance, cell);
+    // The waters left once there are none to replace, which can only happen once since
+    // the blockers only grow
+    let mut left: Option<(Points, CellList)> = None;
+    let oxygen = |water: &[usize]| water.iter().copied().find(|&i| atom_types[i] == "O").unwrap_or(water[0]);
+    let (lo, hi) = bounds(coords);
+
+    let mut removed = HashSet::new();
+    let mut sites = Vec::with_capacity(ions.len());
+    for _ in 0..ions.len() {
+        let site = match waters.iter().position(|w| !blocked.any_within(&blockers, coords[oxygen(w)], min_distance)) {
+            Some(w) => {
+                let water = waters.swap_remove(w);
+                let site = coords[oxygen(&water)];
+                removed.extend(water);
+                site
             }
-            let pair = format!("{}-{}", atom_types[i], atom_types[j]);
-            let typical_dist_key = avg_bond_distances.get(&pair).or_else(|| avg_bond_distances.get(&format!("{}-{}", atom_types[j], atom_types[i])));
-            if let Some(&typ) = typical_dist_key {
-                let dist = ((coords[i].0 - coords[j].0).powi(2) +
-                            (coords[i].1 - coords[j].1).powi(2) +
-                            (coords[i].2 - coords[j].2).powi(2)).sqrt();
-                if (dist - typ).abs() <= threshold {
-                    let new_coord = (
-                        (coords[i].0 + coords[j].0) / 2.0,
-                        (coords[i].1 + coords[j].1) / 2.0,
-                        (coords[i].2 + coords[j].2) / 2.0,
-                    );
-                    possible_locations.push(new_coord);
-                }
+            None => {
+                let (water_coords, water_cells) = left.get_or_insert_with(|| {
+                    let water_coords: Points = waters.iter().flatten().map(|&i| coords[i]).collect();
+                    let water_cells = CellList::new(&water_coords, min_distance, cell);
+                    (water_coords, water_cells)
+                });
+                (0..PLACEMENT_ATTEMPTS)
+                    .map(|_| match cell {
+                        Some(cell) => cell.cartesian([rng.gen(), rng.gen(), rng.gen()]),
+                        None => {
+                            let [x, y, z] = [0, 1, 2].map(|k| lo[k] + rng.gen::<f64>() * (hi[k] - lo[k]));
+                            (x, y, z)
+                        }
+                    })
+                    .find(|&p| !blocked.any_within(&blockers, p, min_distance) && !water_cells.any_within(water_coords, p, min_distance))
+                    .ok_or(IonError::NoRoom(sites.len()))?
             }
+        };
+        blocked.insert(blockers.len(), site);
+        blockers.push(site);
+        sites.push(site);
+    }
+
+    // Drop the replaced waters, renumbering the atoms and bonds that are left
+    let kept: Vec<usize> = (0..coords.len()).filter(|i| !removed.contains(i)).collect();
+    let mut index = vec![usize::MAX; coords.len()];
+    for (new, &old) in kept.iter().enumerate() {
+        index[old] = new;
+    }
+    let mut new_coords: Vec<(f64, f64, f64)> = kept.iter().map(|&i| coords[i]).collect();
+    let mut new_types: Vec<String> = kept.iter().map(|&i| atom_types[i].clone()).collect();
+    let mut new_atoms: Vec<AtomRecord> = kept.iter().map(|&i| atoms[i].clone()).collect();
+    let new_bonds = bonds
+        .iter()
+        .filter(|&&(a, b)| !removed.contains(&a) && !removed.contains(&b))
+        .map(|&(a, b)| (index[a], index[b]))
+        .collect();
+
+    let mut res_seq = new_atoms.iter().map(|a| a.res_seq).max().unwrap_or(0);
+    for (ion, site) in ions.iter().zip(sites) {
+        res_seq += 1;
+        let name = ion.to_uppercase();
+        new_atoms.push(AtomRecord::new(0, &name, &name, res_seq, "", true));
+        new_types.push(ion.to_string());
+        new_coords.push(site);
+    }
+    for (i, atom) in new_atoms.iter_mut().enumerate() {
+        atom.serial = i + 1;
+    }
+
+    Ok(IonizationResult {
+        structure: PdbFilePy::new(new_coords, new_types, new_bonds, Some(new_atoms), cell),
+        cations,
+        anions,
+        net_charge,
+        residual_charge,
+    })
+}
+
+
+/// Candidate sites for `ion`, most favourable first, each with the electrostatic potential
+/// there in kcal/(mol·e).
+///
+/// The potential 332.0637 Σ qᵢ/rᵢ of the atoms' `charges`, or without them of the partial
+/// charges of the residues `atoms` names, is scanned on a grid `spacing` Å apart over the cell,
+/// or over the bounding box of the structure grown by `exclusion` without one. Sites rank
+/// by the ion's energy there, and each lies at least `exclusion` Å from every atom and from
+/// every site ranked above it. At most `count` sites are returned when it is given.
+#[allow(clippy::too_many_arguments)]
+pub fn find_possible_ion_locations(
+    coords: &[(f64, f64, f64)],
+    atom_types: &[String],
+    atoms: Option<&[AtomRecord]>,
+    charges: Option<&[f64]>,
+    cell: Option<UnitCell>,
+    ion: &str,
+    spacing: f64,
+    exclusion: f64,
+    count: Option<usize>,
+) -> Result<IonSites, IonError> {
+    check_lengths(coords.len(), atom_types, atoms, charges)?;
+    let z = ion_charge(ion)? as f64;
+    if spacing <= 0.0 {
+        return Err(IonError::InvalidSpacing(spacing));
+    }
+    let charges = atom_charges(atom_types, atoms, charges)?;
+    let charged: Vec<((f64, f64, f64), f64)> = coords.iter().copied().zip(charges).filter(|&(_, q)| q != 0.0).collect();
+
+    let grid: Vec<(f64, f64, f64)> = match cell {
+        Some(cell) => {
+            let n = cell.vectors().map(|v| ((v.0 * v.0 + v.1 * v.1 + v.2 * v.2).sqrt() / spacing).ceil().max(1.0) as usize);
+            (0..n[0] * n[1] * n[2])
+                .map(|i| {
+                    let index = [i % n[0], i / n[0] % n[1], i / (n[0] * n[1])];
+                    cell.cartesian([0, 1, 2].map(|k| (index[k] as f64 + 0.5) / n[k] as f64))
+                })
+                .collect()
+        }
+        None => {
+            let (lo, hi) = bounds(coords);
+            let n = [0, 1, 2].map(|k| ((hi[k] - lo[k] + 2.0 * exclusion) / spacing).floor() as usize + 1);
+            (0..n[0] * n[1] * n[2])
+                .map(|i| {
+                    let index = [i % n[0], i / n[0] % n[1], i / (n[0] * n[1])];
+                    let [x, y, z] = [0, 1, 2].map(|k| lo[k] - exclusion + index[k] as f64 * spacing);
+                    (x, y, z)
+                })
+                .collect()
+        }
+    };
+
+    let atoms = CellList::new(coords, exclusion, cell);
+    let distance = |a: (f64, f64, f64), b: (f64, f64, f64)| match cell {
+        Some(cell) => cell.distance(a, b),
+        None => distance(a, b),
+    };
+    let mut candidates: IonSites = grid
+        .into_par_iter()
+        .filter(|&p| !atoms.any_within(coords, p, exclusion))
+        .map(|p| (p, COULOMB * charged.iter().map(|&(c, q)| q / distance(p, c)).sum::<f64>()))
+        .collect();
+    candidates.sort_by(|a, b| (z * a.1).total_cmp(&(z * b.1)));
+
+    let mut sites: IonSites = Vec::new();
+    let mut chosen = Vec::new();
+    let mut taken = CellList::new(&chosen, exclusion, cell);
+    for (p, potential) in candidates {
+        if count.is_some_and(|n| sites.len() >= n) {
+            break;
+        }
+        if !taken.any_within(&chosen, p, exclusion) {
+            taken.insert(chosen.len(), p);
+            chosen.push(p);
+            sites.push((p, potential));
         }
     }
+    Ok(sites)
+}
 
-    possible_locations
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::solvation::{solvate_box, WaterModel};
+
+    #[test]
+    fn same_seed_places_same_ions() {
+        let types = vec!["C".to_string(), "O".to_string()];
+        let solvated = solvate_box(&[(0.0, 0.0, 0.0), (1.2, 0.0, 0.0)], &types, None, &[(0, 1)], 6.0, None, WaterModel::Tip3p, 2.4, None).unwrap();
+        let s = &solvated.structure;
+        let ionize = |seed| {
+            add_ions(&s.coords, &s.atom_types, Some(s.atoms.clone()), &s.bonds, None, s.cell, "Na", "Cl", 0.5, true, 3.0, Some(seed))
+                .unwrap()
+                .structure
+        };
+        let (a, b, c) = (ionize(7), ionize(7), ionize(8));
+        assert_eq!(a.coords, b.coords);
+        assert_eq!(a.atoms, b.atoms);
+        assert_ne!(a.coords, c.coords);
+    }
+
+    #[test]
+    fn residue_charges_decide_the_counter_ions() {
+        // An aspartate's carboxylate, charged by its residue rather than its elements
+        let names = ["CB", "CG", "OD1", "OD2"];
+        let coords = [(0.0, 0.0, 0.0), (1.5, 0.0, 0.0), (2.1, 1.1, 0.0), (2.1, -1.1, 0.0)];
+        let types: Vec<String> = names.iter().map(|n| n[..1].to_string()).collect();
+        let atoms: Vec<AtomRecord> = names.iter().enumerate().map(|(i, n)| AtomRecord::new(i + 1, n, "ASP", 1, "A", false)).collect();
+        let bonds = [(0, 1), (1, 2), (1, 3)];
+        let solvated = solvate_box(&coords, &types, Some(atoms), &bonds, 6.0, None, WaterModel::Tip3p, 2.4, None).unwrap();
+        let s = &solvated.structure;
+        let result = add_ions(&s.coords, &s.atom_types, Some(s.atoms.clone()), &s.bonds, None, s.cell, "Na", "Cl", 0.0, true, 3.0, Some(1)).unwrap();
+        assert!((result.net_charge + 1.0).abs() < 1e-6);
+        assert_eq!((result.cations, result.anions), (1, 0));
+        assert!(result.residual_charge.abs() < 1e-6);
+
+        // A calcium ion overshoots a single negative charge
+        let result = add_ions(&s.coords, &s.atom_types, Some(s.atoms.clone()), &s.bonds, None, s.cell, "Ca", "Cl", 0.0, true, 3.0, Some(1)).unwrap();
+        assert!((result.residual_charge - 1.0).abs() < 1e-6);
+        let short = add_ions(&s.coords, &s.atom_types[1..], None, &s.bonds, None, s.cell, "Na", "Cl", 0.0, true, 3.0, None);
+        assert!(matches!(short, Err(IonError::LengthMismatch { what: "atom types", .. })));
+    }
+}
@@ -6,31 +6,107 @@ use pyo3::Python;
 pub mod ions;
 pub mod solvation;
 
-use ions::{add_ions, find_possible_ion_locations};
-use solvation::solvate_box;
+use ions::{add_ions, find_possible_ion_locations, IonSites, IonizationResult};
+use solvation::{solvate_box, SolvationResult, WaterModel};
+
+use crate::atom::AtomRecord;
+use crate::rng::{resolve, Seed};
+use crate::unit_cell::UnitCell;
 
 #[pymodule]
 pub fn builder(_py: Python, m: Bound<PyModule>) -> PyResult<()> {
+    m.add_class::<WaterModel>()?;
+    m.add_class::<SolvationResult>()?;
+    m.add_class::<IonizationResult>()?;
+
     #[pyfn(m, name = "add_ions")]
-    fn add_ions_py(coords: Vec<(f64, f64, f64)>, atom_types: Vec<String>, ion: &str, number: usize) -> (Vec<(f64, f64, f64)>, Vec<String>) {
-        let mut coords = coords;
-        let mut atom_types = atom_types;
-        add_ions(&mut coords, &mut atom_types, ion, number);
-        (coords, atom_types)
+    #[pyo3(signature = (
+        coords,
+        atom_types,
+        cell=None,
+        cation="Na",
+        anion="Cl",
+        concentration=0.0,
+        neutralize=true,
+        charges=None,
+        min_distance=3.0,
+        atoms=None,
+        bonds=Vec::new(),
+        seed=None,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn add_ions_py(
+        coords: Vec<(f64, f64, f64)>,
+        atom_types: Vec<String>,
+        cell: Option<UnitCell>,
+        cation: &str,
+        anion: &str,
+        concentration: f64,
+        neutralize: bool,
+        charges: Option<Vec<f64>>,
+        min_distance: f64,
+        atoms: Option<Vec<AtomRecord>>,
+        bonds: Vec<(usize, usize)>,
+        seed: Option<Seed>,
+    ) -> PyResult<IonizationResult> {
+        Ok(add_ions(
+            &coords,
+            &atom_types,
+            atoms,
+            &bonds,
+            charges.as_deref(),
+            cell,
+            cation,
+            anion,
+            concentration,
+            neutralize,
+            min_distance,
+            resolve(seed),
+        )?)
     }
 
     #[pyfn(m, name = "find_possible_ion_locations")]
-    fn find_possible_ion_locations_py(coords: Vec<(f64, f64, f64)>, atom_types: Vec<String>) -> Vec<(f64, f64, f64)> {
-        let mut atom_types = atom_types;
-        find_possible_ion_locations(&coords, &mut atom_types)
+    #[pyo3(signature = (coords, atom_types, cell=None, charges=None, ion="Na", spacing=1.0, exclusion=3.0, count=None, atoms=None))]
+    #[allow(clippy::too_many_arguments)]
+    fn find_possible_ion_locations_py(
+        coords: Vec<(f64, f64, f64)>,
+        atom_types: Vec<String>,
+        cell: Option<UnitCell>,
+        charges: Option<Vec<f64>>,
+        ion: &str,
+        spacing: f64,
+        exclusion: f64,
+        count: Option<usize>,
+        atoms: Option<Vec<AtomRecord>>,
+    ) -> PyResult<IonSites> {
+        Ok(find_possible_ion_locations(&coords, &atom_types, atoms.as_deref(), charges.as_deref(), cell, ion, spacing, exclusion, count)?)
     }
 
     #[pyfn(m, name = "solvate_box")]
-    fn solvate_box_py(coords: Vec<(f64, f64, f64)>, atom_types: Vec<String>, box_size: f64) -> (Vec<(f64, f64, f64)>, Vec<String>) {
-        let mut coords = coords;
-        let mut atom_types = atom_types;
-        solvate_box(&mut coords, &mut atom_types, box_size);
-        (coords, atom_types)
+    #[pyo3(signature = (
+        coords,
+        atom_types,
+        box_size=10.0,
+        cell=None,
+        model=WaterModel::Tip3p,
+        cutoff=2.4,
+        residue=None,
+        atoms=None,
+        bonds=Vec::new(),
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn solvate_box_py(
+        coords: Vec<(f64, f64, f64)>,
+        atom_types: Vec<String>,
+        box_size: f64,
+        cell: Option<UnitCell>,
+        model: WaterModel,
+        cutoff: f64,
+        residue: Option<&str>,
+        atoms: Option<Vec<AtomRecord>>,
+        bonds: Vec<(usize, usize)>,
+    ) -> PyResult<SolvationResult> {
+        Ok(solvate_box(&coords, &atom_types, atoms, &bonds, box_size, cell, model, cutoff, residue)?)
     }
 
     Ok(())
@@ -1,63 +1,152 @@
-use rand::Rng;
+use pyo3::prelude::*;
 
-/// Add a box of water (or other solvent) around a molecular system.
+use crate::atom::AtomRecord;
+use crate::neighbors::CellList;
+use crate::pdb::PdbFilePy;
+use crate::unit_cell::UnitCell;
+use crate::utilities::water::load_water_box;
+use crate::utilities::DataError;
+
+// Closest two water oxygens may come where tiles meet at a face of the cell, in Å.
+// Every template keeps its own oxygens further apart than this.
+const MIN_OXYGEN_DISTANCE: f64 = 2.3;
+
+/// A water model with a pre-equilibrated box in `data/water`.
+#[pyclass(eq, eq_int)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WaterModel {
+    Tip3p,
+    Spce,
+    /// Four sites, one of them a massless `EP` charge site. The CPU backend keeps it on its
+    /// water's bisector as a virtual site; the GPU backend does not run it.
+    Tip4p,
+}
+
+impl WaterModel {
+    fn file_stem(self) -> &'static str {
+        match self {
+            WaterModel::Tip3p => "tip3p",
+            WaterModel::Spce => "spce",
+            WaterModel::Tip4p => "tip4p",
+        }
+    }
+}
+
+/// A solvated structure, with the solute's atoms first, and how many waters were added.
+#[pyclass]
+#[derive(Clone)]
+pub struct SolvationResult {
+    #[pyo3(get)]
+    pub structure: PdbFilePy,
+    #[pyo3(get)]
+    pub waters: usize,
+}
+
+#[pymethods]
+impl SolvationResult {
+    fn __repr__(&self) -> String {
+        format!("SolvationResult(atoms={}, waters={})", self.structure.coords.len(), self.waters)
+    }
+}
+
+// The lower and upper corners of the bounding box of `coords`.
+fn bounds(coords: &[(f64, f64, f64)]) -> ([f64; 3], [f64; 3]) {
+    if coords.is_empty() {
+        return ([0.0; 3], [0.0; 3]);
+    }
+    coords.iter().fold(([f64::INFINITY; 3], [f64::NEG_INFINITY; 3]), |(lo, hi), c| {
+        let p = [c.0, c.1, c.2];
+        ([0, 1, 2].map(|k| lo[k].min(p[k])), [0, 1, 2].map(|k| hi[k].max(p[k])))
+    })
+}
+
+/// Fill a periodic box around a molecular system with water, by tiling the `model`'s
+/// pre-equilibrated box.
+///
+/// Without a `cell`, the box reaches `padding` past the structure on every side, rounded
+/// up to whole template boxes so the water carries on unbroken through its faces, and
+/// everything is translated so the box corner sits at the origin. A given `cell` is
+/// filled around the structure where it stands, leaving out waters that would crowd
+/// their neighbours through the faces.
+///
+/// Waters with any site within `cutoff` Å of the solute are left out. Each water added is
+/// a HETATM residue named `residue`, or the model's own residue name without one (SPC for
+/// SPC/E, HOH otherwise), numbered on from the solute's residues, and bonded as the
+/// template is.
+#[allow(clippy::too_many_arguments)]
 pub(crate) fn solvate_box(
-    coords: &mut Vec<(f64, f64, f64)>,
-    atom_types: &mut Vec<String>,
-    box_size: f64,
-) {
-    let mut rng = rand::thread_rng();
-    let min_x = coords.iter().map(|c| c.0).min_by(|a, b| a.partial_cmp(b).unwrap()).unwrap();
-    let min_y = coords.iter().map(|c| c.1).min_by(|a, b| a.partial_cmp(b).unwrap()).unwrap();
-    let min_z = coords.iter().map(|c| c.2).min_by(|a, b| a.partial_cmp(b).unwrap()).unwrap();
-    let max_x = coords.iter().map(|c| c.0).max_by(|a, b| a.partial_cmp(b).unwrap()).unwrap();
-    let max_y = coords.iter().map(|c| c.1).max_by(|a, b| a.partial_cmp(b).unwrap()).unwrap();
-    let max_z = coords.iter().map(|c| c.2).max_by(|a, b| a.partial_cmp(b).unwrap()).unwrap();
-
-    let x_offset = max_x + box_size;
-    let y_offset = max_y + box_size;
-    let z_offset = max_z + box_size;
-    let x_start = min_x - box_size;
-    let y_start = min_y - box_size;
-    let z_start = min_z - box_size;
-
-    // Assuming TIP3P water model with a fixed geometry
-    let tip3p_geometry = [
-        ("O", (0.0, 0.0, 0.0)),
-        ("H1", (0.9572, 0.0, 0.0)),
-        ("H2", (-0.2399872, 0.92662721, 0.0)),
-    ];
-
-    for x in (x_start as isize..x_offset as isize).step_by(3) {
-        for y in (y_start as isize..y_offset as isize).step_by(3) {
-            for z in (z_start as isize..z_offset as isize).step_by(3) {
-                let mut water_coords = vec![];
-                for (atom, (dx, dy, dz)) in &tip3p_geometry {
-                    let new_coord = (
-                        x as f64 + dx + rng.gen_range(-0.1..0.1),
-                        y as f64 + dy + rng.gen_range(-0.1..0.1),
-                        z as f64 + dz + rng.gen_range(-0.1..0.1),
-                    );
-                    water_coords.push((atom.to_string(), new_coord));
-                }
+    coords: &[(f64, f64, f64)],
+    atom_types: &[String],
+    atoms: Option<Vec<AtomRecord>>,
+    bonds: &[(usize, usize)],
+    padding: f64,
+    cell: Option<UnitCell>,
+    model: WaterModel,
+    cutoff: f64,
+    residue: Option<&str>,
+) -> Result<SolvationResult, DataError> {
+    let template = load_water_box(model.file_stem())?;
+    let residue = residue.unwrap_or(&template.residue);
+    let mut coords = coords.to_vec();
+    let cell = match cell {
+        Some(cell) => cell,
+        None => {
+            let (lo, hi) = bounds(&coords);
+            let size = [0, 1, 2].map(|k| ((hi[k] - lo[k] + 2.0 * padding) / template.size[k]).ceil().max(1.0) * template.size[k]);
+            for c in coords.iter_mut() {
+                *c = (c.0 - lo[0] + padding, c.1 - lo[1] + padding, c.2 - lo[2] + padding);
+            }
+            // Only a water box of no size gives no cell
+            UnitCell::orthorhombic(size[0], size[1], size[2])
+                .map_err(|e| DataError::Parse { source: format!("water/{}.yml", model.file_stem()), message: e.to_string() })?
+        }
+    };
+
+    let mut atom_types = atom_types.to_vec();
+    let mut atoms = atoms.unwrap_or_else(|| atom_types.iter().enumerate().map(|(i, t)| AtomRecord::placeholder(i + 1, t)).collect());
+    let mut bonds = bonds.to_vec();
+    let mut res_seq = atoms.iter().map(|a| a.res_seq).max().unwrap_or(0);
+
+    let solute = CellList::new(&coords, cutoff, Some(cell));
+    let solute_len = coords.len();
+    let mut oxygens = Vec::new();
+    let mut placed = CellList::new(&oxygens, MIN_OXYGEN_DISTANCE, Some(cell));
 
-                // Ensure water molecules do not overlap with the existing molecule
-                let overlaps = coords.iter().any(|&(cx, cy, cz)| {
-                    water_coords.iter().any(|(_, (wx, wy, wz))| {
-                        let dx = cx - wx;
-                        let dy = cy - wy;
-                        let dz = cz - wz;
-                        (dx * dx + dy * dy + dz * dz).sqrt() < 2.0
-                    })
-                });
-
-                if !overlaps {
-                    for (atom, coord) in water_coords {
-                        atom_types.push(atom);
-                        coords.push(coord);
+    // Every tile that overlaps the cell
+    let corners: Vec<(f64, f64, f64)> = (0..8).map(|i| cell.cartesian([0, 1, 2].map(|k| ((i >> k) & 1) as f64))).collect();
+    let (lo, hi) = bounds(&corners);
+    let range = |k: usize| (lo[k] / template.size[k]).floor() as i64..=(hi[k] / template.size[k]).floor() as i64;
+    let mut waters = 0;
+    for ix in range(0) {
+        for iy in range(1) {
+            for iz in range(2) {
+                let offset = [ix as f64 * template.size[0], iy as f64 * template.size[1], iz as f64 * template.size[2]];
+                for molecule in &template.molecules {
+                    let sites: Vec<(f64, f64, f64)> =
+                        molecule.iter().map(|s| (s[0] + offset[0], s[1] + offset[1], s[2] + offset[2])).collect();
+                    let oxygen = sites[0];
+                    if cell.fractional(oxygen).iter().any(|f| !(0.0..1.0).contains(f))
+                        || sites.iter().any(|&s| solute.any_within(&coords[..solute_len], s, cutoff))
+                        || placed.any_within(&oxygens, oxygen, MIN_OXYGEN_DISTANCE)
+                    {
+                        continue;
                     }
+                    placed.insert(oxygens.len(), oxygen);
+                    oxygens.push(oxygen);
+
+                    let first = coords.len();
+                    res_seq += 1;
+                    for (site, &position) in template.sites.iter().zip(&sites) {
+                        atoms.push(AtomRecord::new(coords.len() + 1, &site.name, residue, res_seq, "", true));
+                        atom_types.push(site.element.clone());
+                        coords.push(position);
+                    }
+                    bonds.extend(template.bonds.iter().map(|&(a, b)| (first + a, first + b)));
+                    waters += 1;
                 }
             }
         }
     }
+
+    Ok(SolvationResult { structure: PdbFilePy::new(coords, atom_types, bonds, Some(atoms), Some(cell)), waters })
 }
@@ -0,0 +1,132 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::atom::{residue_ranges, AtomRecord};
+use crate::utilities::forcefield::force_field;
+use crate::utilities::residues::{residue_templates, Linking};
+use crate::utilities::DataError;
+
+// The entry whose charges a residue takes: a histidine's tautomer by which ring nitrogens
+// carry a hydrogen, and a four-site water's model
+fn entry_name<'a>(res_name: &'a str, names: &HashSet<&str>) -> &'a str {
+    match res_name {
+        "HIS" => match (names.contains("HD1"), names.contains("HE2")) {
+            (true, true) => "HIP",
+            (true, false) => "HID",
+            _ => "HIS",
+        },
+        _ if names.contains("EPW") => "TIP4P",
+        _ => res_name,
+    }
+}
+
+/// The partial charge of every atom in e.
+///
+/// Atoms of a residue in the `charges` table of `forcefield.yml` take the charge of their
+/// name there, and every other atom its element's nonbonded charge, which only ions have.
+/// A hydrogen the table lists but the structure lacks has its charge folded onto the atom
+/// it bonds to in `residues.yml`, so structures without hydrogens keep whole residue
+/// charges. A peptide chain starts with an NH3+ group when its first residue has its `N`,
+/// and ends with COO- when it has an `OXT`, and a nucleic acid strand starts without its
+/// 5' phosphate when `P` is missing: these groups share the extra charge, and any residue
+/// missing heavy atoms shares what it lacks of its whole charge out over the atoms it
+/// has. Without `atoms`, every atom takes its element's charge.
+pub(crate) fn partial_charges(atom_types: &[String], atoms: Option<&[AtomRecord]>) -> Result<Vec<f64>, DataError> {
+    let force_field = force_field()?;
+    let mut charges: Vec<f64> = atom_types.iter().map(|t| force_field.nonbonded(t).map_or(0.0, |p| p.charge)).collect();
+    let Some(atoms) = atoms.filter(|a| a.len() == atom_types.len()) else { return Ok(charges) };
+    let templates = residue_templates()?;
+
+    let mut previous: Option<(&str, Option<Linking>)> = None;
+    for range in residue_ranges(atoms) {
+        let first = &atoms[range.start];
+        let template = templates.get(&first.res_name);
+        let linking = template.and_then(|t| t.linking);
+        let continues = previous.is_some_and(|(chain, link)| chain == first.chain_id && link.is_some() && link == linking);
+        previous = Some((&first.chain_id, linking));
+
+        let names: HashSet<&str> = range.clone().map(|i| atoms[i].name.as_str()).collect();
+        let Some(entry) = force_field.charges.get(entry_name(&first.res_name, &names)) else { continue };
+        let index: HashMap<&str, usize> = range.clone().map(|i| (atoms[i].name.as_str(), i)).collect();
+
+        for i in range.clone() {
+            if let Some(&q) = entry.get(&atoms[i].name) {
+                charges[i] = q;
+            }
+        }
+        if let Some(template) = template {
+            for bond in &template.bonds {
+                for (h, heavy) in [(&bond.atoms.0, &bond.atoms.1), (&bond.atoms.1, &bond.atoms.0)] {
+                    if let (true, false, Some(&q), Some(&i)) = (h.starts_with('H'), names.contains(h.as_str()), entry.get(h), index.get(heavy.as_str())) {
+                        charges[i] += q;
+                    }
+                }
+            }
+        }
+
+        let mut total = entry.values().sum::<f64>().round();
+        let mut group: Vec<&str> = Vec::new();
+        match linking {
+            Some(Linking::Peptide) => {
+                if !continues && names.contains("N") {
+                    total += 1.0;
+                    group.extend(["N", "H", "H1", "H2", "H3"]);
+                }
+                if names.contains("OXT") {
+                    total -= 1.0;
+                    group.extend(["C", "O", "OXT"]);
+                }
+            }
+            Some(Linking::Nucleic) if !names.contains("P") => {
+                total += 1.0;
+                group.extend(["O5'", "HO5'"]);
+            }
+            _ => {}
+        }
+        let mut sharing: Vec<usize> = group.iter().filter_map(|name| index.get(name).copied()).collect();
+        if sharing.is_empty() {
+            sharing = range.clone().filter(|&i| entry.contains_key(&atoms[i].name)).collect();
+        }
+        let residual = total - range.clone().map(|i| charges[i]).sum::<f64>();
+        for &i in &sharing {
+            charges[i] += residual / sharing.len() as f64;
+        }
+    }
+    Ok(charges)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn residues_keep_whole_charges_without_hydrogens() {
+        // Lys-Asp-Gly heavy atoms only, then a TIP3P water and a sodium ion
+        let residues: [(&str, &[&str]); 5] = [
+            ("LYS", &["N", "CA", "C", "O", "CB", "CG", "CD", "CE", "NZ"]),
+            ("ASP", &["N", "CA", "C", "O", "CB", "CG", "OD1", "OD2"]),
+            ("GLY", &["N", "CA", "C", "O", "OXT"]),
+            ("HOH", &["O", "H1", "H2"]),
+            ("NA", &["NA"]),
+        ];
+        let (mut atom_types, mut atoms) = (Vec::new(), Vec::new());
+        for (seq, (res_name, names)) in residues.iter().enumerate() {
+            for name in names.iter() {
+                let element = match *name {
+                    "NA" => "Na".to_string(),
+                    n => n[..1].to_string(),
+                };
+                atom_types.push(element);
+                atoms.push(AtomRecord::new(atoms.len() + 1, name, res_name, seq as i32 + 1, "A", false));
+            }
+        }
+        let charges = partial_charges(&atom_types, Some(&atoms)).unwrap();
+        let net = |range: std::ops::Range<usize>| charges[range].iter().sum::<f64>();
+        // +1 from the N-terminus and +1 from the lysine
+        assert!((net(0..9) - 2.0).abs() < 1e-9);
+        assert!((net(9..17) + 1.0).abs() < 1e-9);
+        // -1 from the C-terminus
+        assert!((net(17..22) + 1.0).abs() < 1e-9);
+        assert_eq!(&charges[22..], &[-0.834, 0.417, 0.417, 1.0]);
+        assert!((charges.iter().sum::<f64>() - 1.0).abs() < 1e-9);
+    }
+}
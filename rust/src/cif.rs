@@ -0,0 +1,506 @@
+use pyo3::prelude::*;
+use pyo3::exceptions::PyValueError;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use crate::atom::AtomRecord;
+use crate::pdb::{check_bonds, normalize_element, AtomLine, PdbFilePy};
+use crate::unit_cell::UnitCell;
+
+pyo3::create_exception!(rustquantic, CifParseError, PyValueError, "An mmCIF file could not be parsed.");
+
+#[derive(Debug)]
+pub enum CifError {
+    Io(std::io::Error),
+    Syntax { line: usize, message: String },
+    Data { block: String, message: String },
+}
+
+impl fmt::Display for CifError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CifError::Io(e) => write!(f, "{}", e),
+            CifError::Syntax { line, message } => write!(f, "line {}: {}", line, message),
+            CifError::Data { block, message } => write!(f, "data_{}: {}", block, message),
+        }
+    }
+}
+
+impl std::error::Error for CifError {}
+
+impl From<std::io::Error> for CifError {
+    fn from(err: std::io::Error) -> CifError {
+        CifError::Io(err)
+    }
+}
+
+impl From<CifError> for PyErr {
+    fn from(err: CifError) -> PyErr {
+        match err {
+            CifError::Io(e) => e.into(),
+            _ => CifParseError::new_err(err.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Data(String),
+    Loop,
+    Tag(String),
+    /// A value; `None` for the unquoted placeholders `?` (unknown) and `.` (inapplicable).
+    Value(Option<String>),
+}
+
+/// Split a CIF file into tokens, each with the line it starts on.
+fn tokenize(text: &str) -> Result<Vec<(Token, usize)>, CifError> {
+    let mut tokens = Vec::new();
+    let mut text_field: Option<(String, usize)> = None;
+    for (i, line) in text.lines().enumerate() {
+        let line_no = i + 1;
+        // Semicolon text fields run from a line starting with ';' to the next such line
+        if let Some(stripped) = line.strip_prefix(';') {
+            match text_field.take() {
+                Some((value, start)) => {
+                    tokens.push((Token::Value(Some(value)), start));
+                    tokenize_line(stripped, line_no, &mut tokens)?;
+                }
+                None => text_field = Some((stripped.to_string(), line_no)),
+            }
+            continue;
+        }
+        if let Some((value, _)) = text_field.as_mut() {
+            value.push('\n');
+            value.push_str(line);
+            continue;
+        }
+        tokenize_line(line, line_no, &mut tokens)?;
+    }
+    if let Some((_, start)) = text_field {
+        return Err(CifError::Syntax { line: start, message: "unterminated text field".to_string() });
+    }
+    Ok(tokens)
+}
+
+fn tokenize_line(line: &str, line_no: usize, tokens: &mut Vec<(Token, usize)>) -> Result<(), CifError> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '#' {
+            break;
+        } else if c == '\'' || c == '"' {
+            // A quoted value ends at a matching quote followed by whitespace or the end of the line
+            let start = i + 1;
+            let mut end = start;
+            loop {
+                if end >= chars.len() {
+                    return Err(CifError::Syntax { line: line_no, message: "unterminated quoted value".to_string() });
+                }
+                if chars[end] == c && (end + 1 == chars.len() || chars[end + 1].is_whitespace()) {
+                    break;
+                }
+                end += 1;
+            }
+            tokens.push((Token::Value(Some(chars[start..end].iter().collect())), line_no));
+            i = end + 1;
+        } else {
+            let start = i;
+            while i < chars.len() && !chars[i].is_whitespace() {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            let lower = word.to_ascii_lowercase();
+            let token = if lower.starts_with("data_") {
+                Token::Data(word[5..].to_string())
+            } else if lower == "loop_" {
+                Token::Loop
+            } else if word.starts_with('_') {
+                Token::Tag(word)
+            } else if lower.starts_with("save_") || lower == "global_" || lower == "stop_" {
+                // Save frames only appear in dictionaries; their contents are read as ordinary items
+                continue;
+            } else if word == "?" || word == "." {
+                Token::Value(None)
+            } else {
+                Token::Value(Some(word))
+            };
+            tokens.push((token, line_no));
+        }
+    }
+    Ok(())
+}
+
+/// The rows of one category, from a loop or from single `_category.item value` pairs.
+#[derive(Clone, Debug, Default)]
+pub struct CifTable {
+    pub items: Vec<String>,
+    pub rows: Vec<Vec<Option<String>>>,
+}
+
+impl CifTable {
+    fn column(&self, item: &str) -> Option<usize> {
+        self.items.iter().position(|i| i.eq_ignore_ascii_case(item))
+    }
+
+    /// The value of `item` in `row`, treating `?` and `.` as absent.
+    pub fn get(&self, row: usize, item: &str) -> Option<&str> {
+        self.column(item).and_then(|c| self.rows[row][c].as_deref())
+    }
+
+    /// The first of `items` present in `row`.
+    pub fn get_any(&self, row: usize, items: &[&str]) -> Option<&str> {
+        items.iter().find_map(|item| self.get(row, item))
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CifBlock {
+    pub name: String,
+    categories: HashMap<String, CifTable>,
+}
+
+impl CifBlock {
+    pub fn category(&self, name: &str) -> Option<&CifTable> {
+        self.categories.get(&name.to_ascii_lowercase())
+    }
+}
+
+/// Split `_category.item` into its lowercase category and its item.
+fn split_tag(tag: &str, line: usize) -> Result<(String, String), CifError> {
+    match tag[1..].split_once('.') {
+        Some((category, item)) => Ok((category.to_ascii_lowercase(), item.to_string())),
+        None => Err(CifError::Syntax { line, message: format!("tag {} has no category", tag) }),
+    }
+}
+
+pub fn parse_cif_blocks(text: &str) -> Result<Vec<CifBlock>, CifError> {
+    let tokens = tokenize(text)?;
+    let mut blocks: Vec<CifBlock> = Vec::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        let (token, line) = &tokens[i];
+        let line = *line;
+        if let Token::Data(name) = token {
+            blocks.push(CifBlock { name: name.clone(), ..Default::default() });
+            i += 1;
+            continue;
+        }
+        let block = blocks.last_mut().ok_or(CifError::Syntax { line, message: "content before the first data_ block".to_string() })?;
+        match token {
+            Token::Loop => {
+                i += 1;
+                let mut tags = Vec::new();
+                while let Some((Token::Tag(tag), tag_line)) = tokens.get(i) {
+                    tags.push(split_tag(tag, *tag_line)?);
+                    i += 1;
+                }
+                let Some((category, _)) = tags.first().cloned() else {
+                    return Err(CifError::Syntax { line, message: "loop_ without tags".to_string() });
+                };
+                if tags.iter().any(|(c, _)| *c != category) {
+                    return Err(CifError::Syntax { line, message: "loop_ mixes categories".to_string() });
+                }
+                let mut values = Vec::new();
+                while let Some((Token::Value(value), _)) = tokens.get(i) {
+                    values.push(value.clone());
+                    i += 1;
+                }
+                if values.len() % tags.len() != 0 {
+                    return Err(CifError::Syntax {
+                        line,
+                        message: format!("loop_ for _{} has {} values for {} tags", category, values.len(), tags.len()),
+                    });
+                }
+                let rows = values.chunks(tags.len()).map(|row| row.to_vec()).collect();
+                let items = tags.into_iter().map(|(_, item)| item).collect();
+                block.categories.insert(category, CifTable { items, rows });
+            }
+            Token::Tag(tag) => {
+                let (category, item) = split_tag(tag, line)?;
+                let Some((Token::Value(value), _)) = tokens.get(i + 1) else {
+                    return Err(CifError::Syntax { line, message: format!("tag {} has no value", tag) });
+                };
+                let table = block.categories.entry(category).or_default();
+                if table.rows.is_empty() {
+                    table.rows.push(Vec::new());
+                }
+                table.items.push(item);
+                table.rows[0].push(value.clone());
+                i += 2;
+            }
+            Token::Value(_) => {
+                return Err(CifError::Syntax { line, message: "value without a tag".to_string() });
+            }
+            Token::Data(_) => unreachable!(),
+        }
+    }
+    Ok(blocks)
+}
+
+/// Formal charge as written in PDB columns 79-80, e.g. `2+` for 2 and `1-` for -1.
+fn pdb_charge(formal_charge: Option<&str>) -> String {
+    match formal_charge.and_then(|c| c.parse::<i32>().ok()) {
+        Some(c) if c > 0 => format!("{}+", c),
+        Some(c) if c < 0 => format!("{}-", -c),
+        _ => String::new(),
+    }
+}
+
+fn formal_charge(pdb_charge: &str) -> Option<i32> {
+    let (digits, sign) = pdb_charge.split_at(pdb_charge.len().checked_sub(1)?);
+    let value = digits.parse::<i32>().ok()?;
+    match sign {
+        "+" => Some(value),
+        "-" => Some(-value),
+        _ => None,
+    }
+}
+
+/// Identifies an atom the way `_struct_conn` partners do: chain, residue number, insertion code and atom name.
+type AtomKey = (String, i32, String, String);
+
+fn atom_key(record: &AtomRecord) -> AtomKey {
+    (record.chain_id.clone(), record.res_seq, record.i_code.clone(), record.name.clone())
+}
+
+fn atom_from_row(sites: &CifTable, row: usize, block_name: &str) -> Result<AtomLine, CifError> {
+    let number = |item: &str| -> Result<f64, CifError> {
+        let data_error = |message: String| CifError::Data { block: block_name.to_string(), message };
+        let value = sites.get(row, item).ok_or_else(|| data_error(format!("_atom_site row {}: missing {}", row + 1, item)))?;
+        value.parse::<f64>().map_err(|_| data_error(format!("_atom_site row {}: invalid {} '{}'", row + 1, item, value)))
+    };
+    let coord = (number("Cartn_x")?, number("Cartn_y")?, number("Cartn_z")?);
+    let name = sites.get_any(row, &["auth_atom_id", "label_atom_id"]).unwrap_or("").to_string();
+    let element = match sites.get(row, "type_symbol") {
+        Some(symbol) => normalize_element(symbol),
+        None => normalize_element(&name.chars().take_while(|c| c.is_ascii_alphabetic()).take(1).collect::<String>()),
+    };
+    let record = AtomRecord {
+        hetatm: sites.get(row, "group_PDB") == Some("HETATM"),
+        serial: number("id")? as usize,
+        name,
+        alt_loc: sites.get(row, "label_alt_id").unwrap_or("").to_string(),
+        res_name: sites.get_any(row, &["auth_comp_id", "label_comp_id"]).unwrap_or("").to_string(),
+        chain_id: sites.get_any(row, &["auth_asym_id", "label_asym_id"]).unwrap_or("").to_string(),
+        res_seq: sites.get_any(row, &["auth_seq_id", "label_seq_id"]).and_then(|s| s.parse().ok()).unwrap_or(0),
+        i_code: sites.get(row, "pdbx_PDB_ins_code").unwrap_or("").to_string(),
+        occupancy: sites.get(row, "occupancy").map(|_| number("occupancy")).transpose()?.unwrap_or(1.0),
+        temp_factor: sites.get(row, "B_iso_or_equiv").map(|_| number("B_iso_or_equiv")).transpose()?.unwrap_or(0.0),
+        segment_id: String::new(),
+        charge: pdb_charge(sites.get(row, "pdbx_formal_charge")),
+    };
+    Ok((record, coord, element))
+}
+
+/// The `_cell` category, ignoring standard uncertainties such as the `(3)` in `50.123(3)`.
+fn cell_from_block(block: &CifBlock) -> Result<Option<UnitCell>, CifError> {
+    let Some(cell) = block.category("cell") else { return Ok(None) };
+    let value = |item: &str| -> Option<f64> { cell.get(0, item)?.split('(').next()?.parse().ok() };
+    let (Some(a), Some(b), Some(c)) = (value("length_a"), value("length_b"), value("length_c")) else { return Ok(None) };
+    let angle = |item: &str| value(item).unwrap_or(90.0);
+    UnitCell::from_cryst1([a, b, c, angle("angle_alpha"), angle("angle_beta"), angle("angle_gamma")])
+        .map_err(|e| CifError::Data { block: block.name.clone(), message: format!("_cell: {}", e) })
+}
+
+/// Convert a data block's `_atom_site` and `_struct_conn` categories into a `PdbFilePy`.
+///
+/// Author numbering (`auth_*`) is preferred where present, matching what a PDB file would hold.
+/// Only the first model of a multi-model block is read.
+pub fn structure_from_block(block: &CifBlock, strict: bool) -> Result<PdbFilePy, CifError> {
+    let data_error = |message: String| CifError::Data { block: block.name.clone(), message };
+    let sites = block.category("atom_site").ok_or_else(|| data_error("no _atom_site category".to_string()))?;
+
+    let mut coords = Vec::new();
+    let mut atom_types = Vec::new();
+    let mut atoms = Vec::new();
+    let mut warnings = Vec::new();
+    let first_model = sites.get(0, "pdbx_PDB_model_num").map(|m| m.to_string());
+    for row in 0..sites.rows.len() {
+        if sites.get(row, "pdbx_PDB_model_num").map(|m| m.to_string()) != first_model {
+            continue;
+        }
+        let parsed = atom_from_row(sites, row, &block.name);
+        match parsed {
+            Ok((record, coord, element)) => {
+                atoms.push(record);
+                coords.push(coord);
+                atom_types.push(element);
+            }
+            Err(e) if !strict => warnings.push(e.to_string()),
+            Err(e) => return Err(e),
+        }
+    }
+
+    let mut bonds = Vec::new();
+    if let Some(conn) = block.category("struct_conn") {
+        let mut index_of: HashMap<AtomKey, usize> = HashMap::new();
+        for (i, record) in atoms.iter().enumerate() {
+            index_of.entry(atom_key(record)).or_insert(i);
+        }
+        for row in 0..conn.rows.len() {
+            // Hydrogen bonds are listed alongside covalent, disulfide and metal links
+            if conn.get(row, "conn_type_id").is_some_and(|t| t.eq_ignore_ascii_case("hydrog")) {
+                continue;
+            }
+            let partner = |n: u8| -> Option<usize> {
+                let p = |item: &str| format!("ptnr{}_{}", n, item);
+                let key = (
+                    conn.get_any(row, &[&p("auth_asym_id"), &p("label_asym_id")])?.to_string(),
+                    conn.get_any(row, &[&p("auth_seq_id"), &p("label_seq_id")])?.parse().ok()?,
+                    conn.get(row, &format!("pdbx_ptnr{}_PDB_ins_code", n)).unwrap_or("").to_string(),
+                    conn.get_any(row, &[&p("auth_atom_id"), &p("label_atom_id")])?.to_string(),
+                );
+                index_of.get(&key).copied()
+            };
+            match (partner(1), partner(2)) {
+                (Some(a), Some(b)) => bonds.push((a, b)),
+                _ => {
+                    let id = conn.get(row, "id").unwrap_or("?");
+                    let err = data_error(format!("_struct_conn {} refers to an atom not in _atom_site", id));
+                    if strict {
+                        return Err(err);
+                    }
+                    warnings.push(err.to_string());
+                }
+            }
+        }
+    }
+
+    Ok(PdbFilePy {
+        coords,
+        atom_types,
+        bonds,
+        typed_bonds: Vec::new(),
+        atoms,
+        cell: cell_from_block(block)?,
+        warnings,
+    })
+}
+
+/// Read the first data block of an mmCIF file that has atom sites.
+pub fn read_cif(file_path: &str, strict: bool) -> Result<PdbFilePy, CifError> {
+    let text = std::fs::read_to_string(file_path)?;
+    let blocks = parse_cif_blocks(&text)?;
+    let block = blocks
+        .iter()
+        .find(|b| b.category("atom_site").is_some())
+        .ok_or_else(|| CifError::Data { block: file_path.to_string(), message: "no data block has an _atom_site category".to_string() })?;
+    structure_from_block(block, strict)
+}
+
+/// Quote a value so it reads back as the same single token.
+fn cif_value(value: &str) -> String {
+    if value.is_empty() {
+        return "?".to_string();
+    }
+    if value.contains('\n') {
+        return format!("\n;{}\n;\n", value);
+    }
+    let needs_quotes = value.contains(char::is_whitespace)
+        || value.starts_with(['_', '#', '$', '\'', '"', ';', '[', ']'])
+        || value == "?"
+        || value == "."
+        || value.to_ascii_lowercase().starts_with("data_")
+        || value.eq_ignore_ascii_case("loop_");
+    if !needs_quotes {
+        value.to_string()
+    } else if !value.contains("' ") && !value.ends_with('\'') {
+        format!("'{}'", value)
+    } else {
+        format!("\"{}\"", value)
+    }
+}
+
+fn write_loop(writer: &mut impl Write, category: &str, items: &[&str], rows: &[Vec<String>]) -> std::io::Result<()> {
+    writeln!(writer, "loop_")?;
+    for item in items {
+        writeln!(writer, "_{}.{}", category, item)?;
+    }
+    for row in rows {
+        writeln!(writer, "{}", row.iter().map(|v| cif_value(v)).collect::<Vec<_>>().join(" "))?;
+    }
+    writeln!(writer, "#")
+}
+
+/// Write atoms as an `_atom_site` loop, and bonds (if given) as covalent `_struct_conn` links.
+pub fn write_cif(file_path: &str, data_name: &str, coords: &[(f64, f64, f64)], atom_types: &[String], atoms: &[AtomRecord], cell: Option<&UnitCell>, bonds: Option<&[(usize, usize)]>) -> std::io::Result<()> {
+    check_bonds(bonds.unwrap_or_default(), coords.len())?;
+    let file = File::create(file_path)?;
+    let mut writer = BufWriter::new(file);
+    writeln!(writer, "data_{}", data_name)?;
+    writeln!(writer, "#")?;
+    if let Some(cell) = cell {
+        let items = [("length_a", cell.a), ("length_b", cell.b), ("length_c", cell.c), ("angle_alpha", cell.alpha), ("angle_beta", cell.beta), ("angle_gamma", cell.gamma)];
+        for (item, value) in items {
+            writeln!(writer, "_cell.{:<12} {:.3}", item, value)?;
+        }
+        writeln!(writer, "#")?;
+    }
+
+    let records: Vec<AtomRecord> = (0..coords.len())
+        .map(|i| atoms.get(i).cloned().unwrap_or_else(|| AtomRecord::placeholder(i + 1, &atom_types[i])))
+        .collect();
+    let site_items = [
+        "group_PDB", "id", "type_symbol", "label_atom_id", "label_alt_id", "label_comp_id", "label_asym_id",
+        "label_seq_id", "pdbx_PDB_ins_code", "Cartn_x", "Cartn_y", "Cartn_z", "occupancy", "B_iso_or_equiv",
+        "pdbx_formal_charge", "auth_seq_id", "auth_comp_id", "auth_asym_id", "auth_atom_id", "pdbx_PDB_model_num",
+    ];
+    let site_rows: Vec<Vec<String>> = records
+        .iter()
+        .zip(coords)
+        .zip(atom_types)
+        .map(|((r, c), element)| {
+            vec![
+                if r.hetatm { "HETATM" } else { "ATOM" }.to_string(),
+                r.serial.to_string(),
+                element.to_uppercase(),
+                r.name.clone(),
+                r.alt_loc.clone(),
+                r.res_name.clone(),
+                r.chain_id.clone(),
+                r.res_seq.to_string(),
+                r.i_code.clone(),
+                format!("{:.3}", c.0),
+                format!("{:.3}", c.1),
+                format!("{:.3}", c.2),
+                format!("{:.2}", r.occupancy),
+                format!("{:.2}", r.temp_factor),
+                formal_charge(&r.charge).map(|c| c.to_string()).unwrap_or_default(),
+                r.res_seq.to_string(),
+                r.res_name.clone(),
+                r.chain_id.clone(),
+                r.name.clone(),
+                "1".to_string(),
+            ]
+        })
+        .collect();
+    write_loop(&mut writer, "atom_site", &site_items, &site_rows)?;
+
+    if let Some(bonds) = bonds.filter(|b| !b.is_empty()) {
+        let conn_items = [
+            "id", "conn_type_id",
+            "ptnr1_auth_asym_id", "ptnr1_auth_comp_id", "ptnr1_auth_seq_id", "pdbx_ptnr1_PDB_ins_code", "ptnr1_auth_atom_id",
+            "ptnr2_auth_asym_id", "ptnr2_auth_comp_id", "ptnr2_auth_seq_id", "pdbx_ptnr2_PDB_ins_code", "ptnr2_auth_atom_id",
+        ];
+        let conn_rows: Vec<Vec<String>> = bonds
+            .iter()
+            .enumerate()
+            .map(|(n, &(a, b))| {
+                let (a, b) = (&records[a], &records[b]);
+                vec![
+                    format!("covale{}", n + 1),
+                    "covale".to_string(),
+                    a.chain_id.clone(), a.res_name.clone(), a.res_seq.to_string(), a.i_code.clone(), a.name.clone(),
+                    b.chain_id.clone(), b.res_name.clone(), b.res_seq.to_string(), b.i_code.clone(), b.name.clone(),
+                ]
+            })
+            .collect();
+        write_loop(&mut writer, "struct_conn", &conn_items, &conn_rows)?;
+    }
+    Ok(())
+}
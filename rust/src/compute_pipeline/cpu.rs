@@ -0,0 +1,317 @@
+use rand::Rng;
+use rayon::prelude::*;
+use std::collections::BTreeMap;
+
+use crate::compute_pipeline::dynamics::{
+    accept_volume_move, berendsen_scaling, berendsen_thermostat, bussi_thermostat, kinetic_energy, langevin_thermostat, pressure,
+    scale_box, MD_UNITS,
+};
+use crate::compute_pipeline::forcefield::{add_outer, bonded_forces, displacement, trace, Energies, PairPotential, Topology, Virial};
+use crate::compute_pipeline::minimizer::minimize;
+use crate::compute_pipeline::{AtomPipelineParams, Barostat, PipelineError, PipelineOutput, Thermostat};
+use crate::rng::seeded;
+use crate::unit_cell::UnitCell;
+
+/// The virial of each interacting pair of atoms, as [`pair_virials`] gives them.
+pub(crate) type PairVirials = Vec<((usize, usize), Virial)>;
+
+/// Forces in kcal/(mol·Å), with the potential energy of each term and the virial.
+pub(crate) struct Evaluation {
+    pub forces: Vec<[f64; 3]>,
+    pub energies: Energies,
+    pub virial: Virial,
+}
+
+impl Evaluation {
+    /// The total potential energy in kcal/mol.
+    pub fn energy(&self) -> f64 {
+        self.energies.total()
+    }
+}
+
+// Every pair within the cutoff that the topology does not exclude, plus the bonded terms.
+pub(crate) fn evaluate(coords: &[[f64; 3]], topology: &Topology, pair: &PairPotential, cell: Option<&UnitCell>) -> Evaluation {
+    let per_atom: Vec<([f64; 3], Energies, Virial)> = coords
+        .par_iter()
+        .enumerate()
+        .map(|(index, &pos)| {
+            let mut force = [0.0; 3];
+            let mut energies = Energies::default();
+            let mut virial = [[0.0; 3]; 3];
+            for (i, &other) in coords.iter().enumerate() {
+                if i != index && !topology.is_excluded(index, i) {
+                    let d = displacement(pos, other, cell);
+                    let (lj, coulomb, f) = pair.energy_force(d, topology.atom_params[index], topology.atom_params[i]);
+                    let f_i = [f * d[0], f * d[1], f * d[2]];
+                    force[0] += f_i[0];
+                    force[1] += f_i[1];
+                    force[2] += f_i[2];
+                    energies.lennard_jones += lj;
+                    energies.coulomb += coulomb;
+                    add_outer(&mut virial, d, f_i);
+                }
+            }
+            (force, energies, virial)
+        })
+        .collect();
+    let mut forces: Vec<[f64; 3]> = per_atom.iter().map(|p| p.0).collect();
+    let (mut energies, mut virial) = bonded_forces(coords, &topology.terms, cell, &mut forces, |_, _, _| {});
+    topology.spread_virtual_forces(&mut forces);
+    // Each pair was visited from both ends
+    for (_, pair_energies, pair_virial) in &per_atom {
+        energies.lennard_jones += 0.5 * pair_energies.lennard_jones;
+        energies.coulomb += 0.5 * pair_energies.coulomb;
+        for (row, pair_row) in virial.iter_mut().zip(pair_virial) {
+            for (w, p) in row.iter_mut().zip(pair_row) {
+                *w += 0.5 * p;
+            }
+        }
+    }
+    Evaluation { forces, energies, virial }
+}
+
+/// The virial r ⊗ F of each pair of atoms that interact, in ascending order of the pairs,
+/// so that they sum to the virial [`evaluate`] gives. Nonbonded pairs and bonds take the
+/// displacement between their atoms; angles and torsions split theirs into a pair for each
+/// outer atom with the atom their displacements are measured from.
+pub(crate) fn pair_virials(coords: &[[f64; 3]], topology: &Topology, pair: &PairPotential, cell: Option<&UnitCell>) -> PairVirials {
+    let mut virials: BTreeMap<(usize, usize), Virial> = BTreeMap::new();
+    let mut add = |(a, b): (usize, usize), r: [f64; 3], f: [f64; 3]| {
+        add_outer(virials.entry((a.min(b), a.max(b))).or_insert([[0.0; 3]; 3]), r, f);
+    };
+    for i in 0..coords.len() {
+        for j in i + 1..coords.len() {
+            if topology.is_excluded(i, j) {
+                continue;
+            }
+            let d = displacement(coords[i], coords[j], cell);
+            let (_, _, f) = pair.energy_force(d, topology.atom_params[i], topology.atom_params[j]);
+            if f != 0.0 {
+                add((i, j), d, [f * d[0], f * d[1], f * d[2]]);
+            }
+        }
+    }
+    bonded_forces(coords, &topology.terms, cell, &mut vec![[0.0; 3]; coords.len()], &mut add);
+    virials.into_iter().collect()
+}
+
+/// Step every atom along its force, as the relax shader does.
+///
+/// Forces for a step are evaluated from a snapshot of the previous step's coordinates.
+fn descend(coords: &mut [[f64; 3]], topology: &Topology, pair: &PairPotential, step_size: f64, max_steps: u32, cell: Option<&UnitCell>) {
+    for _ in 0..max_steps {
+        let forces = evaluate(coords, topology, pair, cell).forces;
+        coords.par_iter_mut().zip(forces.par_iter()).for_each(|(c, f)| {
+            c[0] += f[0] * step_size;
+            c[1] += f[1] * step_size;
+            c[2] += f[2] * step_size;
+        });
+        topology.place_virtual_sites(coords, cell);
+    }
+}
+
+/// Velocity Verlet with a time step of `params.step_size` ps, as the simulate shader does,
+/// with the thermostat and barostat `params` asks for. A barostat rescales `cell`.
+fn velocity_verlet(
+    coords: &mut [[f64; 3]],
+    velocities: &mut [[f64; 3]],
+    cell: &mut Option<UnitCell>,
+    topology: &Topology,
+    pair: &PairPotential,
+    params: &AtomPipelineParams,
+) {
+    let dt = params.step_size as f64;
+    let temperature = params.temperature as f64;
+    let masses: Vec<f64> = topology.atom_params.iter().map(|p| p[3] as f64).collect();
+    let molecule_count = topology.molecules.iter().max().map_or(0, |&m| m + 1);
+    // A stream of its own, so it does not repeat the draws of the starting velocities
+    let mut rng = seeded(params.seed);
+    rng.set_stream(1);
+
+    let mut evaluation = evaluate(coords, topology, pair, cell.as_ref());
+    for step in 1..=params.max_steps {
+        // Massless virtual sites are placed rather than integrated
+        for i in (0..coords.len()).filter(|&i| masses[i] > 0.0) {
+            for k in 0..3 {
+                velocities[i][k] += 0.5 * dt * evaluation.forces[i][k] * MD_UNITS / masses[i];
+                coords[i][k] += dt * velocities[i][k];
+            }
+        }
+        topology.place_virtual_sites(coords, cell.as_ref());
+        // Scale with the pressure of the last force evaluation, so the forces below see the new box
+        if let (Some(Barostat::Berendsen), Some(c)) = (params.barostat, cell.as_mut()) {
+            let current = pressure(kinetic_energy(velocities, &masses), trace(&evaluation.virial), c.volume());
+            let factor = berendsen_scaling(
+                current,
+                params.pressure as f64,
+                params.compressibility as f64,
+                dt,
+                params.barostat_tau as f64,
+            );
+            *c = scale_box(coords, &topology.molecules, &masses, c, factor);
+        }
+        evaluation = evaluate(coords, topology, pair, cell.as_ref());
+        for ((v, f), m) in velocities.iter_mut().zip(&evaluation.forces).zip(&masses).filter(|(_, &m)| m > 0.0) {
+            for k in 0..3 {
+                v[k] += 0.5 * dt * f[k] * MD_UNITS / m;
+            }
+        }
+
+        let tau = params.thermostat_tau as f64;
+        match params.thermostat {
+            Some(Thermostat::Berendsen) => berendsen_thermostat(velocities, &masses, temperature, dt, tau),
+            Some(Thermostat::VRescale) => bussi_thermostat(velocities, &masses, temperature, dt, tau, &mut rng),
+            Some(Thermostat::Langevin) => langevin_thermostat(velocities, &masses, temperature, dt, tau, &mut rng),
+            None => {}
+        }
+
+        if let (Some(Barostat::MonteCarlo), Some(c)) = (params.barostat, *cell) {
+            if step % params.barostat_interval.max(1) == 0 {
+                let volume = c.volume();
+                let new_volume = volume + volume * rng.gen_range(-0.01..0.01);
+                let saved = coords.to_vec();
+                let scaled = scale_box(coords, &topology.molecules, &masses, &c, (new_volume / volume).cbrt());
+                let trial = evaluate(coords, topology, pair, Some(&scaled));
+                let accepted = accept_volume_move(
+                    trial.energy() - evaluation.energy(),
+                    volume,
+                    new_volume,
+                    molecule_count,
+                    params.pressure as f64,
+                    temperature,
+                    &mut rng,
+                );
+                if accepted {
+                    *cell = Some(scaled);
+                    evaluation = trial;
+                } else {
+                    coords.copy_from_slice(&saved);
+                }
+            }
+        }
+    }
+}
+
+pub(crate) fn run_cpu_pipeline(
+    coords: &[[f64; 3]],
+    velocities: &[[f64; 3]],
+    atom_types: &[String],
+    bonds: &[(usize, usize)],
+    charges: Option<&[f64]>,
+    params: AtomPipelineParams,
+) -> Result<PipelineOutput, PipelineError> {
+    let mut cell = params.cell;
+    let topology = Topology::new(coords, atom_types, bonds, charges, cell.as_ref())?;
+    let pair = PairPotential::new(&params)?;
+    let mut coords = coords.to_vec();
+    let mut velocities = velocities.to_vec();
+    let step_size = params.step_size as f64;
+    let mut minimization = None;
+    match params.process_type {
+        0 => descend(&mut coords, &topology, &pair, step_size, params.max_steps, cell.as_ref()),
+        1 => minimization = Some(minimize(&mut coords, &topology, &pair, &params)),
+        2 => velocity_verlet(&mut coords, &mut velocities, &mut cell, &topology, &pair, &params),
+        t => return Err(PipelineError::InvalidProcessType(t)),
+    }
+    Ok(PipelineOutput { coords, velocities, cell, minimization })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compute_pipeline::{run_atom_pipeline, AtomPipelineParams, Backend, Thermostat};
+    use crate::unit_cell::UnitCell;
+
+    #[test]
+    fn pair_virials_sum_to_the_virial() {
+        // A bent, twisted chain with bonds, angles and a torsion, and an ion beside it
+        let coords = [[0.0, 0.0, 0.0], [1.55, 0.1, 0.0], [2.1, 1.5, 0.2], [3.6, 1.7, 0.9], [4.2, 2.9, 0.3], [2.0, -2.5, 1.0]];
+        let atom_types: Vec<String> = ["C", "C", "C", "C", "O", "Na"].iter().map(|t| t.to_string()).collect();
+        let topology = Topology::new(&coords, &atom_types, &[(0, 1), (1, 2), (2, 3), (3, 4)], None, None).unwrap();
+        let pair = PairPotential::new(&AtomPipelineParams::default()).unwrap();
+        // Strained away from the starting geometry so that every term pulls
+        let stretched: Vec<[f64; 3]> = coords.iter().map(|c| [c[0] * 1.1, c[1], c[2] * 0.8]).collect();
+        let total = evaluate(&stretched, &topology, &pair, None).virial;
+        let mut sum = [[0.0; 3]; 3];
+        for (_, v) in pair_virials(&stretched, &topology, &pair, None) {
+            for (row, v_row) in sum.iter_mut().zip(&v) {
+                for (w, x) in row.iter_mut().zip(v_row) {
+                    *w += x;
+                }
+            }
+        }
+        for (row, total_row) in sum.iter().zip(&total) {
+            for (w, t) in row.iter().zip(total_row) {
+                assert!((w - t).abs() < 1e-9 * t.abs().max(1.0), "{:?} vs {:?}", sum, total);
+            }
+        }
+    }
+
+    #[test]
+    fn same_seed_repeats_a_stochastic_simulation() {
+        let coords: Vec<[f64; 3]> = (0..8).map(|i| [(i % 2) as f64 * 3.5, (i / 2 % 2) as f64 * 3.5, (i / 4) as f64 * 3.5]).collect();
+        let atom_types: Vec<String> = (0..8).map(|i| if i % 2 == 0 { "Na" } else { "Cl" }.to_string()).collect();
+        let run = |seed| {
+            let params = AtomPipelineParams {
+                process_type: 2,
+                max_steps: 20,
+                backend: Backend::Cpu,
+                cell: Some(UnitCell::orthorhombic(7.0, 7.0, 7.0).unwrap()),
+                cutoff: 3.4,
+                thermostat: Some(Thermostat::Langevin),
+                seed: Some(seed),
+                ..Default::default()
+            };
+            run_atom_pipeline(&coords, None, &atom_types, &[], None, params).unwrap()
+        };
+        let (a, b, c) = (run(42), run(42), run(43));
+        assert_eq!(a.coords, b.coords);
+        assert_eq!(a.velocities, b.velocities);
+        assert_ne!(a.velocities, c.velocities);
+    }
+
+    #[test]
+    fn mismatched_atom_types_are_an_error() {
+        let coords = [[0.0, 0.0, 0.0], [1.5, 0.0, 0.0], [3.0, 0.0, 0.0]];
+        let atom_types: Vec<String> = ["C", "C"].iter().map(|t| t.to_string()).collect();
+        let params = AtomPipelineParams { backend: Backend::Cpu, ..Default::default() };
+        let result = run_atom_pipeline(&coords, None, &atom_types, &[(0, 1), (1, 2)], None, params);
+        assert!(matches!(result, Err(PipelineError::InvalidAtomTypes(2))), "{:?}", result.err());
+    }
+
+    #[test]
+    fn virtual_sites_follow_their_water() {
+        // Two TIP4P waters 3 Å apart, O, H1, H2 and EPW each
+        let water = [[17.892, 1.125, 16.555], [18.272, 1.904, 16.149], [17.987, 1.275, 17.496], [17.953, 1.244, 16.624]];
+        let coords: Vec<[f64; 3]> = (0..8).map(|i| {
+            let p = water[i % 4];
+            [p[0] - if i < 4 { 15.0 } else { 12.0 }, p[1], p[2] - 15.0]
+        }).collect();
+        let atom_types: Vec<String> = ["O", "H", "H", "EP"].iter().cycle().take(8).map(|t| t.to_string()).collect();
+        let bonds = [(0, 1), (0, 2), (4, 5), (4, 6)];
+        let charges: Vec<f64> = [0.0, 0.52, 0.52, -1.04].iter().cycle().take(8).copied().collect();
+        let params = AtomPipelineParams {
+            process_type: 2,
+            max_steps: 50,
+            step_size: 0.0005,
+            backend: Backend::Cpu,
+            temperature: 300.0,
+            seed: Some(1),
+            ..Default::default()
+        };
+        let output = run_atom_pipeline(&coords, None, &atom_types, &bonds, Some(&charges), params).unwrap();
+
+        let length = |a: [f64; 3], b: [f64; 3]| displacement(a, b, None).iter().map(|x| x * x).sum::<f64>().sqrt();
+        for w in [0, 4] {
+            let c = &output.coords[w..w + 4];
+            assert!((length(c[3], c[0]) - length(coords[w + 3], coords[w])).abs() < 0.02, "{:?}", c);
+            assert_eq!(output.velocities[w + 3], [0.0; 3]);
+        }
+
+        let topology = Topology::new(&coords, &atom_types, &bonds, Some(&charges), None).unwrap();
+        let evaluation = evaluate(&coords, &topology, &PairPotential::new(&params).unwrap(), None);
+        assert_eq!(evaluation.forces[3], [0.0; 3]);
+        let net: Vec<f64> = (0..3).map(|k| evaluation.forces.iter().map(|f| f[k]).sum()).collect();
+        assert!(net.iter().all(|f| f.abs() < 1e-6), "{:?}", net);
+    }
+}
@@ -0,0 +1,195 @@
+use rand::Rng;
+
+use crate::compute_pipeline::forcefield::displacement;
+use crate::compute_pipeline::PipelineError;
+use crate::unit_cell::UnitCell;
+use crate::utilities::atom::atom_mass;
+
+/// amu·Å²/ps² per kcal/mol, so that force × `MD_UNITS` / mass is an acceleration in Å/ps².
+pub(crate) const MD_UNITS: f64 = 418.4;
+
+/// Boltzmann's constant in kcal/(mol·K).
+pub(crate) const BOLTZMANN: f64 = 0.0019872041;
+
+/// bar per kcal/(mol·Å³).
+pub(crate) const BAR: f64 = 69476.95;
+
+/// A standard normal deviate by the Box–Muller transform, since rand has no normal distribution of its own.
+fn standard_normal(rng: &mut impl Rng) -> f64 {
+    let u: f64 = 1.0 - rng.gen::<f64>();
+    let v: f64 = rng.gen();
+    (-2.0 * u.ln()).sqrt() * (2.0 * std::f64::consts::PI * v).cos()
+}
+
+/// The mass of each atom in amu, from the periodic table unless `atom_properties.yml` overrides it.
+pub(crate) fn masses(atom_types: &[String]) -> Result<Vec<f64>, PipelineError> {
+    atom_types
+        .iter()
+        .map(|t| atom_mass(t)?.ok_or_else(|| PipelineError::UnknownMass(t.clone())))
+        .collect()
+}
+
+/// Velocities in Å/ps drawn from the Maxwell–Boltzmann distribution at `temperature` K,
+/// with the centre-of-mass motion removed. Massless virtual sites stay still.
+pub(crate) fn maxwell_boltzmann(masses: &[f64], temperature: f64, rng: &mut impl Rng) -> Vec<[f64; 3]> {
+    let mut normal = || standard_normal(rng);
+    let mut velocities: Vec<[f64; 3]> = masses
+        .iter()
+        .map(|&m| {
+            if m == 0.0 {
+                return [0.0; 3];
+            }
+            let sd = (BOLTZMANN * temperature * MD_UNITS / m).sqrt();
+            [normal() * sd, normal() * sd, normal() * sd]
+        })
+        .collect();
+
+    let total_mass: f64 = masses.iter().sum();
+    let mut momentum = [0.0; 3];
+    for (v, &m) in velocities.iter().zip(masses) {
+        for k in 0..3 {
+            momentum[k] += m * v[k];
+        }
+    }
+    for (v, _) in velocities.iter_mut().zip(masses).filter(|(_, &m)| m > 0.0) {
+        for k in 0..3 {
+            v[k] -= momentum[k] / total_mass;
+        }
+    }
+    velocities
+}
+
+/// Kinetic energy in kcal/mol.
+pub(crate) fn kinetic_energy(velocities: &[[f64; 3]], masses: &[f64]) -> f64 {
+    velocities
+        .iter()
+        .zip(masses)
+        .map(|(v, &m)| 0.5 * m * (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]))
+        .sum::<f64>()
+        / MD_UNITS
+}
+
+/// Degrees of freedom of the atoms with mass, with the centre of mass held still.
+fn degrees_of_freedom(masses: &[f64]) -> usize {
+    let atoms = masses.iter().filter(|&&m| m > 0.0).count();
+    if atoms > 1 { 3 * atoms - 3 } else { 3 * atoms }
+}
+
+/// The instantaneous temperature in K, leaving out the three centre-of-mass degrees of
+/// freedom and the massless virtual sites.
+pub(crate) fn temperature(kinetic_energy: f64, masses: &[f64]) -> f64 {
+    let dof = degrees_of_freedom(masses);
+    if dof == 0 {
+        return 0.0;
+    }
+    2.0 * kinetic_energy / (dof as f64 * BOLTZMANN)
+}
+
+/// The pressure in bar from the kinetic energy and the virial Σ r·F, both in kcal/mol,
+/// in a box of `volume` Å³.
+pub(crate) fn pressure(kinetic_energy: f64, virial: f64, volume: f64) -> f64 {
+    (2.0 * kinetic_energy + virial) / (3.0 * volume) * BAR
+}
+
+fn rescale(velocities: &mut [[f64; 3]], factor: f64) {
+    for v in velocities.iter_mut() {
+        for x in v.iter_mut() {
+            *x *= factor;
+        }
+    }
+}
+
+/// Relax the temperature toward `target` K with time constant `tau` ps (Berendsen et al. 1984).
+pub(crate) fn berendsen_thermostat(velocities: &mut [[f64; 3]], masses: &[f64], target: f64, dt: f64, tau: f64) {
+    let current = temperature(kinetic_energy(velocities, masses), masses);
+    if current > 0.0 {
+        rescale(velocities, (1.0 + dt / tau * (target / current - 1.0)).max(0.0).sqrt());
+    }
+}
+
+/// Stochastic velocity rescaling toward `target` K with time constant `tau` ps, which
+/// samples the canonical ensemble (Bussi, Donadio and Parrinello 2007).
+pub(crate) fn bussi_thermostat(velocities: &mut [[f64; 3]], masses: &[f64], target: f64, dt: f64, tau: f64, rng: &mut impl Rng) {
+    let dof = degrees_of_freedom(masses);
+    let current = kinetic_energy(velocities, masses);
+    if dof == 0 || current <= 0.0 {
+        return;
+    }
+    let n = dof as f64;
+    let goal = 0.5 * n * BOLTZMANN * target;
+    let c = (-dt / tau).exp();
+    let r1 = standard_normal(rng);
+    let rest: f64 = (1..dof).map(|_| standard_normal(rng).powi(2)).sum();
+    let new = current
+        + (1.0 - c) * (goal * (r1 * r1 + rest) / n - current)
+        + 2.0 * r1 * (c * (1.0 - c) * current * goal / n).sqrt();
+    rescale(velocities, (new.max(0.0) / current).sqrt());
+}
+
+/// Langevin friction with collision time `tau` ps and the matching random kicks at `target` K.
+pub(crate) fn langevin_thermostat(velocities: &mut [[f64; 3]], masses: &[f64], target: f64, dt: f64, tau: f64, rng: &mut impl Rng) {
+    let c1 = (-dt / tau).exp();
+    for (v, &m) in velocities.iter_mut().zip(masses).filter(|(_, &m)| m > 0.0) {
+        let sd = ((1.0 - c1 * c1) * BOLTZMANN * target * MD_UNITS / m).sqrt();
+        for x in v.iter_mut() {
+            *x = c1 * *x + sd * standard_normal(rng);
+        }
+    }
+}
+
+/// Scale the box by `factor` along every edge, moving each molecule with its centre of
+/// mass so that bond lengths are left alone. Returns the scaled cell.
+pub(crate) fn scale_box(coords: &mut [[f64; 3]], molecules: &[usize], masses: &[f64], cell: &UnitCell, factor: f64) -> UnitCell {
+    let count = molecules.iter().max().map_or(0, |&m| m + 1);
+    let mut first: Vec<Option<usize>> = vec![None; count];
+    let mut mass = vec![0.0; count];
+    let mut moment = vec![[0.0; 3]; count];
+    // Measure each atom from the first atom of its molecule, so molecules split by the
+    // boundaries are whole
+    for (i, &m) in molecules.iter().enumerate() {
+        let origin = *first[m].get_or_insert(i);
+        let d = displacement(coords[i], coords[origin], Some(cell));
+        mass[m] += masses[i];
+        for k in 0..3 {
+            moment[m][k] += masses[i] * d[k];
+        }
+    }
+    let shift: Vec<[f64; 3]> = (0..count)
+        .map(|m| match first[m] {
+            Some(origin) if mass[m] > 0.0 => {
+                let centre = [0, 1, 2].map(|k| coords[origin][k] + moment[m][k] / mass[m]);
+                centre.map(|x| x * (factor - 1.0))
+            }
+            _ => [0.0; 3],
+        })
+        .collect();
+    for (c, &m) in coords.iter_mut().zip(molecules) {
+        for k in 0..3 {
+            c[k] += shift[m][k];
+        }
+    }
+    cell.scaled(factor)
+}
+
+/// The Berendsen barostat's box scaling factor for one step at pressure `current` bar,
+/// relaxing toward `target` bar with time constant `tau` ps.
+pub(crate) fn berendsen_scaling(current: f64, target: f64, compressibility: f64, dt: f64, tau: f64) -> f64 {
+    (1.0 - compressibility * dt / tau * (target - current)).max(0.0).cbrt()
+}
+
+/// Whether to accept a Monte Carlo volume move from `volume` to `new_volume` Å³ that
+/// changed the potential energy by `delta_energy` kcal/mol, for `molecules` molecules
+/// scaled about their centres at `pressure` bar and `temperature` K.
+pub(crate) fn accept_volume_move(
+    delta_energy: f64,
+    volume: f64,
+    new_volume: f64,
+    molecules: usize,
+    pressure: f64,
+    temperature: f64,
+    rng: &mut impl Rng,
+) -> bool {
+    let kt = BOLTZMANN * temperature;
+    let work = delta_energy + pressure / BAR * (new_volume - volume) - molecules as f64 * kt * (new_volume / volume).ln();
+    work <= 0.0 || rng.gen::<f64>() < (-work / kt).exp()
+}
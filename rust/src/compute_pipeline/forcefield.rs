@@ -0,0 +1,601 @@
+use bytemuck::{Pod, Zeroable};
+use std::collections::{BTreeSet, HashMap};
+
+use crate::compute_pipeline::{AtomPipelineParams, Electrostatics, PipelineError};
+use crate::neighbors::CellList;
+use crate::perception::{perceive, Hybridization, Perception};
+use crate::unit_cell::UnitCell;
+use crate::utilities::atom::atom_mass;
+use crate::utilities::forcefield::{force_field, BondOrder, ForceField, Mixing};
+use crate::utilities::DataError;
+
+/// Coulomb's constant in kcal·Å/(mol·e²).
+pub(crate) const COULOMB: f64 = 332.0637;
+
+pub(crate) const BOND: u32 = 0;
+pub(crate) const ANGLE: u32 = 1;
+pub(crate) const TORSION: u32 = 2;
+
+/// A bond, angle or torsion, laid out as `BondedTerm` in the shaders. Unused atom slots are zero.
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+#[repr(C)]
+pub(crate) struct BondedTerm {
+    pub kind: u32,
+    pub atoms: [u32; 4],
+    pub k: f32,
+    /// r0 in Å, or theta0 and phi0 in radians.
+    pub equilibrium: f32,
+    /// The multiplicity n of a torsion.
+    pub periodicity: f32,
+}
+
+/// The atom type of massless charge sites, such as TIP4P's EPW.
+const VIRTUAL_SITE: &str = "EP";
+
+/// How far in Å an `EP` neither bonded to nor following its water looks for the oxygen;
+/// TIP4P's sits 0.15 Å from it.
+const VIRTUAL_SITE_REACH: f64 = 1.0;
+
+/// A massless site on the bisector of a water's H–O–H angle, kept at
+/// `origin + weight · ((h1 − origin) + (h2 − origin))` for the `arms` h1 and h2. The force
+/// on it passes to the three atoms it is placed from.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct VirtualSite {
+    pub site: usize,
+    pub origin: usize,
+    pub arms: [usize; 2],
+    pub weight: f64,
+}
+
+/// The force-field terms of a structure.
+pub(crate) struct Topology {
+    /// Sigma, epsilon, charge and mass of each atom, as a `vec4<f32>` for the shaders.
+    /// Atom types neither `atom_properties.yml` nor the periodic table knows have zero mass.
+    pub atom_params: Vec<[f32; 4]>,
+    pub terms: Vec<BondedTerm>,
+    /// For each atom, the atoms one or two bonds away in ascending order. Those pairs
+    /// interact only through their bond and angle terms.
+    pub exclusions: Vec<Vec<usize>>,
+    /// The molecule each atom belongs to, numbering bonded groups from zero.
+    pub molecules: Vec<usize>,
+    pub virtual_sites: Vec<VirtualSite>,
+}
+
+/// The potential energy of a structure by term, in kcal/mol.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct Energies {
+    pub bond: f64,
+    pub angle: f64,
+    pub torsion: f64,
+    pub lennard_jones: f64,
+    pub coulomb: f64,
+}
+
+impl Energies {
+    pub fn total(&self) -> f64 {
+        self.bond + self.angle + self.torsion + self.lennard_jones + self.coulomb
+    }
+}
+
+/// The virial tensor Σ r ⊗ F in kcal/mol, whose trace is the scalar virial Σ r·F.
+pub(crate) type Virial = [[f64; 3]; 3];
+
+type Vec3 = [f64; 3];
+
+fn sub(a: Vec3, b: Vec3) -> Vec3 {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn dot(a: Vec3, b: Vec3) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: Vec3, b: Vec3) -> Vec3 {
+    [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+fn scale(a: Vec3, s: f64) -> Vec3 {
+    [a[0] * s, a[1] * s, a[2] * s]
+}
+
+fn add_to(f: &mut Vec3, a: Vec3) {
+    f[0] += a[0];
+    f[1] += a[1];
+    f[2] += a[2];
+}
+
+/// Add the outer product `r ⊗ f` to `virial`.
+pub(crate) fn add_outer(virial: &mut Virial, r: Vec3, f: Vec3) {
+    for (row, &x) in virial.iter_mut().zip(&r) {
+        for (w, &y) in row.iter_mut().zip(&f) {
+            *w += x * y;
+        }
+    }
+}
+
+pub(crate) fn trace(virial: &Virial) -> f64 {
+    virial[0][0] + virial[1][1] + virial[2][2]
+}
+
+/// `a - b`, through the periodic boundaries if there are any.
+pub(crate) fn displacement(a: Vec3, b: Vec3, cell: Option<&UnitCell>) -> Vec3 {
+    let d = sub(a, b);
+    match cell {
+        Some(cell) => {
+            let (x, y, z) = cell.minimum_image((d[0], d[1], d[2]));
+            [x, y, z]
+        }
+        None => d,
+    }
+}
+
+fn bond_length(coords: &[Vec3], i: usize, j: usize, cell: Option<&UnitCell>) -> f64 {
+    dot(displacement(coords[i], coords[j], cell), displacement(coords[i], coords[j], cell)).sqrt()
+}
+
+fn bond_angle(coords: &[Vec3], i: usize, j: usize, l: usize, cell: Option<&UnitCell>) -> f64 {
+    let a = displacement(coords[i], coords[j], cell);
+    let b = displacement(coords[l], coords[j], cell);
+    (dot(a, b) / (dot(a, a) * dot(b, b)).sqrt()).clamp(-1.0, 1.0).acos()
+}
+
+/// The i-j-k-l dihedral in radians, signed as in GROMACS.
+fn dihedral(coords: &[Vec3], [i, j, k, l]: [usize; 4], cell: Option<&UnitCell>) -> f64 {
+    let r_ij = displacement(coords[i], coords[j], cell);
+    let r_kj = displacement(coords[k], coords[j], cell);
+    let r_kl = displacement(coords[k], coords[l], cell);
+    let m = cross(r_ij, r_kj);
+    let n = cross(r_kj, r_kl);
+    let phi = (dot(m, n) / (dot(m, m) * dot(n, n)).sqrt()).clamp(-1.0, 1.0).acos();
+    if dot(r_ij, n) < 0.0 { -phi } else { phi }
+}
+
+/// How each bond is keyed in `forcefield.yml`, by its perceived order. A single bond is
+/// delocalized between two sp2 or sp atoms, and from a terminal N, O or S to an atom with a
+/// double bond to another, as in a carboxylate.
+fn bond_orders(perception: &Perception, atom_types: &[String]) -> HashMap<(usize, usize), BondOrder> {
+    let mut degree = vec![0; atom_types.len()];
+    for bond in &perception.bonds {
+        degree[bond.atoms.0] += 1;
+        degree[bond.atoms.1] += 1;
+    }
+    let terminal = |i: usize| degree[i] == 1 && matches!(atom_types[i].as_str(), "N" | "O" | "S");
+    let mut double_to_terminal = vec![false; atom_types.len()];
+    for bond in perception.bonds.iter().filter(|b| b.order == 2) {
+        let (i, j) = bond.atoms;
+        double_to_terminal[i] |= terminal(j);
+        double_to_terminal[j] |= terminal(i);
+    }
+    let conjugated = |i: usize| matches!(perception.hybridization[i], Hybridization::Sp2 | Hybridization::Sp);
+
+    perception
+        .bonds
+        .iter()
+        .map(|bond| {
+            let (i, j) = bond.atoms;
+            let order = match bond.order {
+                _ if bond.aromatic => BondOrder::Delocalized,
+                2 => BondOrder::Double,
+                3 => BondOrder::Triple,
+                _ if (conjugated(i) && conjugated(j))
+                    || (terminal(i) && double_to_terminal[j])
+                    || (terminal(j) && double_to_terminal[i]) =>
+                {
+                    BondOrder::Delocalized
+                }
+                _ => BondOrder::Single,
+            };
+            ((i.min(j), i.max(j)), order)
+        })
+        .collect()
+}
+
+/// The reference angle in degrees for a centre of hybridization `h`, if `forcefield.yml` has one.
+fn hybridization_angle(force_field: &ForceField, h: Hybridization) -> Option<f64> {
+    let key = match h {
+        Hybridization::Sp => "sp",
+        Hybridization::Sp2 => "sp2",
+        Hybridization::Sp3 => "sp3",
+        Hybridization::Unknown | Hybridization::S => return None,
+    };
+    force_field.hybridization_angles.get(key).copied()
+}
+
+impl Topology {
+    /// Build the terms for `bonds` with parameters from `forcefield.yml`, keyed by the bond
+    /// orders and hybridization [`perceive`] finds in the structure. Bonds may be listed in
+    /// either order and more than once. Atoms take their element's charge unless `charges`
+    /// gives each atom's partial charge.
+    ///
+    /// A term the file gives no reference value for holds the value it has in `coords`,
+    /// and a warning is logged with how many terms did.
+    ///
+    /// Each `EP` atom becomes a [`VirtualSite`] of an oxygen and its two hydrogens: the oxygen
+    /// it is bonded to, else the one three atoms before it in a water's O, H1, H2, EP order,
+    /// else the nearest within 1 Å. It is weighted to stay as far along their bisector as it
+    /// is in `coords`, and shares their exclusions and molecule but has no bonded terms.
+    pub fn new(
+        coords: &[Vec3],
+        atom_types: &[String],
+        bonds: &[(usize, usize)],
+        charges: Option<&[f64]>,
+        cell: Option<&UnitCell>,
+    ) -> Result<Self, PipelineError> {
+        let force_field = force_field()?;
+        let points: Vec<(f64, f64, f64)> = coords.iter().map(|c| (c[0], c[1], c[2])).collect();
+        let perception = perceive(&points, atom_types, bonds, cell)?;
+        let orders = bond_orders(&perception, atom_types);
+        let order = |i: usize, j: usize| orders.get(&(i.min(j), i.max(j))).copied().unwrap_or(BondOrder::Single);
+        let mut unreferenced = 0;
+
+        let mut neighbours = vec![BTreeSet::new(); coords.len()];
+        for &(i, j) in bonds {
+            if i != j {
+                neighbours[i].insert(j);
+                neighbours[j].insert(i);
+            }
+        }
+
+        // Each site's water is the oxygen it is bonded to, or the one it follows as the O, H1, H2
+        // and EP of a water template, and only failing both the nearest oxygen within reach
+        let water_oxygen = |o: usize| atom_types[o] == "O" && neighbours[o].iter().filter(|&&h| atom_types[h] == "H").count() == 2;
+        let mut oxygens: Option<CellList> = None;
+        let mut virtual_sites = Vec::new();
+        for site in (0..coords.len()).filter(|&i| atom_types[i] == VIRTUAL_SITE) {
+            let bonded = neighbours[site].iter().copied().find(|&o| atom_types[o] == "O");
+            let template = site
+                .checked_sub(3)
+                .filter(|&o| water_oxygen(o) && neighbours[o].contains(&(o + 1)) && neighbours[o].contains(&(o + 2)));
+            let origin = bonded.or(template).or_else(|| {
+                let oxygens = oxygens.get_or_insert_with(|| CellList::new(&points, VIRTUAL_SITE_REACH, cell.copied()));
+                oxygens.nearest(&points, points[site], |j| atom_types[j] == "O")
+            });
+            let arms: Vec<usize> = origin.map_or(Vec::new(), |o| neighbours[o].iter().copied().filter(|&h| atom_types[h] == "H").collect());
+            let (Some(origin), &[h1, h2]) = (origin, arms.as_slice()) else {
+                return Err(PipelineError::InvalidVirtualSite(site));
+            };
+            let bisector = [h1, h2].map(|h| displacement(coords[h], coords[origin], cell));
+            let bisector = [0, 1, 2].map(|k| bisector[0][k] + bisector[1][k]);
+            let weight = dot(displacement(coords[site], coords[origin], cell), bisector) / dot(bisector, bisector);
+            virtual_sites.push(VirtualSite { site, origin, arms: [h1, h2], weight });
+        }
+        // A bond to the site only pointed to its water; it takes no bonded term
+        for v in &virtual_sites {
+            for j in std::mem::take(&mut neighbours[v.site]) {
+                neighbours[j].remove(&v.site);
+            }
+        }
+
+        let element = |i: usize| atom_types[i].as_str();
+        let mut terms = Vec::new();
+        for (i, partners) in neighbours.iter().enumerate() {
+            for &j in partners.range(i + 1..) {
+                if let Some(p) = force_field.bond(element(i), element(j), order(i, j)) {
+                    let r0 = p.r0.unwrap_or_else(|| {
+                        unreferenced += 1;
+                        bond_length(coords, i, j, cell)
+                    });
+                    terms.push(BondedTerm {
+                        kind: BOND,
+                        atoms: [i as u32, j as u32, 0, 0],
+                        k: p.k as f32,
+                        equilibrium: r0 as f32,
+                        periodicity: 0.0,
+                    });
+                }
+            }
+        }
+        for (j, partners) in neighbours.iter().enumerate() {
+            for &i in partners {
+                for &l in partners.range(i + 1..) {
+                    if let Some(p) = force_field.angle(element(i), element(j), element(l)) {
+                        let theta0 = p
+                            .theta0
+                            .or_else(|| hybridization_angle(force_field, perception.hybridization[j]))
+                            .map(f64::to_radians)
+                            .unwrap_or_else(|| {
+                                unreferenced += 1;
+                                bond_angle(coords, i, j, l, cell)
+                            });
+                        terms.push(BondedTerm {
+                            kind: ANGLE,
+                            atoms: [i as u32, j as u32, l as u32, 0],
+                            k: p.k as f32,
+                            equilibrium: theta0 as f32,
+                            periodicity: 0.0,
+                        });
+                    }
+                }
+            }
+        }
+        for (j, partners) in neighbours.iter().enumerate() {
+            for &k in partners.range(j + 1..) {
+                for &i in neighbours[j].iter().filter(|&&i| i != k) {
+                    for &l in neighbours[k].iter().filter(|&&l| l != j && l != i) {
+                        if let Some(p) = force_field.torsion(element(i), element(j), element(k), element(l), order(j, k)) {
+                            let n = p.n as f64;
+                            let phi0 = p.phi0.map(f64::to_radians).unwrap_or_else(|| {
+                                unreferenced += 1;
+                                n * dihedral(coords, [i, j, k, l], cell) - std::f64::consts::PI
+                            });
+                            terms.push(BondedTerm {
+                                kind: TORSION,
+                                atoms: [i as u32, j as u32, k as u32, l as u32],
+                                k: p.k as f32,
+                                equilibrium: phi0 as f32,
+                                periodicity: n as f32,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+
+        if unreferenced > 0 {
+            log::warn!(
+                "{} bonded terms have no reference value in forcefield.yml and hold their starting geometry",
+                unreferenced
+            );
+        }
+
+        // Only now, so that the site has no bond term, but is excluded and grouped with its water
+        for v in &virtual_sites {
+            neighbours[v.site].insert(v.origin);
+            neighbours[v.origin].insert(v.site);
+        }
+
+        let exclusions = neighbours
+            .iter()
+            .enumerate()
+            .map(|(i, partners)| {
+                let mut excluded: BTreeSet<usize> = partners.clone();
+                for &j in partners {
+                    excluded.extend(neighbours[j].iter().filter(|&&k| k != i));
+                }
+                excluded.into_iter().collect()
+            })
+            .collect();
+
+        let atom_params = atom_types
+            .iter()
+            .enumerate()
+            .map(|(i, t)| {
+                let [sigma, epsilon, charge] = force_field
+                    .nonbonded(t)
+                    .map_or([0.0; 3], |p| [p.sigma as f32, p.epsilon as f32, p.charge as f32]);
+                let charge = charges.map_or(charge, |q| q[i] as f32);
+                Ok([sigma, epsilon, charge, atom_mass(t)?.unwrap_or(0.0) as f32])
+            })
+            .collect::<Result<_, DataError>>()?;
+
+        let mut molecules = vec![usize::MAX; coords.len()];
+        let mut count = 0;
+        for start in 0..coords.len() {
+            if molecules[start] != usize::MAX {
+                continue;
+            }
+            molecules[start] = count;
+            let mut stack = vec![start];
+            while let Some(i) = stack.pop() {
+                for &j in &neighbours[i] {
+                    if molecules[j] == usize::MAX {
+                        molecules[j] = count;
+                        stack.push(j);
+                    }
+                }
+            }
+            count += 1;
+        }
+
+        Ok(Topology { atom_params, terms, exclusions, molecules, virtual_sites })
+    }
+
+    /// Put every virtual site back in its place relative to the atoms it is placed from.
+    pub fn place_virtual_sites(&self, coords: &mut [Vec3], cell: Option<&UnitCell>) {
+        for v in &self.virtual_sites {
+            let origin = coords[v.origin];
+            let arms = v.arms.map(|h| displacement(coords[h], origin, cell));
+            coords[v.site] = [0, 1, 2].map(|k| origin[k] + v.weight * (arms[0][k] + arms[1][k]));
+        }
+    }
+
+    /// Pass the force on every virtual site to the atoms it is placed from.
+    pub fn spread_virtual_forces(&self, forces: &mut [Vec3]) {
+        for v in &self.virtual_sites {
+            let f = std::mem::take(&mut forces[v.site]);
+            add_to(&mut forces[v.origin], scale(f, 1.0 - 2.0 * v.weight));
+            for h in v.arms {
+                add_to(&mut forces[h], scale(f, v.weight));
+            }
+        }
+    }
+
+    pub fn is_excluded(&self, i: usize, j: usize) -> bool {
+        self.exclusions[i].binary_search(&j).is_ok()
+    }
+}
+
+/// Lennard-Jones plus cutoff Coulomb between one pair of atoms, as configured by
+/// `AtomPipelineParams`. Mirrors `pair_force` in the WGSL shaders.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct PairPotential {
+    pub mixing: Mixing,
+    pub cutoff: f64,
+    pub electrostatics: Electrostatics,
+    /// The reaction-field constants k_rf and c_rf.
+    pub k_rf: f64,
+    pub c_rf: f64,
+}
+
+impl PairPotential {
+    pub fn new(params: &AtomPipelineParams) -> Result<Self, DataError> {
+        let cutoff = params.cutoff as f64;
+        let eps = params.dielectric as f64;
+        let k_rf = (eps - 1.0) / ((2.0 * eps + 1.0) * cutoff.powi(3));
+        Ok(PairPotential {
+            mixing: force_field()?.mixing,
+            cutoff,
+            electrostatics: params.electrostatics,
+            k_rf,
+            c_rf: 1.0 / cutoff + k_rf * cutoff * cutoff,
+        })
+    }
+
+    /// The Lennard-Jones and Coulomb energies of a pair `d` apart, and the force on the
+    /// first atom divided by r, so that the force itself is that times `d`. Zero beyond
+    /// the cutoff, where the Lennard-Jones energy is shifted to meet it.
+    pub fn energy_force(&self, d: Vec3, a: [f32; 4], b: [f32; 4]) -> (f64, f64, f64) {
+        let r2 = dot(d, d);
+        let rc = self.cutoff;
+        if r2 > rc * rc {
+            return (0.0, 0.0, 0.0);
+        }
+        let (a, b) = (a.map(|x| x as f64), b.map(|x| x as f64));
+        let sigma = match self.mixing {
+            Mixing::LorentzBerthelot => (a[0] + b[0]) / 2.0,
+            Mixing::Geometric => (a[0] * b[0]).sqrt(),
+        };
+        let epsilon = (a[1] * b[1]).sqrt();
+        let s6 = (sigma * sigma / r2).powi(3);
+        // Shifted so the energy is continuous at the cutoff, which line searches rely on
+        let s6_rc = (sigma * sigma / (rc * rc)).powi(3);
+        let lennard_jones = 4.0 * epsilon * (s6 * s6 - s6 - (s6_rc * s6_rc - s6_rc));
+        let mut force = 24.0 * epsilon * (2.0 * s6 * s6 - s6) / r2;
+        let mut coulomb = 0.0;
+
+        let qq = COULOMB * a[2] * b[2];
+        if qq != 0.0 {
+            let r = r2.sqrt();
+            match self.electrostatics {
+                Electrostatics::ReactionField => {
+                    coulomb = qq * (1.0 / r + self.k_rf * r2 - self.c_rf);
+                    force += qq * (1.0 / (r2 * r) - 2.0 * self.k_rf);
+                }
+                Electrostatics::ShiftedForce => {
+                    coulomb = qq * (1.0 / r - 1.0 / rc + (r - rc) / (rc * rc));
+                    force += qq * (1.0 / r2 - 1.0 / (rc * rc)) / r;
+                }
+            }
+        }
+        (lennard_jones, coulomb, force)
+    }
+}
+
+/// Add the force from every bonded term to `forces` and return their energies and virial.
+/// `pair_virial` gets each pair of atoms the virial is summed over, with the displacement
+/// r between them and the force F on the first, whose outer product r ⊗ F it adds.
+///
+/// Mirrors `bonded_forces` in the WGSL shaders.
+pub(crate) fn bonded_forces(
+    coords: &[Vec3],
+    terms: &[BondedTerm],
+    cell: Option<&UnitCell>,
+    forces: &mut [Vec3],
+    mut pair_virial: impl FnMut((usize, usize), Vec3, Vec3),
+) -> (Energies, Virial) {
+    let mut energies = Energies::default();
+    let mut virial = [[0.0; 3]; 3];
+    let mut add_virial = |pair: (usize, usize), r: Vec3, f: Vec3| {
+        add_outer(&mut virial, r, f);
+        pair_virial(pair, r, f);
+    };
+    for term in terms {
+        let [i, j, k, l] = term.atoms.map(|a| a as usize);
+        let (kf, eq) = (term.k as f64, term.equilibrium as f64);
+        match term.kind {
+            BOND => {
+                let d = displacement(coords[j], coords[i], cell);
+                let r = dot(d, d).sqrt();
+                energies.bond += kf * (r - eq).powi(2);
+                let f_i = scale(d, 2.0 * kf * (r - eq) / r);
+                add_to(&mut forces[i], f_i);
+                add_to(&mut forces[j], scale(f_i, -1.0));
+                add_virial((i, j), scale(d, -1.0), f_i);
+            }
+            ANGLE => {
+                let (a, b) = (displacement(coords[i], coords[j], cell), displacement(coords[k], coords[j], cell));
+                let (ra, rb) = (dot(a, a).sqrt(), dot(b, b).sqrt());
+                let cos = (dot(a, b) / (ra * rb)).clamp(-1.0, 1.0);
+                let theta = cos.acos();
+                let sin = (1.0 - cos * cos).sqrt().max(1e-8);
+                energies.angle += kf * (theta - eq).powi(2);
+                let de = 2.0 * kf * (theta - eq);
+                let f_i = scale(sub(scale(b, 1.0 / rb), scale(a, cos / ra)), de / (ra * sin));
+                let f_k = scale(sub(scale(a, 1.0 / ra), scale(b, cos / rb)), de / (rb * sin));
+                add_to(&mut forces[i], f_i);
+                add_to(&mut forces[k], f_k);
+                add_to(&mut forces[j], scale([f_i[0] + f_k[0], f_i[1] + f_k[1], f_i[2] + f_k[2]], -1.0));
+                add_virial((i, j), a, f_i);
+                add_virial((k, j), b, f_k);
+            }
+            TORSION => {
+                let r_ij = displacement(coords[i], coords[j], cell);
+                let r_kj = displacement(coords[k], coords[j], cell);
+                let r_kl = displacement(coords[k], coords[l], cell);
+                let m = cross(r_ij, r_kj);
+                let n = cross(r_kj, r_kl);
+                let (iprm, iprn, nrkj2) = (dot(m, m), dot(n, n), dot(r_kj, r_kj));
+                if iprm < 1e-12 || iprn < 1e-12 {
+                    // Three of the atoms are collinear and the dihedral is undefined
+                    continue;
+                }
+                let phi = dihedral(coords, [i, j, k, l], cell);
+                let mult = term.periodicity as f64;
+                energies.torsion += kf * (1.0 + (mult * phi - eq).cos());
+                let ddphi = -kf * mult * (mult * phi - eq).sin();
+                let nrkj = nrkj2.sqrt();
+                let f_i = scale(m, -ddphi * nrkj / iprm);
+                let f_l = scale(n, ddphi * nrkj / iprn);
+                let p = dot(r_ij, r_kj) / nrkj2;
+                let q = dot(r_kl, r_kj) / nrkj2;
+                let s = sub(scale(f_i, p), scale(f_l, q));
+                add_to(&mut forces[i], f_i);
+                let f_k = scale([f_l[0] + s[0], f_l[1] + s[1], f_l[2] + s[2]], -1.0);
+                add_to(&mut forces[j], scale(sub(f_i, s), -1.0));
+                add_to(&mut forces[k], f_k);
+                add_to(&mut forces[l], f_l);
+                // Measured from j, so the box boundaries cannot split the torsion
+                add_virial((i, j), r_ij, f_i);
+                add_virial((k, j), r_kj, f_k);
+                add_virial((l, j), sub(r_kj, r_kl), f_l);
+            }
+            _ => unreachable!("unknown bonded term kind {}", term.kind),
+        }
+    }
+    (energies, virial)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn references_follow_bond_orders_not_the_start() {
+        // Acetate without its hydrogens, with the C-C bond stretched to 1.6 Å
+        let coords = [[0.0, 0.0, 0.0], [1.6, 0.0, 0.0], [2.225, 1.083, 0.0], [2.225, -1.083, 0.0]];
+        let atom_types: Vec<String> = ["C", "C", "O", "O"].iter().map(|t| t.to_string()).collect();
+        let topology = Topology::new(&coords, &atom_types, &[(0, 1), (1, 2), (1, 3)], None, None).unwrap();
+        let equilibrium = |kind: u32, atoms: [u32; 2]| {
+            topology.terms.iter().find(|t| t.kind == kind && t.atoms[..2] == atoms).unwrap().equilibrium
+        };
+        assert_eq!(equilibrium(BOND, [0, 1]), 1.526);
+        // One C=O, and the carboxylate's other oxygen delocalized with it
+        let mut carboxylate = [equilibrium(BOND, [1, 2]), equilibrium(BOND, [1, 3])];
+        carboxylate.sort_by(f32::total_cmp);
+        assert_eq!(carboxylate, [1.229, 1.25]);
+        assert!((equilibrium(ANGLE, [2, 1]) - 120f32.to_radians()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn virtual_sites_find_their_water_out_of_order() {
+        // Two TIP4P waters 3 Å apart with both EPs at the end, the first bonded to its oxygen
+        let water = [[2.892, 1.125, 1.555], [3.272, 1.904, 1.149], [2.987, 1.275, 2.496], [2.953, 1.244, 1.624]];
+        let shifted = |p: [f64; 3]| [p[0] + 3.0, p[1], p[2]];
+        let coords = [water[0], water[1], water[2], shifted(water[0]), shifted(water[1]), shifted(water[2]), water[3], shifted(water[3])];
+        let atom_types: Vec<String> = ["O", "H", "H", "O", "H", "H", "EP", "EP"].iter().map(|t| t.to_string()).collect();
+        let topology = Topology::new(&coords, &atom_types, &[(0, 1), (0, 2), (3, 4), (3, 5), (0, 6)], None, None).unwrap();
+        let origins: Vec<(usize, usize)> = topology.virtual_sites.iter().map(|v| (v.site, v.origin)).collect();
+        assert_eq!(origins, [(6, 0), (7, 3)]);
+        assert!(topology.terms.iter().all(|t| !t.atoms.contains(&6)));
+    }
+}
@@ -0,0 +1,675 @@
+use std::sync::{Arc, Mutex};
+use wgpu::util::DeviceExt;
+use bytemuck::{Pod, Zeroable};
+
+use crate::compute_pipeline::forcefield::{BondedTerm, PairPotential, Topology, ANGLE, BOND};
+use crate::compute_pipeline::layout::{non_empty, pack_element_ids, pack_points, unpack_points, GpuVec4};
+use crate::compute_pipeline::minimizer::summarize;
+use crate::compute_pipeline::{AtomPipelineParams, Electrostatics, Minimizer, PipelineError, PipelineOutput};
+use crate::utilities::forcefield::Mixing;
+use crate::utilities::shader::shaders;
+
+// Uniform layout of `SimulationParams` in the shaders. Each `mat3x3<f32>` column is padded to 16 bytes.
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+struct ShaderParams {
+    step_size: f32,
+    atom_count: u32,
+    process_type: u32,
+    periodic: u32,
+    cell_matrix: [[f32; 4]; 3],
+    cell_inverse: [[f32; 4]; 3],
+    term_count: u32,
+    cutoff: f32,
+    electrostatics: u32,
+    mixing: u32,
+    k_rf: f32,
+    c_rf: f32,
+    _padding: [u32; 2],
+}
+
+// The row-vector matrices of `UnitCell` are exactly the column-major matrices WGSL expects.
+fn matrix_columns(m: [[f64; 3]; 3]) -> [[f32; 4]; 3] {
+    m.map(|row| [row[0] as f32, row[1] as f32, row[2] as f32, 0.0])
+}
+
+// Per-atom lists flattened for the shaders: `atoms + 1` offsets, then the entries.
+fn offset_table(lists: &[Vec<usize>]) -> Vec<u32> {
+    let mut table = Vec::with_capacity(lists.len() + 1);
+    let mut offset = lists.len() + 1;
+    for entries in lists {
+        table.push(offset as u32);
+        offset += entries.len();
+    }
+    table.push(offset as u32);
+    table.extend(lists.iter().flatten().map(|&j| j as u32));
+    table
+}
+
+// The bonded terms of each atom as `4 · term + slot`, so that a thread visits its own terms only.
+fn term_lists(terms: &[BondedTerm], atoms: usize) -> Vec<Vec<usize>> {
+    let mut lists = vec![Vec::new(); atoms];
+    for (t, term) in terms.iter().enumerate() {
+        let slots = match term.kind {
+            BOND => 2,
+            ANGLE => 3,
+            _ => 4,
+        };
+        for (slot, &atom) in term.atoms[..slots].iter().enumerate() {
+            lists[atom as usize].push(4 * t + slot);
+        }
+    }
+    lists
+}
+
+fn storage_entry(binding: u32, read_only: bool) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::COMPUTE,
+        ty: wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Storage { read_only },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    }
+}
+
+// Coordinates, element IDs, each atom's bonded terms, the terms, exclusions, atom parameters,
+// velocities, forces and the next coordinates
+const STORAGE_BUFFERS: u32 = 9;
+
+// Steps encoded into one command buffer, so long runs reach the device in pieces
+const STEPS_PER_SUBMIT: u32 = 100;
+
+/// The kernels of one process. Every step runs `compute_forces` and then `advance`;
+/// simulation starts with `compute_forces` and follows each step with `compute_forces`
+/// and `settle` instead.
+struct Kernels {
+    compute_forces: wgpu::ComputePipeline,
+    advance: wgpu::ComputePipeline,
+    settle: Option<wgpu::ComputePipeline>,
+}
+
+/// A wgpu device with the relax, minimize and simulate pipelines compiled for it,
+/// shared by every system run on it.
+pub struct GpuContext {
+    pub adapter_name: String,
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    bind_group_layout: wgpu::BindGroupLayout,
+    // Indexed by process type
+    kernels: [Kernels; 3],
+}
+
+impl GpuContext {
+    pub fn new() -> Result<Self, PipelineError> {
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+            backends: wgpu::Backends::all(),
+            dx12_shader_compiler: wgpu::Dx12Compiler::Fxc,
+            flags: wgpu::InstanceFlags::empty(),
+            gles_minor_version: wgpu::Gles3MinorVersion::Automatic,
+        });
+
+        let adapter = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::HighPerformance,
+            compatible_surface: None,
+            force_fallback_adapter: false,
+        })).ok_or(PipelineError::NoAdapter)?;
+        if adapter.limits().max_storage_buffers_per_shader_stage < STORAGE_BUFFERS {
+            return Err(PipelineError::NoAdapter);
+        }
+
+        let (device, queue) = pollster::block_on(adapter.request_device(&wgpu::DeviceDescriptor {
+            required_features: wgpu::Features::empty(),
+            required_limits: wgpu::Limits {
+                max_storage_buffers_per_shader_stage: STORAGE_BUFFERS,
+                ..wgpu::Limits::default()
+            },
+            label: None,
+            memory_hints: wgpu::MemoryHints::default(),
+        }, None)).map_err(|e| PipelineError::Device(e.to_string()))?;
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                storage_entry(0, true),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                storage_entry(2, true),
+                storage_entry(3, true),
+                storage_entry(4, true),
+                storage_entry(5, true),
+                storage_entry(6, true),
+                storage_entry(7, false),
+                storage_entry(8, false),
+                storage_entry(9, false),
+            ],
+            label: None,
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let shaders = shaders()?;
+        let kernels = [(&shaders.relax, false), (&shaders.minimize, false), (&shaders.simulate, true)].map(|(source, settles)| {
+            let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: None,
+                source: wgpu::ShaderSource::Wgsl(source.as_str().into()),
+            });
+            let pipeline = |entry_point: &str| {
+                device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                    label: Some(entry_point),
+                    layout: Some(&pipeline_layout),
+                    module: &shader,
+                    entry_point: Some(entry_point),
+                    compilation_options: Default::default(),
+                    cache: None,
+                })
+            };
+            Kernels {
+                compute_forces: pipeline("compute_forces"),
+                advance: pipeline("advance"),
+                settle: settles.then(|| pipeline("settle")),
+            }
+        });
+
+        Ok(GpuContext {
+            adapter_name: adapter.get_info().name,
+            device,
+            queue,
+            bind_group_layout,
+            kernels,
+        })
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref SHARED_CONTEXT: Mutex<Option<Arc<GpuContext>>> = Mutex::new(None);
+}
+
+/// The context `run_atom_pipeline` uses, created on first use and then kept for the
+/// life of the process.
+pub fn shared_context() -> Result<Arc<GpuContext>, PipelineError> {
+    let mut shared = SHARED_CONTEXT.lock().unwrap();
+    if let Some(context) = shared.as_ref() {
+        return Ok(context.clone());
+    }
+    let context = Arc::new(GpuContext::new()?);
+    *shared = Some(context.clone());
+    Ok(context)
+}
+
+fn check_supported(params: &AtomPipelineParams) -> Result<(), PipelineError> {
+    if params.process_type > 2 {
+        return Err(PipelineError::InvalidProcessType(params.process_type));
+    }
+    if params.process_type == 1 && params.minimizer != Minimizer::SteepestDescent {
+        return Err(PipelineError::Unsupported(format!("the {:?} minimizer", params.minimizer)));
+    }
+    if params.process_type == 2 && params.thermostat.is_some() {
+        return Err(PipelineError::Unsupported("thermostats".to_string()));
+    }
+    if params.process_type == 2 && params.barostat.is_some() {
+        return Err(PipelineError::Unsupported("barostats".to_string()));
+    }
+    Ok(())
+}
+
+/// One structure resident on a [`GpuContext`]: its coordinates, velocities and topology,
+/// which stay on the device between runs of the kernels.
+///
+/// Coordinates are double-buffered: each step reads one buffer and writes the other,
+/// and the two bind groups swap which is which.
+///
+/// Bonded terms the force field has no reference value for take theirs from the
+/// coordinates the system was created with; uploading new coordinates does not change it.
+pub struct GpuSystem {
+    context: Arc<GpuContext>,
+    atoms: usize,
+    params: AtomPipelineParams,
+    pair: PairPotential,
+    // As uploaded, for checking a minimization's forces between blocks of steps
+    topology: Topology,
+    coord_buffers: [wgpu::Buffer; 2],
+    velocity_buffer: wgpu::Buffer,
+    param_buffer: wgpu::Buffer,
+    result_buffer: wgpu::Buffer,
+    velocity_result_buffer: wgpu::Buffer,
+    // `bind_groups[i]` reads `coord_buffers[i]` and writes the other
+    bind_groups: [wgpu::BindGroup; 2],
+    // Which coordinate buffer holds the current coordinates
+    current: usize,
+}
+
+impl GpuSystem {
+    pub fn new(
+        context: Arc<GpuContext>,
+        coords: &[[f64; 3]],
+        velocities: &[[f64; 3]],
+        atom_types: &[String],
+        bonds: &[(usize, usize)],
+        charges: Option<&[f64]>,
+        params: AtomPipelineParams,
+    ) -> Result<Self, PipelineError> {
+        check_supported(&params)?;
+        let device = &context.device;
+
+        let coord_data = non_empty(pack_points(coords));
+        let coord_buffers = ["Coordinate Buffer", "Next Coordinate Buffer"].map(|label| {
+            device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(label),
+                contents: bytemuck::cast_slice(&coord_data),
+                usage: wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::STORAGE,
+            })
+        });
+
+        let result_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Result Buffer"),
+            size: coord_buffers[0].size(),
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let element_ids_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Element IDs Buffer"),
+            contents: bytemuck::cast_slice(&non_empty(pack_element_ids(atom_types)?)),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+
+        let topology = Topology::new(coords, atom_types, bonds, charges, params.cell.as_ref())?;
+        if !topology.virtual_sites.is_empty() {
+            return Err(PipelineError::Unsupported("virtual sites such as TIP4P's EPW".to_string()));
+        }
+        let atom_terms_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Atom Terms Buffer"),
+            contents: bytemuck::cast_slice(&non_empty(offset_table(&term_lists(&topology.terms, coords.len())))),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+
+        let terms_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Bonded Terms Buffer"),
+            contents: bytemuck::cast_slice(&non_empty(topology.terms.clone())),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+
+        let exclusions_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Exclusions Buffer"),
+            contents: bytemuck::cast_slice(&non_empty(offset_table(&topology.exclusions))),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+
+        let atom_params_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Atom Parameters Buffer"),
+            contents: bytemuck::cast_slice(&non_empty(topology.atom_params.clone())),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+
+        let velocity_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Velocity Buffer"),
+            contents: bytemuck::cast_slice(&non_empty(pack_points(velocities))),
+            usage: wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::STORAGE,
+        });
+
+        let velocity_result_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Velocity Result Buffer"),
+            size: velocity_buffer.size(),
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let force_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Force Buffer"),
+            size: coord_buffers[0].size(),
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+
+        let param_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Parameter Buffer"),
+            size: std::mem::size_of::<ShaderParams>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let bind_groups = [0, 1].map(|i| {
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                layout: &context.bind_group_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: coord_buffers[i].as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: param_buffer.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: element_ids_buffer.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 3,
+                        resource: atom_terms_buffer.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 4,
+                        resource: terms_buffer.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 5,
+                        resource: exclusions_buffer.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 6,
+                        resource: atom_params_buffer.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 7,
+                        resource: velocity_buffer.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 8,
+                        resource: force_buffer.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 9,
+                        resource: coord_buffers[1 - i].as_entire_binding(),
+                    },
+                ],
+                label: None,
+            })
+        });
+
+        Ok(GpuSystem {
+            atoms: coords.len(),
+            params,
+            pair: PairPotential::new(&params)?,
+            topology,
+            coord_buffers,
+            velocity_buffer,
+            param_buffer,
+            result_buffer,
+            velocity_result_buffer,
+            bind_groups,
+            current: 0,
+            context,
+        })
+    }
+
+    pub fn atoms(&self) -> usize {
+        self.atoms
+    }
+
+    pub fn params(&self) -> AtomPipelineParams {
+        self.params
+    }
+
+    /// Change what the kernels run: the process, step size, box or nonbonded cutoff.
+    /// The topology stays as the system was created with.
+    pub fn set_params(&mut self, params: AtomPipelineParams) -> Result<(), PipelineError> {
+        check_supported(&params)?;
+        self.params = params;
+        self.pair = PairPotential::new(&params)?;
+        Ok(())
+    }
+
+    /// Replace the resident coordinates, and the velocities if given.
+    pub fn upload(&self, coords: &[[f64; 3]], velocities: Option<&[[f64; 3]]>) -> Result<(), PipelineError> {
+        if coords.len() != self.atoms {
+            return Err(PipelineError::InvalidCoordinates(coords.len()));
+        }
+        if let Some(v) = velocities.filter(|v| v.len() != self.atoms) {
+            return Err(PipelineError::InvalidVelocities(v.len()));
+        }
+        let queue = &self.context.queue;
+        queue.write_buffer(&self.coord_buffers[self.current], 0, bytemuck::cast_slice(&pack_points(coords)));
+        if let Some(v) = velocities {
+            queue.write_buffer(&self.velocity_buffer, 0, bytemuck::cast_slice(&pack_points(v)));
+        }
+        Ok(())
+    }
+
+    fn dispatch(&self, cpass: &mut wgpu::ComputePass, pipeline: &wgpu::ComputePipeline) {
+        cpass.set_pipeline(pipeline);
+        cpass.set_bind_group(0, &self.bind_groups[self.current], &[]);
+        cpass.dispatch_workgroups((self.atoms as u32).div_ceil(64), 1, 1);
+    }
+
+    /// Run `steps` steps of the current process on the resident coordinates, one compute
+    /// pass per step.
+    pub fn step(&mut self, steps: u32) {
+        let params = self.params;
+        let shader_params = ShaderParams {
+            step_size: params.step_size,
+            atom_count: self.atoms as u32,
+            process_type: params.process_type,
+            periodic: params.cell.is_some() as u32,
+            cell_matrix: params.cell.map_or([[0.0; 4]; 3], |c| matrix_columns(c.matrix())),
+            cell_inverse: params.cell.map_or([[0.0; 4]; 3], |c| matrix_columns(c.inverse())),
+            term_count: self.topology.terms.len() as u32,
+            cutoff: self.pair.cutoff as f32,
+            electrostatics: match self.pair.electrostatics {
+                Electrostatics::ReactionField => 0,
+                Electrostatics::ShiftedForce => 1,
+            },
+            mixing: match self.pair.mixing {
+                Mixing::LorentzBerthelot => 0,
+                Mixing::Geometric => 1,
+            },
+            k_rf: self.pair.k_rf as f32,
+            c_rf: self.pair.c_rf as f32,
+            _padding: [0; 2],
+        };
+        let context = self.context.clone();
+        context.queue.write_buffer(&self.param_buffer, 0, bytemuck::cast_slice(&[shader_params]));
+        let kernels = &context.kernels[params.process_type as usize];
+
+        let mut remaining = steps;
+        let mut first = true;
+        while remaining > 0 {
+            let block = remaining.min(STEPS_PER_SUBMIT);
+            let mut encoder = context.device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+            for _ in 0..block {
+                let mut cpass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                    label: None,
+                    timestamp_writes: None,
+                });
+                // Consecutive dispatches are ordered, so each kernel sees everything the one before it wrote
+                match &kernels.settle {
+                    Some(settle) => {
+                        if first {
+                            self.dispatch(&mut cpass, &kernels.compute_forces);
+                        }
+                        self.dispatch(&mut cpass, &kernels.advance);
+                        self.current = 1 - self.current;
+                        self.dispatch(&mut cpass, &kernels.compute_forces);
+                        self.dispatch(&mut cpass, settle);
+                    }
+                    None => {
+                        self.dispatch(&mut cpass, &kernels.compute_forces);
+                        self.dispatch(&mut cpass, &kernels.advance);
+                        self.current = 1 - self.current;
+                    }
+                }
+                first = false;
+            }
+            context.queue.submit(Some(encoder.finish()));
+            remaining -= block;
+        }
+    }
+
+    /// Read the resident coordinates and velocities back.
+    pub fn download(&self) -> Result<PipelineOutput, PipelineError> {
+        let context = &self.context;
+        let coord_buffer = &self.coord_buffers[self.current];
+        let mut encoder = context.device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        encoder.copy_buffer_to_buffer(coord_buffer, 0, &self.result_buffer, 0, self.result_buffer.size());
+        encoder.copy_buffer_to_buffer(&self.velocity_buffer, 0, &self.velocity_result_buffer, 0, self.velocity_result_buffer.size());
+        context.queue.submit(Some(encoder.finish()));
+
+        let coords: Vec<GpuVec4> = read_buffer(&context.device, &self.result_buffer)?;
+        let velocities: Vec<GpuVec4> = read_buffer(&context.device, &self.velocity_result_buffer)?;
+        Ok(PipelineOutput {
+            coords: unpack_points(&coords[..self.atoms]),
+            velocities: unpack_points(&velocities[..self.atoms]),
+            cell: self.params.cell,
+            minimization: None,
+        })
+    }
+}
+
+pub(crate) fn run_gpu_pipeline(
+    coords: &[[f64; 3]],
+    velocities: &[[f64; 3]],
+    atom_types: &[String],
+    bonds: &[(usize, usize)],
+    charges: Option<&[f64]>,
+    params: AtomPipelineParams,
+) -> Result<PipelineOutput, PipelineError> {
+    check_supported(&params)?;
+    let mut system = GpuSystem::new(shared_context()?, coords, velocities, atom_types, bonds, charges, params)?;
+    if params.process_type != 1 {
+        system.step(params.max_steps);
+        return system.download();
+    }
+
+    // The shader takes every step it is given, so the forces are checked between blocks of steps
+    let mut output = system.download()?;
+    let mut summary = summarize(&output.coords, &system.topology, &system.pair, &params, 0);
+    while !summary.converged && summary.iterations < params.max_steps {
+        let block = (params.max_steps - summary.iterations).min(STEPS_PER_SUBMIT);
+        system.step(block);
+        output = system.download()?;
+        summary = summarize(&output.coords, &system.topology, &system.pair, &params, summary.iterations + block);
+    }
+    output.minimization = Some(summary);
+    Ok(output)
+}
+
+// Map a buffer filled by a submitted copy and read it back.
+fn read_buffer<T: Pod>(device: &wgpu::Device, buffer: &wgpu::Buffer) -> Result<Vec<T>, PipelineError> {
+    let buffer_slice = buffer.slice(..);
+    let (sender, receiver) = futures_intrusive::channel::shared::oneshot_channel();
+    buffer_slice.map_async(wgpu::MapMode::Read, move |result| {
+        sender.send(result).unwrap();
+    });
+
+    device.poll(wgpu::Maintain::Wait);
+
+    if let Some(Ok(())) = pollster::block_on(receiver.receive()) {
+        let data = buffer_slice.get_mapped_range();
+        let result: Vec<T> = bytemuck::cast_slice(&data).to_vec();
+        drop(data);
+        buffer.unmap();
+        Ok(result)
+    } else {
+        Err(PipelineError::ReadBack)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compute_pipeline::cpu::run_cpu_pipeline;
+    use crate::compute_pipeline::Structure;
+
+    // Two short chains close enough for their atoms to push on each other
+    fn system() -> Structure {
+        let mut coords = Vec::new();
+        let mut bonds = Vec::new();
+        for chain in 0..2 {
+            for i in 0..40 {
+                if i > 0 {
+                    bonds.push((coords.len() - 1, coords.len()));
+                }
+                coords.push([i as f64 * 1.25, (i % 2) as f64 * 0.85 + chain as f64 * 3.5, 0.25 * chain as f64]);
+            }
+        }
+        let atom_types = (0..coords.len()).map(|i| if i % 5 == 0 { "N" } else { "C" }.to_string()).collect();
+        (coords, atom_types, bonds)
+    }
+
+    fn gpu_system(params: AtomPipelineParams) -> Option<GpuSystem> {
+        let context = match shared_context() {
+            Err(PipelineError::NoAdapter) => return None,
+            context => context.unwrap(),
+        };
+        let (coords, atom_types, bonds) = system();
+        let velocities: Vec<[f64; 3]> = (0..coords.len()).map(|i| [(i % 7) as f64 - 3.0, 0.5, -((i % 3) as f64)]).collect();
+        Some(GpuSystem::new(context, &coords, &velocities, &atom_types, &bonds, None, params).unwrap())
+    }
+
+    #[test]
+    fn atoms_list_each_of_their_terms_once() {
+        let (coords, atom_types, bonds) = system();
+        let topology = Topology::new(&coords, &atom_types, &bonds, None, None).unwrap();
+        let table = offset_table(&term_lists(&topology.terms, coords.len()));
+        let mut seen = 0;
+        for atom in 0..coords.len() {
+            for &entry in &table[table[atom] as usize..table[atom + 1] as usize] {
+                assert_eq!(topology.terms[entry as usize / 4].atoms[entry as usize % 4], atom as u32);
+                seen += 1;
+            }
+        }
+        // Two atoms per bond, three per angle and four per torsion
+        let expected: usize = topology.terms.iter().map(|t| t.kind as usize + 2).sum();
+        assert_eq!(seen, expected);
+    }
+
+    #[test]
+    fn runs_are_deterministic() {
+        for process_type in 0..3 {
+            let params = AtomPipelineParams { process_type, ..Default::default() };
+            let Some(mut first) = gpu_system(params) else { return };
+            let mut second = gpu_system(params).unwrap();
+            first.step(50);
+            second.step(50);
+            let (a, b) = (first.download().unwrap(), second.download().unwrap());
+            assert_eq!(a.coords, b.coords);
+            assert_eq!(a.velocities, b.velocities);
+        }
+    }
+
+    #[test]
+    fn stepping_in_blocks_matches_one_run() {
+        for process_type in 0..3 {
+            let params = AtomPipelineParams { process_type, ..Default::default() };
+            let Some(mut whole) = gpu_system(params) else { return };
+            let mut blocks = gpu_system(params).unwrap();
+            whole.step(STEPS_PER_SUBMIT + 20);
+            for _ in 0..4 {
+                blocks.step((STEPS_PER_SUBMIT + 20) / 4);
+            }
+            assert_eq!(whole.download().unwrap().coords, blocks.download().unwrap().coords);
+        }
+    }
+
+    #[test]
+    fn gpu_matches_cpu() {
+        for process_type in 0..3 {
+            let params = AtomPipelineParams { process_type, max_steps: 20, ..Default::default() };
+            let Some(mut gpu) = gpu_system(params) else { return };
+            let start = gpu.download().unwrap();
+            gpu.step(params.max_steps);
+            let gpu = gpu.download().unwrap();
+            let (_, atom_types, bonds) = system();
+            let cpu = run_cpu_pipeline(&start.coords, &start.velocities, &atom_types, &bonds, None, params).unwrap();
+            for (g, c) in gpu.coords.iter().zip(&cpu.coords) {
+                for k in 0..3 {
+                    assert!((g[k] - c[k]).abs() < 1e-3, "process {}: {:?} vs {:?}", process_type, g, c);
+                }
+            }
+        }
+    }
+}
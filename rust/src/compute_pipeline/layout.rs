@@ -0,0 +1,129 @@
+use bytemuck::{Pod, Zeroable};
+
+use crate::utilities::element::{periodic_table, PeriodicTable};
+use crate::utilities::DataError;
+
+/// A `vec4<f32>` in a storage buffer. Coordinates and velocities use `x`, `y` and `z`;
+/// `w` only pads them to the 16-byte stride WGSL gives `vec3<f32>` arrays anyway.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Pod, Zeroable)]
+#[repr(C)]
+pub struct GpuVec4 {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+    pub w: f32,
+}
+
+impl GpuVec4 {
+    pub fn from_point(p: [f64; 3]) -> Self {
+        GpuVec4 { x: p[0] as f32, y: p[1] as f32, z: p[2] as f32, w: 0.0 }
+    }
+
+    pub fn to_point(self) -> [f64; 3] {
+        [self.x as f64, self.y as f64, self.z as f64]
+    }
+}
+
+/// The atomic number of `symbol`, or 0 for an element the table has no entry for.
+pub fn element_id(table: &PeriodicTable, symbol: &str) -> u32 {
+    table.get(symbol).map_or(0, |e| e.atomic_number)
+}
+
+pub fn pack_points(points: &[[f64; 3]]) -> Vec<GpuVec4> {
+    points.iter().map(|&p| GpuVec4::from_point(p)).collect()
+}
+
+pub fn unpack_points(points: &[GpuVec4]) -> Vec<[f64; 3]> {
+    points.iter().map(|p| p.to_point()).collect()
+}
+
+pub fn pack_element_ids(atom_types: &[String]) -> Result<Vec<u32>, DataError> {
+    let table = periodic_table()?;
+    Ok(atom_types.iter().map(|t| element_id(table, t)).collect())
+}
+
+/// `data`, or a single zeroed element in its place, since wgpu rejects empty storage buffers.
+pub fn non_empty<T: Pod>(mut data: Vec<T>) -> Vec<T> {
+    if data.is_empty() {
+        data.push(T::zeroed());
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compute_pipeline::{run_atom_pipeline, AtomPipelineParams, Backend, PipelineError, Structure};
+    use crate::compute_pipeline::gpu::run_gpu_pipeline;
+
+    // A bent chain long enough that its bond indices do not fit in a byte
+    fn chain() -> Structure {
+        let coords: Vec<[f64; 3]> = (0..300).map(|i| [i as f64 * 1.25, (i % 2) as f64 * 0.75, 0.5]).collect();
+        let atom_types = (0..300).map(|i| if i % 3 == 0 { "O" } else { "C" }.to_string()).collect();
+        let bonds = (1..300).map(|i| (i - 1, i)).collect();
+        (coords, atom_types, bonds)
+    }
+
+    #[test]
+    fn layouts_match_wgsl() {
+        assert_eq!(std::mem::size_of::<GpuVec4>(), 16);
+        let bytes: &[u8] = bytemuck::cast_slice(&[GpuVec4 { x: 1.0, y: 2.0, z: 3.0, w: 0.0 }]);
+        assert_eq!(&bytes[4..8], &2.0f32.to_ne_bytes());
+    }
+
+    #[test]
+    fn points_round_trip() {
+        let points = vec![[0.0, -1.5, 2.25], [1024.125, 3.0e-3, -7.0]];
+        let unpacked = unpack_points(&pack_points(&points));
+        assert_eq!(unpacked[0], points[0]);
+        for (a, b) in unpacked[1].iter().zip(&points[1]) {
+            assert!((a - b).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn element_ids_are_atomic_numbers() {
+        let types: Vec<String> = ["H", "C", "O", "Na", "Cl", "Ca", "Fe", "U", "Og", "Xx"].iter().map(|t| t.to_string()).collect();
+        assert_eq!(pack_element_ids(&types).unwrap(), vec![1, 6, 8, 11, 17, 20, 26, 92, 118, 0]);
+    }
+
+    #[test]
+    fn empty_buffers_get_one_element() {
+        assert_eq!(non_empty(pack_element_ids(&[]).unwrap()), vec![0]);
+        assert_eq!(non_empty(pack_points(&[])), vec![GpuVec4::default()]);
+
+        // No atoms at all still binds a buffer of each kind
+        let params = AtomPipelineParams { max_steps: 3, backend: Backend::Gpu, ..Default::default() };
+        match run_atom_pipeline(&[], None, &[], &[], None, params) {
+            Err(PipelineError::NoAdapter) => {}
+            result => assert!(result.unwrap().coords.is_empty()),
+        }
+    }
+
+    #[test]
+    fn zero_steps_leave_coordinates_unchanged() {
+        let (coords, atom_types, bonds) = chain();
+        for process_type in 0..3 {
+            let params = AtomPipelineParams { process_type, max_steps: 0, backend: Backend::Auto, ..Default::default() };
+            let output = run_atom_pipeline(&coords, Some(&vec![[0.0; 3]; coords.len()]), &atom_types, &bonds, None, params).unwrap();
+            assert_eq!(output.coords, coords);
+        }
+    }
+
+    #[test]
+    fn gpu_relaxation_matches_cpu() {
+        let (coords, atom_types, bonds) = chain();
+        let velocities = vec![[0.0; 3]; coords.len()];
+        let params = AtomPipelineParams { process_type: 0, max_steps: 5, backend: Backend::Cpu, ..Default::default() };
+        let gpu = match run_gpu_pipeline(&coords, &velocities, &atom_types, &bonds, None, params) {
+            Err(PipelineError::NoAdapter) => return,
+            result => result.unwrap(),
+        };
+        let cpu = run_atom_pipeline(&coords, None, &atom_types, &bonds, None, params).unwrap();
+        for (g, c) in gpu.coords.iter().zip(&cpu.coords) {
+            for k in 0..3 {
+                assert!((g[k] - c[k]).abs() < 1e-3, "{:?} vs {:?}", g, c);
+            }
+        }
+    }
+}
@@ -0,0 +1,328 @@
+use std::collections::VecDeque;
+
+use crate::compute_pipeline::cpu::evaluate;
+use crate::compute_pipeline::forcefield::{PairPotential, Topology};
+use crate::compute_pipeline::{AtomPipelineParams, Minimizer};
+use crate::unit_cell::UnitCell;
+
+/// How a minimization ended.
+#[derive(Clone, Copy, Debug)]
+pub struct MinimizationSummary {
+    /// Potential energy in kcal/mol.
+    pub energy: f64,
+    /// Largest force on any atom in kcal/(mol·Å).
+    pub max_force: f64,
+    pub iterations: u32,
+    /// Whether the force tolerance, or for the line-search minimizers the energy
+    /// tolerance, was met within `max_steps` iterations.
+    pub converged: bool,
+}
+
+// Furthest any atom may move in one iteration, in Å
+const MAX_DISPLACEMENT: f64 = 0.2;
+// Sufficient decrease constant of the Armijo condition
+const ARMIJO: f64 = 1e-4;
+const MAX_BACKTRACKS: u32 = 20;
+// Steps of history L-BFGS keeps
+const LBFGS_HISTORY: usize = 8;
+// FIRE settings from Bitzek et al. (2006), with unit masses and the time step in √(amu·Å²/(kcal/mol))
+const FIRE_DT: f64 = 0.01;
+const FIRE_DT_MAX: f64 = 0.1;
+const FIRE_ALPHA: f64 = 0.1;
+const FIRE_MIN_STEPS: u32 = 5;
+
+// Coordinates and forces are flattened to 3N-vectors for the vector algebra.
+fn flatten(points: &[[f64; 3]]) -> Vec<f64> {
+    points.iter().flatten().copied().collect()
+}
+
+fn unflatten(x: &[f64]) -> Vec<[f64; 3]> {
+    x.chunks_exact(3).map(|c| [c[0], c[1], c[2]]).collect()
+}
+
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+// a + s·b
+fn axpy(a: &[f64], s: f64, b: &[f64]) -> Vec<f64> {
+    a.iter().zip(b).map(|(x, y)| x + s * y).collect()
+}
+
+// The largest per-atom length in a flattened vector
+fn max_norm(v: &[f64]) -> f64 {
+    v.chunks_exact(3).map(|c| (c[0] * c[0] + c[1] * c[1] + c[2] * c[2]).sqrt()).fold(0.0, f64::max)
+}
+
+/// Coordinates with their energy and forces.
+struct Point {
+    x: Vec<f64>,
+    energy: f64,
+    forces: Vec<f64>,
+}
+
+struct Objective<'a> {
+    topology: &'a Topology,
+    pair: &'a PairPotential,
+    cell: Option<&'a UnitCell>,
+}
+
+impl Objective<'_> {
+    fn at(&self, x: Vec<f64>) -> Point {
+        let mut coords = unflatten(&x);
+        self.topology.place_virtual_sites(&mut coords, self.cell);
+        let evaluation = evaluate(&coords, self.topology, self.pair, self.cell);
+        Point { x: flatten(&coords), energy: evaluation.energy(), forces: flatten(&evaluation.forces) }
+    }
+
+    /// Backtracking from `step` along `direction` until the energy drops enough
+    /// (the Armijo condition), never moving an atom further than `MAX_DISPLACEMENT`.
+    fn line_search(&self, from: &Point, direction: &[f64], step: f64) -> Option<(f64, Point)> {
+        let slope = -dot(&from.forces, direction);
+        if slope >= 0.0 {
+            return None;
+        }
+        let mut step = step.min(MAX_DISPLACEMENT / max_norm(direction));
+        for _ in 0..MAX_BACKTRACKS {
+            let trial = self.at(axpy(&from.x, step, direction));
+            if trial.energy <= from.energy + ARMIJO * step * slope {
+                return Some((step, trial));
+            }
+            step *= 0.5;
+        }
+        None
+    }
+}
+
+/// One iteration of a minimizer, or `None` when it can make no more progress.
+trait Iteration {
+    fn next(&mut self, objective: &Objective, from: &Point) -> Option<Point>;
+}
+
+/// Fixed steps along the force, as the minimize shader takes them.
+struct SteepestDescent {
+    step_size: f64,
+}
+
+impl Iteration for SteepestDescent {
+    fn next(&mut self, objective: &Objective, from: &Point) -> Option<Point> {
+        Some(objective.at(axpy(&from.x, self.step_size, &from.forces)))
+    }
+}
+
+/// Damped dynamics that turn the velocity toward the force while it keeps doing work,
+/// and stop dead when it starts doing the opposite.
+struct Fire {
+    velocities: Vec<f64>,
+    dt: f64,
+    alpha: f64,
+    downhill_steps: u32,
+}
+
+impl Iteration for Fire {
+    fn next(&mut self, objective: &Objective, from: &Point) -> Option<Point> {
+        // At a stationary point there is no direction to turn toward or move along
+        let force = dot(&from.forces, &from.forces).sqrt();
+        if force == 0.0 {
+            return None;
+        }
+        let power = dot(&from.forces, &self.velocities);
+        if power > 0.0 {
+            let speed = dot(&self.velocities, &self.velocities).sqrt();
+            for (v, f) in self.velocities.iter_mut().zip(&from.forces) {
+                *v = (1.0 - self.alpha) * *v + self.alpha * speed * f / force;
+            }
+            if self.downhill_steps > FIRE_MIN_STEPS {
+                self.dt = (self.dt * 1.1).min(FIRE_DT_MAX);
+                self.alpha *= 0.99;
+            }
+            self.downhill_steps += 1;
+        } else {
+            self.velocities.iter_mut().for_each(|v| *v = 0.0);
+            self.dt *= 0.5;
+            self.alpha = FIRE_ALPHA;
+            self.downhill_steps = 0;
+        }
+
+        self.velocities = axpy(&self.velocities, self.dt, &from.forces);
+        let mut dt = self.dt;
+        let moved = max_norm(&self.velocities) * dt;
+        if moved > MAX_DISPLACEMENT {
+            dt *= MAX_DISPLACEMENT / moved;
+        }
+        Some(objective.at(axpy(&from.x, dt, &self.velocities)))
+    }
+}
+
+/// Polak–Ribière conjugate gradient, restarting along the force whenever β turns
+/// negative, the direction stops going downhill, or every 3N iterations.
+struct ConjugateGradient {
+    previous: Option<(Vec<f64>, Vec<f64>)>, // forces and direction
+    step: f64,
+    since_restart: usize,
+}
+
+impl Iteration for ConjugateGradient {
+    fn next(&mut self, objective: &Objective, from: &Point) -> Option<Point> {
+        let mut direction = from.forces.clone();
+        let mut restart = true;
+        if let Some((forces, previous)) = self.previous.as_ref().filter(|_| self.since_restart < from.x.len()) {
+            let change: Vec<f64> = from.forces.iter().zip(forces).map(|(f, p)| f - p).collect();
+            let beta = dot(&from.forces, &change) / dot(forces, forces);
+            let conjugate = axpy(&from.forces, beta, previous);
+            if beta > 0.0 && dot(&conjugate, &from.forces) > 0.0 {
+                direction = conjugate;
+                restart = false;
+            }
+        }
+        self.since_restart = if restart { 0 } else { self.since_restart + 1 };
+
+        let (step, point) = objective.line_search(from, &direction, 2.0 * self.step)?;
+        self.step = step;
+        self.previous = Some((from.forces.clone(), direction));
+        Some(point)
+    }
+}
+
+/// Limited-memory BFGS: the last few steps and force changes stand in for the inverse Hessian.
+struct Lbfgs {
+    // Steps s and gradient changes y, newest last
+    history: VecDeque<(Vec<f64>, Vec<f64>)>,
+    // Scale of the inverse Hessian before there is any history
+    initial_scale: f64,
+}
+
+impl Lbfgs {
+    // The two-loop recursion for H·F, the quasi-Newton step downhill
+    fn direction(&self, forces: &[f64]) -> Vec<f64> {
+        let mut q = forces.to_vec();
+        let mut coefficients = Vec::with_capacity(self.history.len());
+        for (s, y) in self.history.iter().rev() {
+            let rho = 1.0 / dot(y, s);
+            let a = rho * dot(s, &q);
+            q = axpy(&q, -a, y);
+            coefficients.push((rho, a));
+        }
+        let scale = self.history.back().map_or(self.initial_scale, |(s, y)| dot(s, y) / dot(y, y));
+        let mut r: Vec<f64> = q.iter().map(|x| x * scale).collect();
+        for ((s, y), (rho, a)) in self.history.iter().zip(coefficients.into_iter().rev()) {
+            let b = rho * dot(y, &r);
+            r = axpy(&r, a - b, s);
+        }
+        r
+    }
+}
+
+impl Iteration for Lbfgs {
+    fn next(&mut self, objective: &Objective, from: &Point) -> Option<Point> {
+        let (_, point) = match objective.line_search(from, &self.direction(&from.forces), 1.0) {
+            Some(found) => found,
+            // Stale curvature can point the wrong way; start again from the force
+            None if !self.history.is_empty() => {
+                self.history.clear();
+                objective.line_search(from, &self.direction(&from.forces), 1.0)?
+            }
+            None => return None,
+        };
+
+        let s: Vec<f64> = point.x.iter().zip(&from.x).map(|(a, b)| a - b).collect();
+        let y: Vec<f64> = from.forces.iter().zip(&point.forces).map(|(a, b)| a - b).collect();
+        // Only keep pairs that leave the inverse Hessian positive definite
+        if dot(&s, &y) > 1e-10 {
+            if self.history.len() == LBFGS_HISTORY {
+                self.history.pop_front();
+            }
+            self.history.push_back((s, y));
+        }
+        Some(point)
+    }
+}
+
+/// The energy and largest force at `coords`, for a minimization run elsewhere.
+pub(crate) fn summarize(
+    coords: &[[f64; 3]],
+    topology: &Topology,
+    pair: &PairPotential,
+    params: &AtomPipelineParams,
+    iterations: u32,
+) -> MinimizationSummary {
+    let evaluation = evaluate(coords, topology, pair, params.cell.as_ref());
+    let max_force = max_norm(&flatten(&evaluation.forces));
+    MinimizationSummary {
+        energy: evaluation.energy(),
+        max_force,
+        iterations,
+        converged: max_force < params.force_tolerance as f64,
+    }
+}
+
+/// Minimize the energy with `params.minimizer` for at most `params.max_steps` iterations,
+/// stopping once the largest force falls below `params.force_tolerance`. Conjugate
+/// gradient and L-BFGS also stop once a line search changes the energy by less than
+/// `params.energy_tolerance`; steepest descent and FIRE take steps of their own size,
+/// which can change the energy by little anywhere, so only the force stops them.
+pub(crate) fn minimize(
+    coords: &mut [[f64; 3]],
+    topology: &Topology,
+    pair: &PairPotential,
+    params: &AtomPipelineParams,
+) -> MinimizationSummary {
+    let objective = Objective { topology, pair, cell: params.cell.as_ref() };
+    let step_size = params.step_size as f64;
+    let mut method: Box<dyn Iteration> = match params.minimizer {
+        Minimizer::SteepestDescent => Box::new(SteepestDescent { step_size }),
+        Minimizer::Fire => Box::new(Fire {
+            velocities: vec![0.0; 3 * coords.len()],
+            dt: FIRE_DT,
+            alpha: FIRE_ALPHA,
+            downhill_steps: 0,
+        }),
+        Minimizer::ConjugateGradient => Box::new(ConjugateGradient { previous: None, step: step_size, since_restart: 0 }),
+        Minimizer::Lbfgs => Box::new(Lbfgs { history: VecDeque::new(), initial_scale: step_size }),
+    };
+
+    let force_tolerance = params.force_tolerance as f64;
+    let energy_tolerance = match params.minimizer {
+        Minimizer::ConjugateGradient | Minimizer::Lbfgs => params.energy_tolerance as f64,
+        Minimizer::SteepestDescent | Minimizer::Fire => 0.0,
+    };
+    let mut point = objective.at(flatten(coords));
+    let mut iterations = 0;
+    let mut converged = max_norm(&point.forces) < force_tolerance;
+    while !converged && iterations < params.max_steps {
+        let Some(next) = method.next(&objective, &point) else { break };
+        iterations += 1;
+        converged = max_norm(&next.forces) < force_tolerance || (next.energy - point.energy).abs() < energy_tolerance;
+        point = next;
+    }
+
+    coords.copy_from_slice(&unflatten(&point.x));
+    MinimizationSummary { energy: point.energy, max_force: max_norm(&point.forces), iterations, converged }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compute_pipeline::{run_atom_pipeline, AtomPipelineParams, Backend, Minimizer};
+
+    #[test]
+    fn small_steps_do_not_count_as_converged() {
+        // A stretched C-C bond that a tiny step barely relaxes
+        let coords = [[0.0, 0.0, 0.0], [1.7, 0.0, 0.0]];
+        let atom_types = ["C".to_string(), "C".to_string()];
+        let bonds = [(0, 1)];
+        for minimizer in [Minimizer::SteepestDescent, Minimizer::Fire] {
+            let params = AtomPipelineParams {
+                process_type: 1,
+                step_size: 1e-7,
+                max_steps: 10,
+                backend: Backend::Cpu,
+                minimizer,
+                energy_tolerance: 1.0,
+                ..Default::default()
+            };
+            let summary = run_atom_pipeline(&coords, None, &atom_types, &bonds, None, params).unwrap().minimization.unwrap();
+            assert_eq!(summary.iterations, 10, "{:?}", minimizer);
+            assert!(!summary.converged, "{:?}", minimizer);
+        }
+    }
+}
@@ -0,0 +1,576 @@
+use pyo3::prelude::*;
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
+use std::fmt;
+
+use crate::pdb::PdbFilePy;
+use crate::perception::PerceptionError;
+use crate::rng::seeded;
+use crate::unit_cell::UnitCell;
+use crate::utilities::DataError;
+
+pub mod cpu;
+pub mod dynamics;
+pub mod forcefield;
+pub mod gpu;
+pub mod layout;
+pub mod minimizer;
+pub mod simulator;
+
+use cpu::{evaluate, pair_virials, run_cpu_pipeline, Evaluation, PairVirials};
+use forcefield::{Energies, PairPotential, Topology};
+use minimizer::MinimizationSummary;
+use gpu::run_gpu_pipeline;
+
+/// Which device runs the relaxation, minimization and simulation kernels.
+#[pyclass(eq, eq_int)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    /// Use the GPU when wgpu finds an adapter and opens a device on it, otherwise fall back
+    /// to the CPU, as also, with a warning, for anything the GPU backend does not support.
+    Auto,
+    Gpu,
+    Cpu,
+}
+
+/// How charges interact beyond the nonbonded cutoff.
+#[pyclass(eq, eq_int)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Electrostatics {
+    /// Treat everything past the cutoff as a dielectric continuum of permittivity `dielectric`.
+    ReactionField,
+    /// Shift the Coulomb force so it and the energy fall smoothly to zero at the cutoff.
+    ShiftedForce,
+}
+
+/// How a simulation holds its temperature at `temperature`.
+#[pyclass(eq, eq_int)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Thermostat {
+    /// Rescale velocities so the temperature relaxes exponentially. Quick to equilibrate,
+    /// but it does not sample the canonical ensemble.
+    Berendsen,
+    /// Bussi–Donadio–Parrinello stochastic velocity rescaling, which does.
+    VRescale,
+    /// Friction with the matching random force on every atom.
+    Langevin,
+}
+
+/// How a periodic simulation holds its pressure at `pressure`, by scaling the box and
+/// the molecules' centres of mass.
+#[pyclass(eq, eq_int)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Barostat {
+    /// Scale the box every step so the virial pressure relaxes exponentially.
+    Berendsen,
+    /// Try a random volume change every `barostat_interval` steps and accept it by the
+    /// Metropolis criterion.
+    MonteCarlo,
+}
+
+/// How a minimization (`process_type` 1) walks downhill.
+#[pyclass(eq, eq_int)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Minimizer {
+    /// Fixed steps of `step_size` along the force. The only minimizer the GPU runs.
+    SteepestDescent,
+    /// The fast inertial relaxation engine of Bitzek et al. (2006).
+    Fire,
+    /// Polak–Ribière conjugate gradient with a backtracking line search.
+    ConjugateGradient,
+    /// Limited-memory BFGS with a backtracking line search.
+    Lbfgs,
+}
+
+#[pyclass]
+#[derive(Clone, Copy, Debug)]
+pub struct AtomPipelineParams {
+    /// For relaxation and minimization, the displacement per unit force in Å² per kcal/mol;
+    /// the stiffest bonds in `forcefield.yml` go unstable above about 0.0015. For
+    /// simulation, the time step in ps.
+    #[pyo3(get, set)]
+    pub step_size: f32,
+    /// Steps to take, or for minimization the most iterations to take.
+    #[pyo3(get, set)]
+    pub max_steps: u32,
+    #[pyo3(get, set)]
+    pub process_type: u32, // 0 for relaxation, 1 for minimization, 2 for simulation
+    #[pyo3(get, set)]
+    pub backend: Backend,
+    /// Periodic box for minimum-image forces; `None` runs the system in vacuum.
+    #[pyo3(get, set)]
+    pub cell: Option<UnitCell>,
+    /// Lennard-Jones and Coulomb cutoff in Å. In a periodic box it may be at most half the box width.
+    #[pyo3(get, set)]
+    pub cutoff: f32,
+    #[pyo3(get, set)]
+    pub electrostatics: Electrostatics,
+    /// Relative permittivity beyond the cutoff for the reaction field.
+    #[pyo3(get, set)]
+    pub dielectric: f32,
+    /// Temperature in K of the Maxwell–Boltzmann velocities a simulation starts from
+    /// when it is given none.
+    #[pyo3(get, set)]
+    pub temperature: f32,
+    /// Holds a simulation at `temperature`; `None` conserves energy instead. The GPU backend
+    /// runs no thermostat, so `Backend::Auto` runs a thermostatted simulation on the CPU.
+    #[pyo3(get, set)]
+    pub thermostat: Option<Thermostat>,
+    /// Thermostat time constant in ps, or the inverse friction for Langevin.
+    #[pyo3(get, set)]
+    pub thermostat_tau: f32,
+    /// Holds a simulation at `pressure`; `None` keeps the box fixed. Needs a `cell`. Like a
+    /// thermostat, it keeps `Backend::Auto` on the CPU.
+    #[pyo3(get, set)]
+    pub barostat: Option<Barostat>,
+    /// Target pressure in bar.
+    #[pyo3(get, set)]
+    pub pressure: f32,
+    /// Berendsen barostat time constant in ps.
+    #[pyo3(get, set)]
+    pub barostat_tau: f32,
+    /// Isothermal compressibility in 1/bar for the Berendsen barostat; water's by default.
+    #[pyo3(get, set)]
+    pub compressibility: f32,
+    /// Steps between Monte Carlo volume moves.
+    #[pyo3(get, set)]
+    pub barostat_interval: u32,
+    #[pyo3(get, set)]
+    pub minimizer: Minimizer,
+    /// Minimization stops once no atom feels more than this force, in kcal/(mol·Å).
+    #[pyo3(get, set)]
+    pub force_tolerance: f32,
+    /// Conjugate gradient and L-BFGS minimization stop once an iteration changes the energy
+    /// by less than this, in kcal/mol. Steepest descent and FIRE stop on the force alone.
+    #[pyo3(get, set)]
+    pub energy_tolerance: f32,
+    /// Seeds the starting velocities and the stochastic thermostats and barostat, so a
+    /// simulation can be repeated exactly; `None` seeds them from the operating system.
+    #[pyo3(get, set)]
+    pub seed: Option<u64>,
+}
+
+#[pymethods]
+impl AtomPipelineParams {
+    #[new]
+    #[pyo3(signature = (
+        step_size=0.001,
+        max_steps=100,
+        process_type=0,
+        backend=Backend::Auto,
+        cell=None,
+        cutoff=10.0,
+        electrostatics=Electrostatics::ReactionField,
+        dielectric=78.5,
+        temperature=300.0,
+        thermostat=None,
+        thermostat_tau=0.1,
+        barostat=None,
+        pressure=1.0,
+        barostat_tau=1.0,
+        compressibility=4.5e-5,
+        barostat_interval=25,
+        minimizer=Minimizer::SteepestDescent,
+        force_tolerance=0.1,
+        energy_tolerance=1e-6,
+        seed=None,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        step_size: f32,
+        max_steps: u32,
+        process_type: u32,
+        backend: Backend,
+        cell: Option<UnitCell>,
+        cutoff: f32,
+        electrostatics: Electrostatics,
+        dielectric: f32,
+        temperature: f32,
+        thermostat: Option<Thermostat>,
+        thermostat_tau: f32,
+        barostat: Option<Barostat>,
+        pressure: f32,
+        barostat_tau: f32,
+        compressibility: f32,
+        barostat_interval: u32,
+        minimizer: Minimizer,
+        force_tolerance: f32,
+        energy_tolerance: f32,
+        seed: Option<u64>,
+    ) -> Self {
+        AtomPipelineParams {
+            step_size,
+            max_steps,
+            process_type,
+            backend,
+            cell,
+            cutoff,
+            electrostatics,
+            dielectric,
+            temperature,
+            thermostat,
+            thermostat_tau,
+            barostat,
+            pressure,
+            barostat_tau,
+            compressibility,
+            barostat_interval,
+            minimizer,
+            force_tolerance,
+            energy_tolerance,
+            seed,
+        }
+    }
+}
+
+impl Default for AtomPipelineParams {
+    fn default() -> Self {
+        AtomPipelineParams::new(
+            0.001,
+            100,
+            0,
+            Backend::Auto,
+            None,
+            10.0,
+            Electrostatics::ReactionField,
+            78.5,
+            300.0,
+            None,
+            0.1,
+            None,
+            1.0,
+            1.0,
+            4.5e-5,
+            25,
+            Minimizer::SteepestDescent,
+            0.1,
+            1e-6,
+            None,
+        )
+    }
+}
+
+/// Coordinates, velocities and box after a run of the pipeline.
+#[derive(Clone, Debug)]
+pub struct PipelineOutput {
+    pub coords: Vec<[f64; 3]>,
+    /// In Å/ps. Relaxation and minimization leave them as they were given.
+    pub velocities: Vec<[f64; 3]>,
+    /// The box, which only a barostat changes.
+    pub cell: Option<UnitCell>,
+    /// How a minimization ended; `None` for the other processes.
+    pub minimization: Option<MinimizationSummary>,
+}
+
+/// The end of a simulation: the final structure, its velocities and how hot it is.
+#[pyclass]
+#[derive(Clone)]
+pub struct SimulationResult {
+    #[pyo3(get)]
+    pub structure: PdbFilePy,
+    /// Velocities in Å/ps, which can start the next simulation where this one stopped.
+    #[pyo3(get)]
+    pub velocities: Vec<(f64, f64, f64)>,
+    /// Kinetic energy in kcal/mol.
+    #[pyo3(get)]
+    pub kinetic_energy: f64,
+    /// Instantaneous temperature in K.
+    #[pyo3(get)]
+    pub temperature: f64,
+}
+
+#[pymethods]
+impl SimulationResult {
+    fn __repr__(&self) -> String {
+        format!(
+            "SimulationResult(atoms={}, kinetic_energy={:.3}, temperature={:.2})",
+            self.velocities.len(),
+            self.kinetic_energy,
+            self.temperature
+        )
+    }
+}
+
+/// The end of a minimization: the final structure and how close it came to a minimum.
+#[pyclass]
+#[derive(Clone)]
+pub struct MinimizationReport {
+    #[pyo3(get)]
+    pub structure: PdbFilePy,
+    /// Potential energy in kcal/mol.
+    #[pyo3(get)]
+    pub energy: f64,
+    /// Largest force on any atom in kcal/(mol·Å).
+    #[pyo3(get)]
+    pub max_force: f64,
+    #[pyo3(get)]
+    pub iterations: u32,
+    /// Whether a tolerance was met before the iteration limit.
+    #[pyo3(get)]
+    pub converged: bool,
+}
+
+#[pymethods]
+impl MinimizationReport {
+    fn __repr__(&self) -> String {
+        format!(
+            "MinimizationReport(energy={:.4}, max_force={:.4}, iterations={}, converged={})",
+            self.energy,
+            self.max_force,
+            self.iterations,
+            if self.converged { "True" } else { "False" }
+        )
+    }
+}
+
+/// The potential energy of a structure by term, in kcal/mol.
+#[pyclass]
+#[derive(Clone)]
+pub struct EnergyBreakdown {
+    #[pyo3(get)]
+    pub bond: f64,
+    #[pyo3(get)]
+    pub angle: f64,
+    #[pyo3(get)]
+    pub torsion: f64,
+    #[pyo3(get)]
+    pub lennard_jones: f64,
+    #[pyo3(get)]
+    pub coulomb: f64,
+}
+
+impl From<Energies> for EnergyBreakdown {
+    fn from(e: Energies) -> Self {
+        EnergyBreakdown { bond: e.bond, angle: e.angle, torsion: e.torsion, lennard_jones: e.lennard_jones, coulomb: e.coulomb }
+    }
+}
+
+#[pymethods]
+impl EnergyBreakdown {
+    #[getter]
+    fn total(&self) -> f64 {
+        self.bond + self.angle + self.torsion + self.lennard_jones + self.coulomb
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "EnergyBreakdown(total={:.4}, bond={:.4}, angle={:.4}, torsion={:.4}, lennard_jones={:.4}, coulomb={:.4})",
+            self.total(),
+            self.bond,
+            self.angle,
+            self.torsion,
+            self.lennard_jones,
+            self.coulomb
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum PipelineError {
+    InvalidProcessType(u32),
+    InvalidBond(usize, usize),
+    InvalidCutoff(f32),
+    InvalidStepSize(f32),
+    InvalidVelocities(usize),
+    InvalidCoordinates(usize),
+    InvalidCharges(usize),
+    InvalidAtomTypes(usize),
+    InvalidVirtualSite(usize),
+    UnknownMass(String),
+    BarostatWithoutCell,
+    Unsupported(String),
+    NoAdapter,
+    Device(String),
+    ReadBack,
+    Data(DataError),
+}
+
+impl fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PipelineError::InvalidProcessType(t) => write!(f, "Invalid process type: {}", t),
+            PipelineError::InvalidBond(i, j) => write!(f, "Invalid bond ({}, {})", i, j),
+            PipelineError::InvalidCutoff(c) => write!(f, "Invalid cutoff {} Å: it must be positive and at most half the box width", c),
+            PipelineError::InvalidStepSize(s) => write!(f, "Invalid step size {}: it must be positive", s),
+            PipelineError::InvalidVelocities(n) => write!(f, "Got {} velocities for a different number of atoms", n),
+            PipelineError::InvalidCoordinates(n) => write!(f, "Got {} coordinates for a different number of atoms", n),
+            PipelineError::InvalidCharges(n) => write!(f, "Got {} charges for a different number of atoms", n),
+            PipelineError::InvalidAtomTypes(n) => write!(f, "Got {} atom types for a different number of atoms", n),
+            PipelineError::InvalidVirtualSite(i) => write!(f, "Virtual site {} has no water oxygen with two hydrogens to be placed from", i),
+            PipelineError::UnknownMass(t) => write!(f, "No mass for atom type '{}' in atom_properties.yml or the periodic table", t),
+            PipelineError::BarostatWithoutCell => write!(f, "A barostat needs a periodic cell"),
+            PipelineError::Unsupported(what) => write!(f, "The GPU backend does not support {}", what),
+            PipelineError::NoAdapter => write!(f, "Failed to find an appropriate adapter"),
+            PipelineError::Device(e) => write!(f, "Failed to create device: {}", e),
+            PipelineError::ReadBack => write!(f, "Failed to read result buffer"),
+            PipelineError::Data(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for PipelineError {}
+
+impl From<DataError> for PipelineError {
+    fn from(err: DataError) -> PipelineError {
+        PipelineError::Data(err)
+    }
+}
+
+impl From<PerceptionError> for PipelineError {
+    fn from(err: PerceptionError) -> PipelineError {
+        match err {
+            PerceptionError::InvalidBond(i, j) => PipelineError::InvalidBond(i, j),
+            PerceptionError::Data(e) => PipelineError::Data(e),
+        }
+    }
+}
+
+impl From<PipelineError> for PyErr {
+    fn from(err: PipelineError) -> PyErr {
+        match err {
+            PipelineError::InvalidProcessType(_)
+            | PipelineError::InvalidBond(..)
+            | PipelineError::InvalidCutoff(_)
+            | PipelineError::InvalidStepSize(_)
+            | PipelineError::InvalidVelocities(_)
+            | PipelineError::InvalidCoordinates(_)
+            | PipelineError::InvalidCharges(_)
+            | PipelineError::InvalidAtomTypes(_)
+            | PipelineError::InvalidVirtualSite(_)
+            | PipelineError::UnknownMass(_)
+            | PipelineError::BarostatWithoutCell
+            | PipelineError::Unsupported(_) => PyValueError::new_err(err.to_string()),
+            PipelineError::Data(e) => e.into(),
+            _ => PyRuntimeError::new_err(err.to_string()),
+        }
+    }
+}
+
+// Coordinates, atom types and bonds of a test structure.
+#[cfg(test)]
+pub(crate) type Structure = (Vec<[f64; 3]>, Vec<String>, Vec<(usize, usize)>);
+
+// The checks on `params` alone, which hold whenever they are replaced as well
+fn validate_params(params: &AtomPipelineParams) -> Result<(), PipelineError> {
+    if params.process_type > 2 {
+        return Err(PipelineError::InvalidProcessType(params.process_type));
+    }
+    if !(params.step_size > 0.0 && params.step_size.is_finite()) {
+        return Err(PipelineError::InvalidStepSize(params.step_size));
+    }
+    let max_cutoff = params.cell.map_or(f64::INFINITY, |c| c.widths().into_iter().fold(f64::INFINITY, f64::min) / 2.0);
+    if params.cutoff <= 0.0 || params.cutoff as f64 > max_cutoff {
+        return Err(PipelineError::InvalidCutoff(params.cutoff));
+    }
+    if params.barostat.is_some() && params.cell.is_none() {
+        return Err(PipelineError::BarostatWithoutCell);
+    }
+    Ok(())
+}
+
+// Checks shared by every way of running the kernels.
+fn validate(
+    coords: &[[f64; 3]],
+    velocities: Option<&[[f64; 3]]>,
+    atom_types: &[String],
+    bonds: &[(usize, usize)],
+    charges: Option<&[f64]>,
+    params: &AtomPipelineParams,
+) -> Result<(), PipelineError> {
+    validate_params(params)?;
+    if atom_types.len() != coords.len() {
+        return Err(PipelineError::InvalidAtomTypes(atom_types.len()));
+    }
+    if let Some(&(i, j)) = bonds.iter().find(|&&(i, j)| i == j || i >= coords.len() || j >= coords.len()) {
+        return Err(PipelineError::InvalidBond(i, j));
+    }
+    if let Some(v) = velocities.filter(|v| v.len() != coords.len()) {
+        return Err(PipelineError::InvalidVelocities(v.len()));
+    }
+    if let Some(q) = charges.filter(|q| q.len() != coords.len()) {
+        return Err(PipelineError::InvalidCharges(q.len()));
+    }
+    Ok(())
+}
+
+// The given velocities, or for a simulation without any, Maxwell–Boltzmann ones at `params.temperature`.
+fn initial_velocities(
+    atoms: usize,
+    velocities: Option<&[[f64; 3]]>,
+    atom_types: &[String],
+    params: &AtomPipelineParams,
+) -> Result<Vec<[f64; 3]>, PipelineError> {
+    if params.process_type == 2 {
+        // Every atom needs a mass to be integrated, whether or not it comes with a velocity
+        let masses = dynamics::masses(atom_types)?;
+        Ok(match velocities {
+            Some(v) => v.to_vec(),
+            None => dynamics::maxwell_boltzmann(&masses, params.temperature as f64, &mut seeded(params.seed)),
+        })
+    } else {
+        Ok(velocities.map_or_else(|| vec![[0.0; 3]; atoms], <[_]>::to_vec))
+    }
+}
+
+/// Relax, minimize or simulate a structure.
+///
+/// A simulation without `velocities` starts from Maxwell–Boltzmann velocities at
+/// `params.temperature`; the other processes ignore velocities and pass them through.
+/// On the GPU every call shares one device and its compiled pipelines; [`simulator::SimulatorPy`]
+/// also keeps the structure itself on the device between runs. Atoms carry the partial
+/// `charges` when given, otherwise their element's charge from `forcefield.yml`.
+pub fn run_atom_pipeline(
+    coords: &[[f64; 3]],
+    velocities: Option<&[[f64; 3]]>,
+    atom_types: &[String],
+    bonds: &[(usize, usize)],
+    charges: Option<&[f64]>,
+    params: AtomPipelineParams,
+) -> Result<PipelineOutput, PipelineError> {
+    validate(coords, velocities, atom_types, bonds, charges, &params)?;
+    let velocities = initial_velocities(coords.len(), velocities, atom_types, &params)?;
+
+    match params.backend {
+        Backend::Cpu => run_cpu_pipeline(coords, &velocities, atom_types, bonds, charges, params),
+        Backend::Gpu => run_gpu_pipeline(coords, &velocities, atom_types, bonds, charges, params),
+        Backend::Auto => match run_gpu_pipeline(coords, &velocities, atom_types, bonds, charges, params) {
+            Err(err @ PipelineError::Unsupported(_)) => {
+                // The CPU path is far slower on a large system, so falling back to it should not go unnoticed
+                log::warn!("{}; running on the CPU instead", err);
+                run_cpu_pipeline(coords, &velocities, atom_types, bonds, charges, params)
+            }
+            Err(err @ (PipelineError::NoAdapter | PipelineError::Device(_))) => {
+                log::info!("{}; running on the CPU instead", err);
+                run_cpu_pipeline(coords, &velocities, atom_types, bonds, charges, params)
+            }
+            result => result,
+        },
+    }
+}
+
+/// The energies, forces and virial of a structure under the force field the kernels use,
+/// with `params.cutoff`, `params.electrostatics` and `params.cell`, and with `by_pair` the
+/// virial of each interacting pair as well.
+///
+/// The topology is built from `reference`, or from `coords` without it, as a process
+/// builds it from its starting structure: bond orders and hybridization are perceived
+/// there, and bonded terms without a reference value in the force field take theirs
+/// from it, so that they contribute no energy when it is left out.
+pub(crate) fn evaluate_structure(
+    coords: &[[f64; 3]],
+    reference: Option<&[[f64; 3]]>,
+    atom_types: &[String],
+    bonds: &[(usize, usize)],
+    charges: Option<&[f64]>,
+    params: &AtomPipelineParams,
+    by_pair: bool,
+) -> Result<(Evaluation, PairVirials), PipelineError> {
+    validate(coords, None, atom_types, bonds, charges, params)?;
+    if let Some(r) = reference.filter(|r| r.len() != coords.len()) {
+        return Err(PipelineError::InvalidCoordinates(r.len()));
+    }
+    let topology = Topology::new(reference.unwrap_or(coords), atom_types, bonds, charges, params.cell.as_ref())?;
+    let pair = PairPotential::new(params)?;
+    let cell = params.cell.as_ref();
+    let pairs = if by_pair { pair_virials(coords, &topology, &pair, cell) } else { Vec::new() };
+    Ok((evaluate(coords, &topology, &pair, cell), pairs))
+}
@@ -0,0 +1,134 @@
+use std::sync::Arc;
+
+use pyo3::prelude::*;
+
+use crate::compute_pipeline::gpu::{shared_context, GpuContext, GpuSystem};
+use crate::compute_pipeline::{dynamics, initial_velocities, validate, validate_params, AtomPipelineParams};
+
+type Points = Vec<(f64, f64, f64)>;
+
+fn to_arrays(points: &[(f64, f64, f64)]) -> Vec<[f64; 3]> {
+    points.iter().map(|p| [p.0, p.1, p.2]).collect()
+}
+
+fn to_tuples(points: &[[f64; 3]]) -> Points {
+    points.iter().map(|p| (p[0], p[1], p[2])).collect()
+}
+
+/// A GPU device with the relax, minimize and simulate pipelines compiled, for any
+/// number of `Simulator`s to share.
+#[pyclass(name = "GpuContext")]
+#[derive(Clone)]
+pub struct GpuContextPy {
+    pub(crate) context: Arc<GpuContext>,
+}
+
+#[pymethods]
+impl GpuContextPy {
+    /// Open a device of its own.
+    #[new]
+    fn new() -> PyResult<Self> {
+        Ok(GpuContextPy { context: Arc::new(GpuContext::new()?) })
+    }
+
+    /// The device `run_simulation` and friends use.
+    #[staticmethod]
+    fn shared() -> PyResult<Self> {
+        Ok(GpuContextPy { context: shared_context()? })
+    }
+
+    #[getter]
+    fn adapter_name(&self) -> String {
+        self.context.adapter_name.clone()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("GpuContext(adapter='{}')", self.context.adapter_name)
+    }
+}
+
+/// A structure kept on the GPU, so that it can be stepped again and again without
+/// uploading it each time.
+///
+/// `params.process_type` picks what `step` runs, and `params` may be replaced between
+/// calls; `params.backend` is ignored. Bonded terms the force field has no reference
+/// value for keep the one in the coordinates the simulator was created with, and the
+/// atoms keep the partial `charges` given then, or their element's charge without them.
+#[pyclass(name = "Simulator")]
+pub struct SimulatorPy {
+    system: GpuSystem,
+    atom_types: Vec<String>,
+}
+
+#[pymethods]
+impl SimulatorPy {
+    #[new]
+    #[pyo3(signature = (coords, atom_types, bonds, params=None, velocities=None, context=None, charges=None))]
+    fn new(
+        coords: Points,
+        atom_types: Vec<String>,
+        bonds: Vec<(usize, usize)>,
+        params: Option<AtomPipelineParams>,
+        velocities: Option<Points>,
+        context: Option<GpuContextPy>,
+        charges: Option<Vec<f64>>,
+    ) -> PyResult<Self> {
+        let params = params.unwrap_or_default();
+        let coords = to_arrays(&coords);
+        let velocities = velocities.map(|v| to_arrays(&v));
+        validate(&coords, velocities.as_deref(), &atom_types, &bonds, charges.as_deref(), &params)?;
+        let velocities = initial_velocities(coords.len(), velocities.as_deref(), &atom_types, &params)?;
+        let context = match context {
+            Some(c) => c.context,
+            None => shared_context()?,
+        };
+        let system = GpuSystem::new(context, &coords, &velocities, &atom_types, &bonds, charges.as_deref(), params)?;
+        Ok(SimulatorPy { system, atom_types })
+    }
+
+    #[getter]
+    fn params(&self) -> AtomPipelineParams {
+        self.system.params()
+    }
+
+    #[setter]
+    fn set_params(&mut self, params: AtomPipelineParams) -> PyResult<()> {
+        validate_params(&params)?;
+        if params.process_type == 2 {
+            dynamics::masses(&self.atom_types)?;
+        }
+        Ok(self.system.set_params(params)?)
+    }
+
+    /// Queue `n` steps of the current process. They run while Python carries on, and
+    /// `download` waits for them.
+    #[pyo3(signature = (n=1))]
+    fn step(&mut self, n: u32) {
+        self.system.step(n);
+    }
+
+    /// Replace the coordinates on the GPU, and the velocities if given.
+    #[pyo3(signature = (coords, velocities=None))]
+    fn upload(&self, coords: Points, velocities: Option<Points>) -> PyResult<()> {
+        let velocities = velocities.map(|v| to_arrays(&v));
+        Ok(self.system.upload(&to_arrays(&coords), velocities.as_deref())?)
+    }
+
+    /// The current coordinates.
+    fn download(&self) -> PyResult<Points> {
+        Ok(to_tuples(&self.system.download()?.coords))
+    }
+
+    /// The current velocities in Å/ps.
+    fn download_velocities(&self) -> PyResult<Points> {
+        Ok(to_tuples(&self.system.download()?.velocities))
+    }
+
+    fn __len__(&self) -> usize {
+        self.system.atoms()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("Simulator(atoms={}, process_type={})", self.system.atoms(), self.system.params().process_type)
+    }
+}
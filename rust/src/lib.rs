@@ -1,68 +1,233 @@
+// pyo3 0.22's `#[pyfn]` expansion converts `PyResult` errors into `PyErr`, which clippy flags.
+#![allow(clippy::useless_conversion)]
+
 use pyo3::prelude::*;
 use pyo3::wrap_pymodule;
 use pyo3::Python;
 
 mod atom;
 mod bonds;
+mod charges;
+mod cif;
 mod pdb;
+mod perception;
 mod builder;
 mod compute_pipeline;
+mod neighbors;
+mod rng;
+mod trajectory;
+mod unit_cell;
 mod utilities;
 
+use atom::AtomRecord;
+use bonds::{BondRule, BondSearch};
 use pdb::PdbFilePy;
+use perception::{Bond, Hybridization, Perception};
+use rng::{resolve, RngPy, Seed};
+use trajectory::TrajectoryPy;
+use unit_cell::UnitCell;
 use builder::builder as build;
-use compute_pipeline::{run_atom_pipeline, AtomPipelineParams};
+use compute_pipeline::simulator::{GpuContextPy, SimulatorPy};
+use compute_pipeline::{
+    dynamics, evaluate_structure, run_atom_pipeline, AtomPipelineParams, Backend, Barostat, Electrostatics, EnergyBreakdown,
+    MinimizationReport, Minimizer, SimulationResult, Thermostat,
+};
+use numpy::ndarray::{Array2, Array3};
+use numpy::IntoPyArray;
 
 
 #[pymodule]
 fn simulate(_py: Python, m: Bound<PyModule>) -> PyResult<()> {
+    m.add_class::<AtomPipelineParams>()?;
+    m.add_class::<Backend>()?;
+    m.add_class::<Electrostatics>()?;
+    m.add_class::<Thermostat>()?;
+    m.add_class::<Barostat>()?;
+    m.add_class::<SimulationResult>()?;
+    m.add_class::<Minimizer>()?;
+    m.add_class::<MinimizationReport>()?;
+    m.add_class::<EnergyBreakdown>()?;
+    m.add_class::<GpuContextPy>()?;
+    m.add_class::<SimulatorPy>()?;
+
     #[pyfn(m, name = "run_atom_pipeline")]
     // params should be a dict compatible with ypthon dict
-    fn rap(coords: Vec<(f64, f64, f64)>, atom_types: Vec<String>, bonds: Vec<(usize, usize)>, params: AtomPipelineParams) -> Vec<(f64, f64, f64)> {
+    fn rap(coords: Vec<(f64, f64, f64)>, atom_types: Vec<String>, bonds: Vec<(usize, usize)>, params: AtomPipelineParams) -> PyResult<Vec<(f64, f64, f64)>> {
         let coords_vec: Vec<[f64; 3]> = coords.iter().map(|c| [c.0, c.1, c.2]).collect();
-        run_atom_pipeline(&coords_vec, &atom_types, &bonds, params).iter().map(|c| (c[0], c[1], c[2])).collect()
+        Ok(run_atom_pipeline(&coords_vec, None, &atom_types, &bonds, None, params)?.coords.iter().map(|c| (c[0], c[1], c[2])).collect())
     }
     
     #[pyfn(m, name = "run_simulation")]
-    fn run_simulation_py(coords: Vec<(f64, f64, f64)>, atom_types: Vec<String>, bonds: Vec<(usize, usize)>) -> PyResult<PdbFilePy> {
-        let pdb = rap(coords, atom_types.clone(), bonds.clone(), AtomPipelineParams {
-            step_size: 0.1,
-            max_steps: 100,
+    #[pyo3(signature = (
+        coords,
+        atom_types,
+        bonds,
+        backend=Backend::Auto,
+        cell=None,
+        velocities=None,
+        temperature=300.0,
+        steps=100,
+        timestep=0.001,
+        thermostat=None,
+        barostat=None,
+        pressure=1.0,
+        seed=None,
+        charges=None,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn run_simulation_py(
+        coords: Vec<(f64, f64, f64)>,
+        atom_types: Vec<String>,
+        bonds: Vec<(usize, usize)>,
+        backend: Backend,
+        cell: Option<UnitCell>,
+        velocities: Option<Vec<(f64, f64, f64)>>,
+        temperature: f32,
+        steps: u32,
+        timestep: f32,
+        thermostat: Option<Thermostat>,
+        barostat: Option<Barostat>,
+        pressure: f32,
+        seed: Option<Seed>,
+        charges: Option<Vec<f64>>,
+    ) -> PyResult<SimulationResult> {
+        let coords_vec: Vec<[f64; 3]> = coords.iter().map(|c| [c.0, c.1, c.2]).collect();
+        let velocities_vec: Option<Vec<[f64; 3]>> = velocities.map(|v| v.iter().map(|c| [c.0, c.1, c.2]).collect());
+        let output = run_atom_pipeline(&coords_vec, velocities_vec.as_deref(), &atom_types, &bonds, charges.as_deref(), AtomPipelineParams {
+            step_size: timestep,
+            max_steps: steps,
             process_type: 2,
-        });
-        Ok(PdbFilePy {
-            coords: pdb,
-            atom_types: atom_types,
-            bonds: bonds,
+            backend,
+            cell,
+            temperature,
+            thermostat,
+            barostat,
+            pressure,
+            seed: resolve(seed),
+            ..AtomPipelineParams::default()
+        })?;
+        let masses = dynamics::masses(&atom_types)?;
+        let kinetic_energy = dynamics::kinetic_energy(&output.velocities, &masses);
+        let coords = output.coords.iter().map(|c| (c[0], c[1], c[2])).collect();
+        Ok(SimulationResult {
+            structure: PdbFilePy::new(coords, atom_types.clone(), bonds, None, output.cell),
+            velocities: output.velocities.iter().map(|v| (v[0], v[1], v[2])).collect(),
+            kinetic_energy,
+            temperature: dynamics::temperature(kinetic_energy, &masses),
         })
     }
 
     #[pyfn(m, name = "run_minimization")]
-    fn run_minimization_py(coords: Vec<(f64, f64, f64)>, atom_types: Vec<String>, bonds: Vec<(usize, usize)>) -> PyResult<PdbFilePy> {
-        let pdb = rap(coords, atom_types.clone(), bonds.clone(), AtomPipelineParams {
-            step_size: 0.1,
-            max_steps: 100,
+    #[pyo3(signature = (
+        coords,
+        atom_types,
+        bonds,
+        backend=Backend::Auto,
+        cell=None,
+        minimizer=Minimizer::Lbfgs,
+        max_iterations=1000,
+        force_tolerance=0.1,
+        energy_tolerance=1e-6,
+        charges=None,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn run_minimization_py(
+        coords: Vec<(f64, f64, f64)>,
+        atom_types: Vec<String>,
+        bonds: Vec<(usize, usize)>,
+        backend: Backend,
+        cell: Option<UnitCell>,
+        minimizer: Minimizer,
+        max_iterations: u32,
+        force_tolerance: f32,
+        energy_tolerance: f32,
+        charges: Option<Vec<f64>>,
+    ) -> PyResult<MinimizationReport> {
+        let coords_vec: Vec<[f64; 3]> = coords.iter().map(|c| [c.0, c.1, c.2]).collect();
+        let output = run_atom_pipeline(&coords_vec, None, &atom_types, &bonds, charges.as_deref(), AtomPipelineParams {
             process_type: 1,
-        });
-        Ok(PdbFilePy {
-            coords: pdb,
-            atom_types: atom_types,
-            bonds: bonds,
+            max_steps: max_iterations,
+            backend,
+            cell,
+            minimizer,
+            force_tolerance,
+            energy_tolerance,
+            ..AtomPipelineParams::default()
+        })?;
+        let summary = output.minimization.expect("minimization always reports how it ended");
+        let coords = output.coords.iter().map(|c| (c[0], c[1], c[2])).collect();
+        Ok(MinimizationReport {
+            structure: PdbFilePy::new(coords, atom_types, bonds, None, cell),
+            energy: summary.energy,
+            max_force: summary.max_force,
+            iterations: summary.iterations,
+            converged: summary.converged,
+        })
+    }
+
+    // The energy by term in kcal/mol. Bonded terms the force field has no reference value
+    // for take theirs from `reference`, the structure a process would start from, or
+    // from `coords` without it.
+    #[pyfn(m, name = "compute_energy")]
+    #[pyo3(signature = (coords, atom_types, bonds, cell=None, params=None, charges=None, reference=None))]
+    #[allow(clippy::too_many_arguments)]
+    fn compute_energy_py(
+        coords: Vec<(f64, f64, f64)>,
+        atom_types: Vec<String>,
+        bonds: Vec<(usize, usize)>,
+        cell: Option<UnitCell>,
+        params: Option<AtomPipelineParams>,
+        charges: Option<Vec<f64>>,
+        reference: Option<Vec<(f64, f64, f64)>>,
+    ) -> PyResult<EnergyBreakdown> {
+        let coords_vec: Vec<[f64; 3]> = coords.iter().map(|c| [c.0, c.1, c.2]).collect();
+        let reference_vec: Option<Vec<[f64; 3]>> = reference.map(|r| r.iter().map(|c| [c.0, c.1, c.2]).collect());
+        let params = AtomPipelineParams { cell: cell.or(params.and_then(|p| p.cell)), ..params.unwrap_or_default() };
+        let (evaluation, _) = evaluate_structure(&coords_vec, reference_vec.as_deref(), &atom_types, &bonds, charges.as_deref(), &params, false)?;
+        Ok(evaluation.energies.into())
+    }
+
+    // Forces as an (N, 3) array in kcal/(mol·Å), with `reference` as for `compute_energy`.
+    // With `virial`, also the interacting pairs as a (P, 2) array of atom indices and the
+    // virial r ⊗ F of each as a (P, 3, 3) array in kcal/mol, which sum to the virial tensor.
+    #[pyfn(m, name = "compute_forces")]
+    #[pyo3(signature = (coords, atom_types, bonds, cell=None, params=None, virial=false, charges=None, reference=None))]
+    #[allow(clippy::too_many_arguments)]
+    fn compute_forces_py(
+        py: Python,
+        coords: Vec<(f64, f64, f64)>,
+        atom_types: Vec<String>,
+        bonds: Vec<(usize, usize)>,
+        cell: Option<UnitCell>,
+        params: Option<AtomPipelineParams>,
+        virial: bool,
+        charges: Option<Vec<f64>>,
+        reference: Option<Vec<(f64, f64, f64)>>,
+    ) -> PyResult<PyObject> {
+        let coords_vec: Vec<[f64; 3]> = coords.iter().map(|c| [c.0, c.1, c.2]).collect();
+        let reference_vec: Option<Vec<[f64; 3]>> = reference.map(|r| r.iter().map(|c| [c.0, c.1, c.2]).collect());
+        let params = AtomPipelineParams { cell: cell.or(params.and_then(|p| p.cell)), ..params.unwrap_or_default() };
+        let (evaluation, pairs) = evaluate_structure(&coords_vec, reference_vec.as_deref(), &atom_types, &bonds, charges.as_deref(), &params, virial)?;
+        let forces = Array2::from(evaluation.forces).into_pyarray_bound(py);
+        Ok(if virial {
+            let atoms = Array2::from(pairs.iter().map(|&((i, j), _)| [i, j]).collect::<Vec<_>>());
+            let virials = Array3::from(pairs.into_iter().map(|(_, v)| v).collect::<Vec<_>>());
+            (forces, atoms.into_pyarray_bound(py), virials.into_pyarray_bound(py)).into_py(py)
+        } else {
+            forces.into_py(py)
         })
     }
 
     #[pyfn(m, name = "run_relaxation")]
-    fn run_relaxation_py(coords: Vec<(f64, f64, f64)>, atom_types: Vec<String>, bonds: Vec<(usize, usize)>) -> PyResult<PdbFilePy> {
+    #[pyo3(signature = (coords, atom_types, bonds, backend=Backend::Auto, cell=None))]
+    fn run_relaxation_py(coords: Vec<(f64, f64, f64)>, atom_types: Vec<String>, bonds: Vec<(usize, usize)>, backend: Backend, cell: Option<UnitCell>) -> PyResult<PdbFilePy> {
         let pdb = rap(coords, atom_types.clone(), bonds.clone(), AtomPipelineParams {
-            step_size: 0.1,
-            max_steps: 100,
             process_type: 0,
-        });
-        Ok(PdbFilePy {
-            coords: pdb,
-            atom_types: atom_types,
-            bonds: bonds,
-        })
+            backend,
+            cell,
+            ..AtomPipelineParams::default()
+        })?;
+        Ok(PdbFilePy::new(pdb, atom_types, bonds, None, cell))
     }
 
     Ok(())
@@ -72,9 +237,24 @@ fn simulate(_py: Python, m: Bound<PyModule>) -> PyResult<()> {
 #[pymodule]
 fn rustquantic(_py: Python, m: Bound<PyModule>) -> PyResult<()> {
     m.add_class::<PdbFilePy>()?;
+    m.add_class::<AtomRecord>()?;
+    m.add_class::<TrajectoryPy>()?;
+    m.add_class::<UnitCell>()?;
+    m.add_class::<RngPy>()?;
+    m.add_class::<BondSearch>()?;
+    m.add_class::<BondRule>()?;
+    m.add_class::<Bond>()?;
+    m.add_class::<Hybridization>()?;
+    m.add_class::<Perception>()?;
+    m.add("PdbParseError", _py.get_type_bound::<pdb::PdbParseError>())?;
+    m.add("DataParseError", _py.get_type_bound::<utilities::DataParseError>())?;
+    m.add("CifParseError", _py.get_type_bound::<cif::CifParseError>())?;
     m.add_wrapped(wrap_pymodule!(crate::utilities::utilities))?;
     m.add_wrapped(wrap_pymodule!(build))?;
     m.add_wrapped(wrap_pymodule!(simulate))?;
 
     Ok(())
 }
+
+
+
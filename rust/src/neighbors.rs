@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+
+use crate::unit_cell::UnitCell;
+
+type Cell = (i64, i64, i64);
+
+/// Points binned into cells at least `cutoff` wide, so that every point within `cutoff`
+/// of another lies in the same cell or one of its 26 neighbours.
+///
+/// In a periodic box the cells tile the box in fractional coordinates, neighbours wrap
+/// around its faces, and distances are minimum-image distances.
+pub struct CellList {
+    cutoff: f64,
+    cell: Option<UnitCell>,
+    /// Cells along each box vector; `None` without a box. A box too small for three
+    /// cells along every vector keeps all points in one cell.
+    dims: Option<[i64; 3]>,
+    cells: HashMap<Cell, Vec<usize>>,
+}
+
+impl CellList {
+    pub fn new(coords: &[(f64, f64, f64)], cutoff: f64, cell: Option<UnitCell>) -> Self {
+        let dims = cell.map(|c| {
+            let counts = c.widths().map(|w| (w / cutoff).floor() as i64);
+            if counts.iter().all(|&n| n >= 3) { counts } else { [1, 1, 1] }
+        });
+        let mut list = CellList { cutoff, cell, dims, cells: HashMap::new() };
+        for (i, &c) in coords.iter().enumerate() {
+            list.insert(i, c);
+        }
+        list
+    }
+
+    /// Add the point with index `i`, which callers must also append to the coordinates they pass in.
+    pub fn insert(&mut self, i: usize, point: (f64, f64, f64)) {
+        let key = self.cell_of(point);
+        self.cells.entry(key).or_default().push(i);
+    }
+
+    fn cell_of(&self, p: (f64, f64, f64)) -> Cell {
+        match (&self.cell, self.dims) {
+            (Some(cell), Some(dims)) => {
+                let f = cell.fractional(p);
+                let bin = |k: usize| (((f[k] - f[k].floor()) * dims[k] as f64).floor() as i64).min(dims[k] - 1);
+                (bin(0), bin(1), bin(2))
+            }
+            _ => (
+                (p.0 / self.cutoff).floor() as i64,
+                (p.1 / self.cutoff).floor() as i64,
+                (p.2 / self.cutoff).floor() as i64,
+            ),
+        }
+    }
+
+    /// Indices of the points in the cell containing `point` and its neighbours, in ascending order.
+    fn candidates(&self, point: (f64, f64, f64)) -> Vec<usize> {
+        let (cx, cy, cz) = self.cell_of(point);
+        let mut found = Vec::new();
+        if self.dims == Some([1, 1, 1]) {
+            found.extend(self.cells.values().flatten());
+        } else {
+            for dx in -1..=1 {
+                for dy in -1..=1 {
+                    for dz in -1..=1 {
+                        let key = match self.dims {
+                            Some([nx, ny, nz]) => ((cx + dx).rem_euclid(nx), (cy + dy).rem_euclid(ny), (cz + dz).rem_euclid(nz)),
+                            None => (cx + dx, cy + dy, cz + dz),
+                        };
+                        if let Some(members) = self.cells.get(&key) {
+                            found.extend_from_slice(members);
+                        }
+                    }
+                }
+            }
+        }
+        found.sort_unstable();
+        found
+    }
+
+    /// The distance between two points, through the periodic boundaries if there are any.
+    pub fn distance(&self, a: (f64, f64, f64), b: (f64, f64, f64)) -> f64 {
+        match &self.cell {
+            Some(cell) => cell.distance(a, b),
+            None => distance(a, b),
+        }
+    }
+
+    /// Call `f(i, j, distance)` for every pair `i < j` of `coords` closer than the cutoff,
+    /// in the same order as a double loop over `i` and `j`.
+    ///
+    /// `coords` must be the points the list was built from.
+    pub fn for_each_pair(&self, coords: &[(f64, f64, f64)], mut f: impl FnMut(usize, usize, f64)) {
+        for (i, &a) in coords.iter().enumerate() {
+            for j in self.candidates(a) {
+                if j <= i {
+                    continue;
+                }
+                let d = self.distance(a, coords[j]);
+                if d <= self.cutoff {
+                    f(i, j, d);
+                }
+            }
+        }
+    }
+
+    /// The point of `coords` nearest to `point` among those within the cutoff that `accept` takes.
+    pub fn nearest(&self, coords: &[(f64, f64, f64)], point: (f64, f64, f64), accept: impl Fn(usize) -> bool) -> Option<usize> {
+        self.candidates(point)
+            .into_iter()
+            .filter(|&j| accept(j))
+            .map(|j| (j, self.distance(point, coords[j])))
+            .filter(|&(_, d)| d <= self.cutoff)
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(j, _)| j)
+    }
+
+    /// Whether any point of `coords` lies within `radius` of `point`.
+    pub fn any_within(&self, coords: &[(f64, f64, f64)], point: (f64, f64, f64), radius: f64) -> bool {
+        debug_assert!(radius <= self.cutoff);
+        self.candidates(point).into_iter().any(|j| self.distance(point, coords[j]) < radius)
+    }
+}
+
+pub(crate) fn distance(a: (f64, f64, f64), b: (f64, f64, f64)) -> f64 {
+    ((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2) + (a.2 - b.2).powi(2)).sqrt()
+}
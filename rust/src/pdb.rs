@@ -2,12 +2,21 @@ use pyo3::prelude::*;
 use pyo3::types::PyTuple;
 use std::fs::File;
 use std::io::{BufReader, BufRead, BufWriter, Write};
-use std::collections::HashSet;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use pyo3::exceptions::PyValueError;
 
 
-use crate::bonds::determine_bonds;
+use crate::atom::AtomRecord;
+use crate::bonds::{determine_bonds, determine_template_bonds, BondSearch};
+use crate::charges::partial_charges;
+use crate::cif::{read_cif, write_cif};
+use crate::perception::{bonds_from_conect, conect_pairs, perceive, Bond, Perception};
+use crate::unit_cell::UnitCell;
 
 #[pyclass]
+#[derive(Clone)]
 pub struct PdbFilePy {
     #[pyo3(get)]
     pub coords: Vec<(f64, f64, f64)>,
@@ -15,47 +24,295 @@ pub struct PdbFilePy {
     pub atom_types: Vec<String>,
     #[pyo3(get)]
     pub bonds: Vec<(usize, usize)>,
+    /// The bonds with their orders, once perceived or read from repeated CONECT entries.
+    #[pyo3(get)]
+    pub typed_bonds: Vec<Bond>,
+    #[pyo3(get, set)]
+    pub atoms: Vec<AtomRecord>,
+    /// The periodic box from the CRYST1 record, if any.
+    #[pyo3(get, set)]
+    pub cell: Option<UnitCell>,
+    /// Records skipped by a lenient parse, and why.
+    #[pyo3(get)]
+    pub warnings: Vec<String>,
 }
 
+pyo3::create_exception!(rustquantic, PdbParseError, PyValueError, "A PDB record could not be parsed.");
 
-#[pyfunction]
-pub fn parse_pdb(file_path: &str) -> PdbFilePy {
-    let file = File::open(file_path).unwrap();
+#[derive(Debug)]
+pub enum PdbError {
+    Io(std::io::Error),
+    Parse {
+        line: usize,
+        record: String,
+        columns: (usize, usize),
+        message: String,
+    },
+}
+
+impl fmt::Display for PdbError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PdbError::Io(e) => write!(f, "{}", e),
+            PdbError::Parse { line, record, columns, message } => {
+                write!(f, "line {}, {} record, columns {}-{}: {}", line, record, columns.0, columns.1, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PdbError {}
+
+impl From<std::io::Error> for PdbError {
+    fn from(err: std::io::Error) -> PdbError {
+        PdbError::Io(err)
+    }
+}
+
+impl From<PdbError> for PyErr {
+    fn from(err: PdbError) -> PyErr {
+        match err {
+            PdbError::Io(e) => e.into(),
+            PdbError::Parse { .. } => PdbParseError::new_err(err.to_string()),
+        }
+    }
+}
+
+/// The text in the 1-based, inclusive column range `start..=end`, or as much of it as the line has.
+fn columns(line: &str, start: usize, end: usize) -> &str {
+    let end = end.min(line.len());
+    if start > end {
+        return "";
+    }
+    line.get(start - 1..end).unwrap_or("")
+}
+
+/// Parse the trimmed contents of a column range, reporting where the record went wrong.
+fn field<T: FromStr>(line: &str, line_no: usize, start: usize, end: usize, what: &str) -> Result<T, PdbError> {
+    let text = columns(line, start, end).trim();
+    text.parse::<T>().map_err(|_| PdbError::Parse {
+        line: line_no,
+        record: columns(line, 1, 6).trim().to_string(),
+        columns: (start, end),
+        message: if text.is_empty() {
+            format!("missing {}", what)
+        } else {
+            format!("invalid {} '{}'", what, text)
+        },
+    })
+}
+
+/// Like `field`, but a blank column range gives `default`.
+fn optional_field<T: FromStr>(line: &str, line_no: usize, start: usize, end: usize, what: &str, default: T) -> Result<T, PdbError> {
+    if columns(line, start, end).trim().is_empty() {
+        Ok(default)
+    } else {
+        field(line, line_no, start, end, what)
+    }
+}
+
+/// Title-case an element symbol, so `FE` and `fe` both become `Fe`.
+pub(crate) fn normalize_element(symbol: &str) -> String {
+    let mut chars = symbol.trim().chars();
+    match chars.next() {
+        Some(first) => first.to_ascii_uppercase().to_string() + &chars.as_str().to_ascii_lowercase(),
+        None => String::new(),
+    }
+}
+
+/// Guess the element from the atom-name columns 13-16 when columns 77-78 are blank.
+///
+/// Two-letter elements start in column 13 and one-letter elements in column 14, except
+/// for four-character hydrogen names such as `HG21`, which also start in column 13.
+fn element_from_name(name_columns: &str) -> String {
+    let padded = format!("{:<4}", name_columns);
+    let bytes = padded.as_bytes();
+    let (first, second) = (bytes[0] as char, bytes[1] as char);
+    if !first.is_ascii_alphabetic() {
+        return padded.chars().skip(1).find(|c| c.is_ascii_alphabetic()).map(|c| c.to_string()).unwrap_or_default();
+    }
+    let full_width = !padded.ends_with(' ');
+    if !second.is_ascii_alphabetic() || (full_width && (first == 'H' || first == 'h')) {
+        return normalize_element(&first.to_string());
+    }
+    normalize_element(&padded[..2])
+}
+
+/// An ATOM/HETATM record split into its record fields, coordinates and element.
+pub(crate) type AtomLine = (AtomRecord, (f64, f64, f64), String);
+
+fn parse_atom_line(line: &str, line_no: usize) -> Result<AtomLine, PdbError> {
+    let x = field::<f64>(line, line_no, 31, 38, "x coordinate")?;
+    let y = field::<f64>(line, line_no, 39, 46, "y coordinate")?;
+    let z = field::<f64>(line, line_no, 47, 54, "z coordinate")?;
+    let record = AtomRecord {
+        hetatm: line.starts_with("HETATM"),
+        serial: field(line, line_no, 7, 11, "atom serial number")?,
+        name: columns(line, 13, 16).trim().to_string(),
+        alt_loc: columns(line, 17, 17).trim().to_string(),
+        res_name: columns(line, 18, 20).trim().to_string(),
+        chain_id: columns(line, 22, 22).trim().to_string(),
+        res_seq: field(line, line_no, 23, 26, "residue sequence number")?,
+        i_code: columns(line, 27, 27).trim().to_string(),
+        occupancy: optional_field(line, line_no, 55, 60, "occupancy", 1.0)?,
+        temp_factor: optional_field(line, line_no, 61, 66, "temperature factor", 0.0)?,
+        segment_id: columns(line, 73, 76).trim().to_string(),
+        charge: columns(line, 79, 80).trim().to_string(),
+    };
+    let element = match columns(line, 77, 78).trim() {
+        "" => element_from_name(columns(line, 13, 16)),
+        symbol => normalize_element(symbol),
+    };
+    Ok((record, (x, y, z), element))
+}
+
+/// The cell edges and angles in columns 7-54.
+fn parse_cryst1_line(line: &str, line_no: usize) -> Result<Option<UnitCell>, PdbError> {
+    UnitCell::from_cryst1([
+        field(line, line_no, 7, 15, "a")?,
+        field(line, line_no, 16, 24, "b")?,
+        field(line, line_no, 25, 33, "c")?,
+        field(line, line_no, 34, 40, "alpha")?,
+        field(line, line_no, 41, 47, "beta")?,
+        field(line, line_no, 48, 54, "gamma")?,
+    ])
+    .map_err(|e| PdbError::Parse { line: line_no, record: "CRYST1".to_string(), columns: (7, 54), message: e.to_string() })
+}
+
+/// The atom serial in columns 7-11 and the up to four bonded serials after it.
+fn parse_conect_line(line: &str, line_no: usize) -> Result<Vec<(usize, usize)>, PdbError> {
+    let from = field::<usize>(line, line_no, 7, 11, "atom serial number")?;
+    let mut pairs = Vec::new();
+    for start in [12, 17, 22, 27] {
+        if !columns(line, start, start + 4).trim().is_empty() {
+            pairs.push((from, field(line, line_no, start, start + 4, "bonded atom serial number")?));
+        }
+    }
+    Ok(pairs)
+}
+
+/// Whether a path names an mmCIF/PDBx file rather than a PDB file.
+pub(crate) fn is_cif_path(file_path: &str) -> bool {
+    let lower = file_path.to_ascii_lowercase();
+    lower.ends_with(".cif") || lower.ends_with(".mmcif")
+}
+
+/// Read a PDB file. In strict mode the first malformed record is an error; otherwise
+/// malformed records are skipped and described in `PdbFilePy::warnings`.
+///
+/// Only the first MODEL is read; see `read_pdb_models` for the rest.
+pub fn read_pdb(file_path: &str, strict: bool) -> Result<PdbFilePy, PdbError> {
+    read_pdb_models(file_path, strict).map(|(topology, _)| topology)
+}
+
+/// The coordinates of every atom at one point in a trajectory.
+pub type Frame = Vec<(f64, f64, f64)>;
+
+/// Read every MODEL of a PDB file as a frame of coordinates over the first model's atoms.
+///
+/// The returned structure holds the first model; the frames include it as frame 0.
+/// A file without MODEL records is a single frame.
+pub fn read_pdb_models(file_path: &str, strict: bool) -> Result<(PdbFilePy, Vec<Frame>), PdbError> {
+    let file = File::open(file_path)?;
     let reader = BufReader::new(file);
     let mut coords = Vec::new();
     let mut atom_types = Vec::new();
-    let mut bonds = Vec::new();
-    for line in reader.lines() {
-        let line = line.unwrap();
-        // Handle both ATOM and HETATM records
-        if line.starts_with("ATOM") || line.starts_with("HETATM") {
-            let atom_type = line[76..78].trim().to_string();
-            let x = line[30..38].trim().parse::<f64>().unwrap();
-            let y = line[38..46].trim().parse::<f64>().unwrap();
-            let z = line[46..54].trim().parse::<f64>().unwrap();
-            coords.push((x, y, z));
-            atom_types.push(atom_type);
-        }
-        // Handle CONECT records for bonds
-        if line.starts_with("CONECT") {
-            let mut split = line.split_whitespace();
-            split.next();
-            let from = split.next().unwrap().parse::<usize>().unwrap() - 1;
-            let mut to = Vec::new();
-            while let Some(t) = split.next() {
-                to.push(t.parse::<usize>().unwrap() - 1);
+    let mut atoms = Vec::new();
+    let mut conects = Vec::new();
+    let mut cell = None;
+    let mut warnings = Vec::new();
+    // Later models only contribute coordinates, with the line of their MODEL record
+    let mut models_seen = 0;
+    let mut later_models: Vec<(usize, Frame)> = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line_no = i + 1;
+        let parsed = if line.starts_with("CRYST1") {
+            parse_cryst1_line(&line, line_no).map(|c| cell = c)
+        } else if line.starts_with("MODEL") {
+            models_seen += 1;
+            if models_seen > 1 {
+                later_models.push((line_no, Vec::new()));
             }
-            for t in to {
-                bonds.push((from, t));
+            Ok(())
+        } else if line.starts_with("ATOM") || line.starts_with("HETATM") {
+            parse_atom_line(&line, line_no).map(|(record, coord, element)| match later_models.last_mut() {
+                Some((_, frame)) => frame.push(coord),
+                None => {
+                    atoms.push(record);
+                    coords.push(coord);
+                    atom_types.push(element);
+                }
+            })
+        } else if line.starts_with("CONECT") {
+            parse_conect_line(&line, line_no).map(|pairs| {
+                conects.extend(pairs.into_iter().map(|pair| (line_no, pair)));
+            })
+        } else {
+            Ok(())
+        };
+        match parsed {
+            Err(e @ PdbError::Parse { .. }) if !strict => warnings.push(e.to_string()),
+            other => other?,
+        }
+    }
+
+    // CONECT records refer to atoms by serial number, which need not match the atom's position
+    let index_of: HashMap<usize, usize> = atoms.iter().enumerate().map(|(i, a)| (a.serial, i)).collect();
+    let mut bonds = Vec::new();
+    for (line_no, (from, to)) in conects {
+        match (index_of.get(&from), index_of.get(&to)) {
+            (Some(&a), Some(&b)) => bonds.push((a, b)),
+            _ => {
+                let missing = if index_of.contains_key(&from) { to } else { from };
+                let err = PdbError::Parse {
+                    line: line_no,
+                    record: "CONECT".to_string(),
+                    columns: (7, 31),
+                    message: format!("no atom with serial number {}", missing),
+                };
+                if strict {
+                    return Err(err);
+                }
+                warnings.push(err.to_string());
             }
         }
     }
 
-    PdbFilePy {
+    let mut frames = vec![coords.clone()];
+    for (line_no, frame) in later_models {
+        if frame.len() == coords.len() {
+            frames.push(frame);
+            continue;
+        }
+        let err = PdbError::Parse {
+            line: line_no,
+            record: "MODEL".to_string(),
+            columns: (11, 14),
+            message: format!("model has {} atoms but the first model has {}", frame.len(), coords.len()),
+        };
+        if strict {
+            return Err(err);
+        }
+        warnings.push(err.to_string());
+    }
+
+    let topology = PdbFilePy {
         coords,
         atom_types,
+        typed_bonds: bonds_from_conect(&bonds).unwrap_or_default(),
         bonds,
-    }
+        atoms,
+        cell,
+        warnings,
+    };
+    Ok((topology, frames))
+}
+
+#[pyfunction]
+#[pyo3(signature = (file_path, strict=true))]
+pub fn parse_pdb(file_path: &str, strict: bool) -> PyResult<PdbFilePy> {
+    Ok(read_pdb(file_path, strict)?)
 }
 
 #[pyfunction]
@@ -100,36 +357,143 @@ pub fn adjust_coordinates_tuple(
     adjusted_coords
 }
 
-// write pdb - with optional bonds
-pub fn write_pdb(file_path: &str, coords: Vec<(f64, f64, f64)>, atom_types: Vec<String>, bonds: Option<Vec<(usize, usize)>>) {
-    let file = File::create(file_path).unwrap();
+/// Format an ATOM/HETATM line with every field in its fixed column.
+pub(crate) fn format_atom_line(record: &AtomRecord, coord: (f64, f64, f64), element: &str) -> String {
+    // Names of one-letter elements start in column 14 unless they fill all four columns
+    let name = if record.name.len() < 4 && element.len() < 2 {
+        format!(" {:<3}", record.name)
+    } else {
+        format!("{:<4}", record.name)
+    };
+    format!(
+        "{:<6}{:>5} {}{:1}{:>3} {:1}{:>4}{:1}   {:>8.3}{:>8.3}{:>8.3}{:>6.2}{:>6.2}      {:<4}{:>2}{:<2}",
+        if record.hetatm { "HETATM" } else { "ATOM" },
+        record.serial,
+        name,
+        record.alt_loc,
+        record.res_name,
+        record.chain_id,
+        record.res_seq,
+        record.i_code,
+        coord.0,
+        coord.1,
+        coord.2,
+        record.occupancy,
+        record.temp_factor,
+        record.segment_id,
+        element,
+        record.charge,
+    )
+}
+
+/// The record for each atom, falling back to a placeholder where `atoms` is short.
+fn records_for(atom_types: &[String], atoms: &[AtomRecord]) -> Vec<AtomRecord> {
+    (0..atom_types.len())
+        .map(|i| atoms.get(i).cloned().unwrap_or_else(|| AtomRecord::placeholder(i + 1, &atom_types[i])))
+        .collect()
+}
+
+fn write_atom_lines(writer: &mut impl Write, records: &[AtomRecord], coords: &[(f64, f64, f64)], atom_types: &[String]) -> std::io::Result<()> {
+    for (i, coord) in coords.iter().enumerate() {
+        writeln!(writer, "{}", format_atom_line(&records[i], *coord, &atom_types[i].to_uppercase()))?;
+    }
+    Ok(())
+}
+
+/// Checked before a writer creates its file, so that a bond to a missing atom leaves none behind.
+pub(crate) fn check_bonds(bonds: &[(usize, usize)], atoms: usize) -> std::io::Result<()> {
+    match bonds.iter().find(|&&(i, j)| i >= atoms || j >= atoms) {
+        Some(&(i, j)) => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Bond ({}, {}) refers to an atom beyond the {} to write", i, j, atoms),
+        )),
+        None => Ok(()),
+    }
+}
+
+fn write_conect_lines(writer: &mut impl Write, records: &[AtomRecord], bonds: &[(usize, usize)]) -> std::io::Result<()> {
+    // Consecutive bonds from the same atom share a CONECT record, four bonded atoms at most
+    let mut i = 0;
+    while i < bonds.len() {
+        let from = bonds[i].0;
+        let mut line = format!("CONECT{:>5}", records[from].serial);
+        let mut count = 0;
+        while i < bonds.len() && bonds[i].0 == from && count < 4 {
+            line.push_str(&format!("{:>5}", records[bonds[i].1].serial));
+            i += 1;
+            count += 1;
+        }
+        writeln!(writer, "{}", line)?;
+    }
+    Ok(())
+}
+
+/// Write a single model, with CONECT records when `bonds` are given.
+pub fn write_pdb(file_path: &str, coords: Vec<(f64, f64, f64)>, atom_types: Vec<String>, atoms: &[AtomRecord], cell: Option<&UnitCell>, bonds: Option<Vec<(usize, usize)>>) -> std::io::Result<()> {
+    check_bonds(bonds.as_deref().unwrap_or_default(), coords.len())?;
+    let file = File::create(file_path)?;
     let mut writer = BufWriter::new(file);
-    for (i, (x, y, z)) in coords.iter().enumerate() {
-        let atom_type = atom_types[i].clone();
-        writeln!(writer, "ATOM  {:>5} {:<3} MOL     1    {:>8.3} {:>8.3} {:>8.3}  1.00  0.00          {:>2}", i + 1, atom_type, x, y, z, atom_type).unwrap();
+    if let Some(cell) = cell {
+        writeln!(writer, "{}", cell.cryst1_record())?;
     }
+    let records = records_for(&atom_types, atoms);
+    write_atom_lines(&mut writer, &records, &coords, &atom_types)?;
     if let Some(bonds) = bonds {
-        for (from, to) in bonds {
-            writeln!(writer, "CONECT {:>5} {:>5}", from + 1, to + 1).unwrap();
-        }
+        write_conect_lines(&mut writer, &records, &bonds)?;
     }
+    writeln!(writer, "END")?;
+    writer.flush()
+}
+
+/// Write each frame as a MODEL/ENDMDL block over the same atoms, with CONECT records once at the end.
+pub fn write_pdb_models(file_path: &str, frames: &[Frame], atom_types: &[String], atoms: &[AtomRecord], cell: Option<&UnitCell>, bonds: Option<&[(usize, usize)]>) -> std::io::Result<()> {
+    check_bonds(bonds.unwrap_or_default(), atom_types.len())?;
+    let file = File::create(file_path)?;
+    let mut writer = BufWriter::new(file);
+    if let Some(cell) = cell {
+        writeln!(writer, "{}", cell.cryst1_record())?;
+    }
+    let records = records_for(atom_types, atoms);
+    for (i, frame) in frames.iter().enumerate() {
+        writeln!(writer, "MODEL     {:>4}", i + 1)?;
+        write_atom_lines(&mut writer, &records, frame, atom_types)?;
+        writeln!(writer, "ENDMDL")?;
+    }
+    if let Some(bonds) = bonds {
+        write_conect_lines(&mut writer, &records, bonds)?;
+    }
+    writeln!(writer, "END")?;
+    writer.flush()
 }
 
 #[pymethods]
 impl PdbFilePy {
     #[new]
-    pub fn new(coords: Vec<(f64, f64, f64)>, atom_types: Vec<String>, bonds: Vec<(usize, usize)>) -> Self {
+    #[pyo3(signature = (coords, atom_types, bonds, atoms=None, cell=None))]
+    pub fn new(coords: Vec<(f64, f64, f64)>, atom_types: Vec<String>, bonds: Vec<(usize, usize)>, atoms: Option<Vec<AtomRecord>>, cell: Option<UnitCell>) -> Self {
+        let atoms = atoms.unwrap_or_else(|| {
+            atom_types.iter().enumerate().map(|(i, t)| AtomRecord::placeholder(i + 1, t)).collect()
+        });
         PdbFilePy {
             coords,
             atom_types,
             bonds,
+            typed_bonds: Vec::new(),
+            atoms,
+            cell,
+            warnings: Vec::new(),
         }
     }
 
 
     #[staticmethod]
-    pub fn parse(file_path: &str) -> PdbFilePy {
-        parse_pdb(file_path)
+    #[pyo3(signature = (file_path, strict=true))]
+    pub fn parse(file_path: &str, strict: bool) -> PyResult<PdbFilePy> {
+        if is_cif_path(file_path) {
+            Ok(read_cif(file_path, strict)?)
+        } else {
+            parse_pdb(file_path, strict)
+        }
     }
 
 
@@ -137,15 +501,52 @@ impl PdbFilePy {
         self.coords = adjust_coordinates_tuple(self.coords.clone(), fill_size, margin);
     }
 
-    pub fn determine_bonds(&self) -> (Vec<(usize, usize)>, Vec<(usize, usize)>, HashSet<(String, String)>) {
-        determine_bonds(self.coords.clone(), self.atom_types.clone())
+    /// Find bonds by distance, as `bond_distances.yml` tabulates them and otherwise from
+    /// covalent radii, with `tolerance` Å of slack. With `templates`, standard residues are
+    /// bonded by their templates in `residues.yml` and only the rest by distance.
+    #[pyo3(signature = (tolerance=0.45, templates=true))]
+    pub fn determine_bonds(&self, tolerance: f64, templates: bool) -> PyResult<BondSearch> {
+        if templates {
+            Ok(determine_template_bonds(&self.coords, &self.atom_types, &self.atoms, self.cell, tolerance)?)
+        } else {
+            Ok(determine_bonds(self.coords.clone(), self.atom_types.clone(), self.cell, tolerance)?)
+        }
     }
 
     pub fn set_bonds(&mut self, bonds: Vec<(usize, usize)>) {
         self.bonds = bonds;
+        self.typed_bonds.clear();
+    }
+
+    /// Set the bonds with their orders, such as a `BondSearch`'s `typed_bonds`.
+    pub fn set_typed_bonds(&mut self, bonds: Vec<Bond>) {
+        self.bonds = bonds.iter().map(|b| b.atoms).collect();
+        self.typed_bonds = bonds;
     }
 
-    pub fn write(&self, file_path: &str, write_bonds: bool) {
-        write_pdb(file_path, self.coords.clone(), self.atom_types.clone(), if write_bonds { Some(self.bonds.clone()) } else { None });
+    /// Assign bond orders, aromaticity and hybridization to the current bonds, keeping the
+    /// orders in `typed_bonds` so that `write` repeats CONECT entries for multiple bonds.
+    pub fn perceive_bonds(&mut self) -> PyResult<Perception> {
+        let perception = perceive(&self.coords, &self.atom_types, &self.bonds, self.cell.as_ref())?;
+        self.typed_bonds = perception.bonds.clone();
+        Ok(perception)
+    }
+
+    /// The partial charge of every atom in e, by residue and atom name after the `charges`
+    /// table of `forcefield.yml`, for `run_simulation`, `compute_energy` and friends.
+    pub fn partial_charges(&self) -> PyResult<Vec<f64>> {
+        Ok(partial_charges(&self.atom_types, Some(&self.atoms))?)
+    }
+
+    /// Write as mmCIF when the path ends in `.cif` or `.mmcif`, otherwise as PDB.
+    pub fn write(&self, file_path: &str, write_bonds: bool) -> PyResult<()> {
+        if is_cif_path(file_path) {
+            let data_name = std::path::Path::new(file_path).file_stem().and_then(|s| s.to_str()).unwrap_or("structure");
+            write_cif(file_path, data_name, &self.coords, &self.atom_types, &self.atoms, self.cell.as_ref(), if write_bonds { Some(&self.bonds) } else { None })?;
+        } else {
+            let bonds = if self.typed_bonds.is_empty() { self.bonds.clone() } else { conect_pairs(&self.typed_bonds) };
+            write_pdb(file_path, self.coords.clone(), self.atom_types.clone(), &self.atoms, self.cell.as_ref(), if write_bonds { Some(bonds) } else { None })?;
+        }
+        Ok(())
     }
 }
\ No newline at end of file
@@ -0,0 +1,574 @@
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
+
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+
+use crate::unit_cell::UnitCell;
+use crate::utilities::element::{periodic_table, Element};
+use crate::utilities::DataError;
+
+type Point = (f64, f64, f64);
+
+// How much shorter than the single bond a double and a triple bond are, in Å, as for C, N and O
+const DOUBLE_SHORTENING: f64 = 0.2;
+const TRIPLE_SHORTENING: f64 = 0.33;
+// Furthest any atom of an aromatic ring may lie from the ring's plane, in Å
+const PLANARITY_TOLERANCE: f64 = 0.1;
+const MAX_RING_SIZE: usize = 7;
+
+#[derive(Debug)]
+pub enum PerceptionError {
+    /// A bond to an atom that is not there.
+    InvalidBond(usize, usize),
+    Data(DataError),
+}
+
+impl fmt::Display for PerceptionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PerceptionError::InvalidBond(i, j) => write!(f, "Invalid bond ({}, {})", i, j),
+            PerceptionError::Data(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for PerceptionError {}
+
+impl From<DataError> for PerceptionError {
+    fn from(err: DataError) -> PerceptionError {
+        PerceptionError::Data(err)
+    }
+}
+
+impl From<PerceptionError> for PyErr {
+    fn from(err: PerceptionError) -> PyErr {
+        match err {
+            PerceptionError::Data(e) => e.into(),
+            _ => PyValueError::new_err(err.to_string()),
+        }
+    }
+}
+
+/// The hybridization of an atom's valence orbitals.
+#[pyclass(eq, eq_int)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Hybridization {
+    /// Unbonded atoms, and elements outside the p-block.
+    Unknown,
+    /// Hydrogen and helium.
+    S,
+    Sp,
+    Sp2,
+    Sp3,
+}
+
+/// A bond with its order. Aromatic bonds keep the order of one Kekulé structure, so that
+/// they can still be written as single and double CONECT entries.
+#[pyclass]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Bond {
+    #[pyo3(get)]
+    pub atoms: (usize, usize),
+    #[pyo3(get)]
+    pub order: u8,
+    #[pyo3(get)]
+    pub aromatic: bool,
+}
+
+#[pymethods]
+impl Bond {
+    #[new]
+    #[pyo3(signature = (atoms, order=1, aromatic=false))]
+    pub fn new(atoms: (usize, usize), order: u8, aromatic: bool) -> Self {
+        Bond { atoms, order, aromatic }
+    }
+
+    fn __repr__(&self) -> String {
+        format!("Bond(atoms={:?}, order={}, aromatic={})", self.atoms, self.order, if self.aromatic { "True" } else { "False" })
+    }
+}
+
+/// Bond orders, aromaticity and hybridization perceived from the bonds and geometry.
+#[pyclass]
+#[derive(Clone)]
+pub struct Perception {
+    /// Each distinct bond once, in the order first given.
+    #[pyo3(get)]
+    pub bonds: Vec<Bond>,
+    #[pyo3(get)]
+    pub hybridization: Vec<Hybridization>,
+    /// The atoms of each aromatic ring, in order around it.
+    #[pyo3(get)]
+    pub aromatic_rings: Vec<Vec<usize>>,
+}
+
+#[pymethods]
+impl Perception {
+    fn __repr__(&self) -> String {
+        let multiple = self.bonds.iter().filter(|b| b.order > 1).count();
+        format!("Perception(bonds={}, multiple={}, aromatic_rings={})", self.bonds.len(), multiple, self.aromatic_rings.len())
+    }
+}
+
+/// The bonds as CONECT pairs, each repeated once per order the way RCSB files mark double and triple bonds.
+pub(crate) fn conect_pairs(bonds: &[Bond]) -> Vec<(usize, usize)> {
+    bonds.iter().flat_map(|b| std::iter::repeat_n(b.atoms, b.order.max(1) as usize)).collect()
+}
+
+/// Bonds from CONECT pairs, with a pair listed more than once from the same atom taken as a
+/// multiple bond. `None` when every pair is listed once, and the file says nothing of orders.
+pub(crate) fn bonds_from_conect(pairs: &[(usize, usize)]) -> Option<Vec<Bond>> {
+    let mut counts: HashMap<(usize, usize), u8> = HashMap::new();
+    for &pair in pairs {
+        *counts.entry(pair).or_default() += 1;
+    }
+    if counts.values().all(|&n| n == 1) {
+        return None;
+    }
+    let mut seen = HashSet::new();
+    let bonds = pairs
+        .iter()
+        .filter(|&&(a, b)| seen.insert((a.min(b), a.max(b))))
+        .map(|&(a, b)| {
+            let order = counts[&(a, b)].max(counts.get(&(b, a)).copied().unwrap_or(0));
+            Bond::new((a, b), order.min(3), false)
+        })
+        .collect();
+    Some(bonds)
+}
+
+fn sub(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn dot(a: [f64; 3], b: [f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+struct Geometry<'a> {
+    coords: &'a [Point],
+    cell: Option<&'a UnitCell>,
+}
+
+impl Geometry<'_> {
+    /// From atom `i` to atom `j`, through the periodic boundaries if there are any.
+    fn vector(&self, i: usize, j: usize) -> [f64; 3] {
+        let (p, q) = (self.coords[i], self.coords[j]);
+        let d = (q.0 - p.0, q.1 - p.1, q.2 - p.2);
+        let (x, y, z) = self.cell.map_or(d, |c| c.minimum_image(d));
+        [x, y, z]
+    }
+
+    fn length(&self, i: usize, j: usize) -> f64 {
+        let d = self.vector(i, j);
+        dot(d, d).sqrt()
+    }
+
+    /// The i-j-l angle in degrees.
+    fn angle(&self, i: usize, j: usize, l: usize) -> f64 {
+        let (a, b) = (self.vector(j, i), self.vector(j, l));
+        (dot(a, b) / (dot(a, a) * dot(b, b)).sqrt()).clamp(-1.0, 1.0).acos().to_degrees()
+    }
+
+    /// Whether every atom of `ring` lies within `PLANARITY_TOLERANCE` of its mean plane,
+    /// with the normal from Newell's method.
+    fn is_planar(&self, ring: &[usize]) -> bool {
+        let points: Vec<[f64; 3]> = ring.iter().map(|&k| self.vector(ring[0], k)).collect();
+        let n = points.len() as f64;
+        let centroid = [0, 1, 2].map(|k| points.iter().map(|p| p[k]).sum::<f64>() / n);
+        let mut normal = [0.0; 3];
+        for (k, p) in points.iter().enumerate() {
+            let q = points[(k + 1) % points.len()];
+            let c = cross(sub(*p, centroid), sub(q, centroid));
+            normal = [normal[0] + c[0], normal[1] + c[1], normal[2] + c[2]];
+        }
+        let norm = dot(normal, normal).sqrt();
+        norm > 0.0 && points.iter().all(|&p| (dot(sub(p, centroid), normal) / norm).abs() < PLANARITY_TOLERANCE)
+    }
+}
+
+fn is_p_block(atomic_number: u32) -> bool {
+    matches!(atomic_number, 5..=9 | 13..=17 | 31..=35 | 49..=53 | 81..=85)
+}
+
+/// The smallest ring through each bond, each ring once, in order around it.
+fn smallest_rings(neighbours: &[BTreeSet<usize>], pairs: &[(usize, usize)]) -> Vec<Vec<usize>> {
+    let mut seen = HashSet::new();
+    let mut rings = Vec::new();
+    for &(u, v) in pairs {
+        // Breadth-first from u to v without the u-v bond itself
+        let mut parent = HashMap::from([(u, u)]);
+        let mut queue = VecDeque::from([(u, 1)]);
+        while let Some((atom, depth)) = queue.pop_front() {
+            if atom == v || depth >= MAX_RING_SIZE {
+                continue;
+            }
+            for &next in &neighbours[atom] {
+                if (atom == u && next == v) || parent.contains_key(&next) {
+                    continue;
+                }
+                parent.insert(next, atom);
+                queue.push_back((next, depth + 1));
+            }
+        }
+        if !parent.contains_key(&v) {
+            continue;
+        }
+        let mut ring = vec![v];
+        while *ring.last().unwrap() != u {
+            ring.push(parent[ring.last().unwrap()]);
+        }
+        let mut key = ring.clone();
+        key.sort_unstable();
+        if seen.insert(key) {
+            rings.push(ring);
+        }
+    }
+    rings
+}
+
+/// As many disjoint pairs of adjacent atoms as there can be, found by backtracking, and of
+/// those the one leaving the fewest carbons out. It runs on one connected ring system at a
+/// time, gives up on a branch that cannot beat the best found, and stops at the first
+/// matching that pairs every atom with as many carbons paired as possible.
+struct Kekulizer<'a> {
+    adjacency: &'a [Vec<usize>],
+    carbon: &'a [bool],
+    partner: Vec<Option<usize>>,
+    best: Vec<Option<usize>>,
+    // Pairs and carbons paired, as they are now and in the best found
+    score: (usize, usize),
+    best_score: (usize, usize),
+    // Carbons among the atoms from each index on
+    carbons_after: Vec<usize>,
+}
+
+impl Kekulizer<'_> {
+    fn new<'a>(adjacency: &'a [Vec<usize>], carbon: &'a [bool]) -> Kekulizer<'a> {
+        let n = adjacency.len();
+        let mut carbons_after = vec![0; n + 1];
+        for k in (0..n).rev() {
+            carbons_after[k] = carbons_after[k + 1] + carbon[k] as usize;
+        }
+        Kekulizer { adjacency, carbon, partner: vec![None; n], best: vec![None; n], score: (0, 0), best_score: (0, 0), carbons_after }
+    }
+
+    fn is_perfect(&self) -> bool {
+        let n = self.partner.len();
+        self.best_score == (n / 2, self.carbons_after[0].min(n / 2 * 2))
+    }
+
+    fn search(&mut self, next: usize) {
+        let n = self.partner.len();
+        if next == n {
+            if self.score > self.best_score {
+                self.best_score = self.score;
+                self.best = self.partner.clone();
+            }
+            return;
+        }
+        // Every atom from `next` on pairing, each carbon among them included, is as well as this branch can do
+        let bound = (self.score.0 + (n - next) / 2, self.score.1 + self.carbons_after[next]);
+        if self.is_perfect() || bound <= self.best_score {
+            return;
+        }
+        if self.partner[next].is_none() {
+            for &j in self.adjacency[next].iter().filter(|&&j| j > next) {
+                if self.partner[j].is_some() {
+                    continue;
+                }
+                let carbons = self.carbon[next] as usize + self.carbon[j] as usize;
+                self.partner[next] = Some(j);
+                self.partner[j] = Some(next);
+                self.score = (self.score.0 + 1, self.score.1 + carbons);
+                self.search(next + 1);
+                self.score = (self.score.0 - 1, self.score.1 - carbons);
+                self.partner[next] = None;
+                self.partner[j] = None;
+            }
+        }
+        self.search(next + 1);
+    }
+}
+
+/// The connected components of a graph, each as its atoms in ascending order.
+fn components(adjacency: &[Vec<usize>]) -> Vec<Vec<usize>> {
+    let mut seen = vec![false; adjacency.len()];
+    let mut components = Vec::new();
+    for start in 0..adjacency.len() {
+        if seen[start] {
+            continue;
+        }
+        seen[start] = true;
+        let mut component = vec![start];
+        let mut stack = vec![start];
+        while let Some(k) = stack.pop() {
+            for &j in &adjacency[k] {
+                if !seen[j] {
+                    seen[j] = true;
+                    component.push(j);
+                    stack.push(j);
+                }
+            }
+        }
+        component.sort_unstable();
+        components.push(component);
+    }
+    components
+}
+
+/// Assign bond orders, aromaticity and hybridization from the elements' valences and the geometry.
+///
+/// Atoms first get a hybridization from their bond angles, and planar five- and six-membered
+/// rings of C, N, O and S make their atoms sp2. Such rings take a Kekulé structure, and are
+/// aromatic when it gives them 4n + 2 π electrons, counting two for each N, O or S whose lone
+/// pair is not in a double bond. Elsewhere a bond becomes double or triple when it is shorter
+/// than the sum of the covalent radii by about `DOUBLE_SHORTENING` or `TRIPLE_SHORTENING`, the
+/// shortest first, while both atoms have valence to spare: the smallest of the element's
+/// valences that covers its bonds. Second-row atoms never exceed what their geometry allows.
+pub(crate) fn perceive(coords: &[Point], atom_types: &[String], bonds: &[(usize, usize)], cell: Option<&UnitCell>) -> Result<Perception, PerceptionError> {
+    if let Some(&(i, j)) = bonds.iter().find(|&&(i, j)| i >= coords.len() || j >= coords.len() || i >= atom_types.len() || j >= atom_types.len()) {
+        return Err(PerceptionError::InvalidBond(i, j));
+    }
+    let table = periodic_table()?;
+    let geometry = Geometry { coords, cell };
+    let elements: Vec<Option<&Element>> = atom_types.iter().map(|t| table.get(t)).collect();
+    let number = |i: usize| elements[i].map_or(0, |e| e.atomic_number);
+
+    let mut pairs = Vec::new();
+    let mut index = HashMap::new();
+    let mut neighbours = vec![BTreeSet::new(); coords.len()];
+    for &(i, j) in bonds {
+        if i != j && !index.contains_key(&(i.min(j), i.max(j))) {
+            index.insert((i.min(j), i.max(j)), pairs.len());
+            pairs.push((i, j));
+            neighbours[i].insert(j);
+            neighbours[j].insert(i);
+        }
+    }
+    let bond_index = |i: usize, j: usize| index[&(i.min(j), i.max(j))];
+
+    let mut hybridization: Vec<Hybridization> = (0..coords.len())
+        .map(|i| {
+            let partners: Vec<usize> = neighbours[i].iter().copied().collect();
+            match (number(i), partners.len()) {
+                (1 | 2, _) => Hybridization::S,
+                (z, 2..=4) if is_p_block(z) => {
+                    let angles: Vec<f64> = (0..partners.len())
+                        .flat_map(|a| (a + 1..partners.len()).map(move |b| (a, b)))
+                        .map(|(a, b)| geometry.angle(partners[a], i, partners[b]))
+                        .collect();
+                    let mean = angles.iter().sum::<f64>() / angles.len() as f64;
+                    if mean > 155.0 {
+                        Hybridization::Sp
+                    } else if mean > 115.0 {
+                        Hybridization::Sp2
+                    } else {
+                        Hybridization::Sp3
+                    }
+                }
+                _ => Hybridization::Unknown,
+            }
+        })
+        .collect();
+
+    // Candidate aromatic rings, whose atoms are sp2 whatever their angles say
+    let rings: Vec<Vec<usize>> = smallest_rings(&neighbours, &pairs)
+        .into_iter()
+        .filter(|ring| {
+            (5..=6).contains(&ring.len())
+                && ring.iter().all(|&k| matches!(number(k), 6 | 7 | 8 | 16) && neighbours[k].len() <= 3)
+                && geometry.is_planar(ring)
+        })
+        .collect();
+    for &k in rings.iter().flatten() {
+        hybridization[k] = Hybridization::Sp2;
+    }
+
+    // How far short of a single bond each bond is
+    let shortening: Vec<Option<f64>> = pairs
+        .iter()
+        .map(|&(i, j)| Some(elements[i]?.covalent_radius + elements[j]?.covalent_radius - geometry.length(i, j)))
+        .collect();
+    // Beyond the second row, only bonds to a terminal atom are multiple, as in S=O and P=O
+    let is_short = |b: usize| {
+        let (i, j) = pairs[b];
+        let terminal = neighbours[i].len() == 1 || neighbours[j].len() == 1;
+        shortening[b].is_some_and(|s| s >= DOUBLE_SHORTENING / 2.0) && (terminal || number(i).max(number(j)) <= 10)
+    };
+
+    // Valence each atom has to spare for multiple bonds
+    let mut spare: Vec<u32> = (0..coords.len())
+        .map(|i| {
+            let Some(element) = elements[i] else { return 0 };
+            let degree = neighbours[i].len() as u32;
+            let short = neighbours[i].iter().filter(|&&j| is_short(bond_index(i, j))).count() as u32;
+            let valence = element.valences.iter().copied().filter(|&v| v >= degree + short).min()
+                .or_else(|| element.valences.iter().copied().filter(|&v| v >= degree).max())
+                .unwrap_or(degree);
+            let spare = valence - degree;
+            match (element.atomic_number <= 10, hybridization[i]) {
+                (true, Hybridization::S | Hybridization::Sp3) => 0,
+                (true, Hybridization::Sp2) => spare.min(1),
+                (true, Hybridization::Sp) => spare.min(2),
+                _ => spare,
+            }
+        })
+        .collect();
+
+    let mut orders = vec![1u8; pairs.len()];
+    let mut aromatic = vec![false; pairs.len()];
+    let mut aromatic_rings = Vec::new();
+
+    // A Kekulé structure over the candidate rings, kept only where a ring turns out aromatic
+    let ring_atoms: Vec<usize> = rings.iter().flatten().copied().collect::<BTreeSet<_>>().into_iter().filter(|&k| spare[k] > 0).collect();
+    let local: HashMap<usize, usize> = ring_atoms.iter().enumerate().map(|(l, &k)| (k, l)).collect();
+    let ring_bonds: HashSet<usize> = rings.iter().flat_map(|r| (0..r.len()).map(|k| bond_index(r[k], r[(k + 1) % r.len()]))).collect();
+    let adjacency: Vec<Vec<usize>> = ring_atoms
+        .iter()
+        .map(|&k| neighbours[k].iter().filter(|&&j| ring_bonds.contains(&bond_index(k, j))).filter_map(|j| local.get(j).copied()).collect())
+        .collect();
+    let mut matched: Vec<Option<usize>> = vec![None; ring_atoms.len()];
+    for component in components(&adjacency) {
+        let within: HashMap<usize, usize> = component.iter().enumerate().map(|(c, &l)| (l, c)).collect();
+        let adjacency: Vec<Vec<usize>> = component.iter().map(|&l| adjacency[l].iter().map(|j| within[j]).collect()).collect();
+        let carbon: Vec<bool> = component.iter().map(|&l| number(ring_atoms[l]) == 6).collect();
+        let mut kekulizer = Kekulizer::new(&adjacency, &carbon);
+        kekulizer.search(0);
+        for (c, &l) in component.iter().enumerate() {
+            matched[l] = kekulizer.best[c].map(|p| component[p]);
+        }
+    }
+    let partner = |k: usize| local.get(&k).and_then(|&l| matched[l]).map(|l| ring_atoms[l]);
+
+    for ring in &rings {
+        let electrons: Option<u32> = ring
+            .iter()
+            .map(|&k| match (partner(k), number(k)) {
+                (Some(_), _) => Some(1),
+                (None, 7) if neighbours[k].len() <= 3 => Some(2),
+                (None, 8 | 16) if neighbours[k].len() == 2 => Some(2),
+                _ => None,
+            })
+            .sum();
+        if electrons.is_some_and(|e| e % 4 == 2) {
+            for k in 0..ring.len() {
+                let (a, b) = (ring[k], ring[(k + 1) % ring.len()]);
+                aromatic[bond_index(a, b)] = true;
+                if partner(a) == Some(b) {
+                    orders[bond_index(a, b)] = 2;
+                }
+            }
+            aromatic_rings.push(ring.clone());
+        }
+    }
+    for (b, &(i, j)) in pairs.iter().enumerate() {
+        if aromatic[b] {
+            spare[i] = 0;
+            spare[j] = 0;
+        }
+    }
+
+    // Everything else, the bonds furthest short of single first
+    let mut candidates: Vec<(usize, f64)> = (0..pairs.len()).filter(|&b| !aromatic[b] && is_short(b)).map(|b| (b, shortening[b].unwrap())).collect();
+    candidates.sort_by(|x, y| y.1.total_cmp(&x.1));
+    for (b, s) in candidates {
+        let (i, j) = pairs[b];
+        let order = if s >= (DOUBLE_SHORTENING + TRIPLE_SHORTENING) / 2.0 && spare[i] >= 2 && spare[j] >= 2 {
+            3
+        } else if spare[i] >= 1 && spare[j] >= 1 {
+            2
+        } else {
+            continue;
+        };
+        orders[b] = order;
+        spare[i] -= order as u32 - 1;
+        spare[j] -= order as u32 - 1;
+    }
+
+    // Multiple bonds settle the hybridization of p-block atoms, and terminal ones are otherwise
+    // sp3. Geometry alone decides for hypervalent atoms such as the P of a phosphate.
+    let mut pi_bonds = vec![0; coords.len()];
+    for (b, &(i, j)) in pairs.iter().enumerate() {
+        pi_bonds[i] += orders[b] - 1;
+        pi_bonds[j] += orders[b] - 1;
+    }
+    for (i, h) in hybridization.iter_mut().enumerate() {
+        if !is_p_block(number(i)) || (number(i) > 10 && neighbours[i].len() >= 3) {
+            continue;
+        }
+        *h = match (pi_bonds[i], neighbours[i].len()) {
+            (0, 0) => Hybridization::Unknown,
+            (0, 1) => Hybridization::Sp3,
+            (0, _) => *h,
+            (1, _) => Hybridization::Sp2,
+            _ => Hybridization::Sp,
+        };
+    }
+
+    let bonds = pairs.iter().enumerate().map(|(b, &atoms)| Bond::new(atoms, orders[b], aromatic[b])).collect();
+    Ok(Perception { bonds, hybridization, aromatic_rings })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn benzonitrile_is_aromatic_with_a_triple_bond() {
+        // A benzene ring of 1.39 Å bonds without hydrogens, and a nitrile along the x axis
+        let mut coords: Vec<Point> = (0..6)
+            .map(|k| {
+                let a = std::f64::consts::PI / 3.0 * k as f64;
+                (1.39 * a.cos(), 1.39 * a.sin(), 0.0)
+            })
+            .collect();
+        coords.extend([(2.83, 0.0, 0.0), (3.99, 0.0, 0.0)]);
+        let atom_types: Vec<String> = ["C", "C", "C", "C", "C", "C", "C", "N"].iter().map(|t| t.to_string()).collect();
+        let mut bonds: Vec<(usize, usize)> = (0..6).map(|k| (k, (k + 1) % 6)).collect();
+        bonds.extend([(0, 6), (6, 7), (7, 6)]);
+
+        let perception = perceive(&coords, &atom_types, &bonds, None).unwrap();
+        assert_eq!(perception.bonds.len(), 8);
+        let ring = &perception.bonds[..6];
+        assert!(ring.iter().all(|b| b.aromatic));
+        assert_eq!(ring.iter().map(|b| b.order as u32).sum::<u32>(), 9);
+        assert_eq!(perception.bonds[6], Bond::new((0, 6), 1, false));
+        assert_eq!(perception.bonds[7], Bond::new((6, 7), 3, false));
+        assert_eq!(perception.hybridization[0], Hybridization::Sp2);
+        assert_eq!(perception.hybridization[6], Hybridization::Sp);
+        assert_eq!(perception.aromatic_rings.len(), 1);
+
+        let pairs = conect_pairs(&perception.bonds);
+        assert_eq!(pairs.len(), 6 + 3 + 1 + 3);
+        assert_eq!(bonds_from_conect(&pairs), Some(perception.bonds.iter().map(|b| Bond::new(b.atoms, b.order, false)).collect()));
+        assert_eq!(bonds_from_conect(&bonds[..6]), None);
+    }
+
+    #[test]
+    fn many_rings_kekulize_one_system_at_a_time() {
+        // Twenty separate benzene rings, which together have 2^20 Kekulé structures
+        let (mut coords, mut bonds) = (Vec::new(), Vec::new());
+        for r in 0..20 {
+            let first = coords.len();
+            coords.extend((0..6).map(|k| {
+                let a = std::f64::consts::PI / 3.0 * k as f64;
+                (1.39 * a.cos() + 10.0 * r as f64, 1.39 * a.sin(), 0.0)
+            }));
+            bonds.extend((0..6).map(|k| (first + k, first + (k + 1) % 6)));
+        }
+        let atom_types = vec!["C".to_string(); coords.len()];
+        let perception = perceive(&coords, &atom_types, &bonds, None).unwrap();
+        assert_eq!(perception.aromatic_rings.len(), 20);
+        assert_eq!(perception.bonds.iter().filter(|b| b.order == 2).count(), 60);
+
+        bonds.push((0, coords.len()));
+        assert!(matches!(perceive(&coords, &atom_types, &bonds, None), Err(PerceptionError::InvalidBond(0, 120))));
+    }
+}
@@ -0,0 +1,55 @@
+use pyo3::prelude::*;
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+
+/// A seedable random number generator, for reproducible builders and simulations.
+///
+/// Every call handed one draws a fresh seed from it, so a script that creates an `Rng`
+/// from a seed and passes it to each step in turn repeats itself exactly.
+#[pyclass(name = "Rng")]
+pub struct RngPy {
+    rng: ChaCha20Rng,
+}
+
+#[pymethods]
+impl RngPy {
+    /// Seeded from `seed`, or from the operating system without one.
+    #[new]
+    #[pyo3(signature = (seed=None))]
+    fn new(seed: Option<u64>) -> Self {
+        RngPy { rng: seeded(seed) }
+    }
+
+    /// A uniform random float in [0, 1).
+    fn random(&mut self) -> f64 {
+        rand::Rng::gen(&mut self.rng)
+    }
+
+    fn __repr__(&self) -> String {
+        format!("Rng(word_pos={})", self.rng.get_word_pos())
+    }
+}
+
+/// A `seed` argument: a fixed seed, or an `Rng` to draw one from.
+#[derive(FromPyObject)]
+pub enum Seed<'py> {
+    Value(u64),
+    Rng(Bound<'py, RngPy>),
+}
+
+/// The seed for one call, or `None` to seed from the operating system.
+pub(crate) fn resolve(seed: Option<Seed>) -> Option<u64> {
+    seed.map(|seed| match seed {
+        Seed::Value(value) => value,
+        Seed::Rng(rng) => rng.borrow_mut().rng.next_u64(),
+    })
+}
+
+/// ChaCha20 seeded from `seed`, whose stream is the same on every platform, or from the
+/// operating system without one.
+pub(crate) fn seeded(seed: Option<u64>) -> ChaCha20Rng {
+    match seed {
+        Some(seed) => ChaCha20Rng::seed_from_u64(seed),
+        None => ChaCha20Rng::from_entropy(),
+    }
+}
@@ -0,0 +1,94 @@
+use pyo3::prelude::*;
+use pyo3::exceptions::{PyIndexError, PyValueError};
+
+use crate::pdb::{is_cif_path, read_pdb_models, write_pdb_models, Frame, PdbFilePy};
+use crate::cif::read_cif;
+
+/// Frames of coordinates that share one topology: an NMR ensemble, a multi-MODEL file,
+/// or the output of successive `run_atom_pipeline` calls.
+#[pyclass]
+#[derive(Clone)]
+pub struct TrajectoryPy {
+    #[pyo3(get)]
+    pub topology: PdbFilePy,
+    pub frames: Vec<Frame>,
+}
+
+impl TrajectoryPy {
+    fn check_frame(&self, frame: &[(f64, f64, f64)]) -> PyResult<()> {
+        if frame.len() != self.topology.atom_types.len() {
+            return Err(PyValueError::new_err(format!(
+                "frame has {} atoms but the topology has {}",
+                frame.len(),
+                self.topology.atom_types.len()
+            )));
+        }
+        Ok(())
+    }
+
+    fn frame_index(&self, index: isize) -> PyResult<usize> {
+        let len = self.frames.len() as isize;
+        let i = if index < 0 { index + len } else { index };
+        if i < 0 || i >= len {
+            return Err(PyIndexError::new_err("frame index out of range"));
+        }
+        Ok(i as usize)
+    }
+}
+
+#[pymethods]
+impl TrajectoryPy {
+    /// Start a trajectory over `topology`, by default with its own coordinates as the first frame.
+    #[new]
+    #[pyo3(signature = (topology, frames=None))]
+    pub fn new(topology: PdbFilePy, frames: Option<Vec<Frame>>) -> PyResult<Self> {
+        let frames = frames.unwrap_or_else(|| vec![topology.coords.clone()]);
+        let trajectory = TrajectoryPy { topology, frames: Vec::new() };
+        for frame in &frames {
+            trajectory.check_frame(frame)?;
+        }
+        Ok(TrajectoryPy { frames, ..trajectory })
+    }
+
+    /// Read every MODEL of a PDB file; an mmCIF file gives a single frame.
+    #[staticmethod]
+    #[pyo3(signature = (file_path, strict=true))]
+    pub fn parse(file_path: &str, strict: bool) -> PyResult<Self> {
+        if is_cif_path(file_path) {
+            let topology = read_cif(file_path, strict)?;
+            let frames = vec![topology.coords.clone()];
+            return Ok(TrajectoryPy { topology, frames });
+        }
+        let (topology, frames) = read_pdb_models(file_path, strict)?;
+        Ok(TrajectoryPy { topology, frames })
+    }
+
+    fn __len__(&self) -> usize {
+        self.frames.len()
+    }
+
+    fn __getitem__(&self, index: isize) -> PyResult<Vec<(f64, f64, f64)>> {
+        Ok(self.frames[self.frame_index(index)?].clone())
+    }
+
+    pub fn append(&mut self, frame: Vec<(f64, f64, f64)>) -> PyResult<()> {
+        self.check_frame(&frame)?;
+        self.frames.push(frame);
+        Ok(())
+    }
+
+    /// The topology with the coordinates of one frame.
+    pub fn frame(&self, index: isize) -> PyResult<PdbFilePy> {
+        let mut structure = self.topology.clone();
+        structure.coords = self.frames[self.frame_index(index)?].clone();
+        Ok(structure)
+    }
+
+    /// Write a multi-model PDB file with one MODEL per frame.
+    #[pyo3(signature = (file_path, write_bonds=false))]
+    pub fn write(&self, file_path: &str, write_bonds: bool) -> PyResult<()> {
+        let bonds = if write_bonds { Some(self.topology.bonds.as_slice()) } else { None };
+        write_pdb_models(file_path, &self.frames, &self.topology.atom_types, &self.topology.atoms, self.topology.cell.as_ref(), bonds)?;
+        Ok(())
+    }
+}
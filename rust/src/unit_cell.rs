@@ -0,0 +1,298 @@
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use std::collections::VecDeque;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum UnitCellError {
+    /// An edge that is not a positive length.
+    DegenerateEdge(f64),
+    /// Angles that do not span a box of any volume.
+    DegenerateAngles(f64, f64, f64),
+    /// A bond to an atom that is not there.
+    InvalidBond(usize, usize),
+}
+
+impl fmt::Display for UnitCellError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UnitCellError::DegenerateEdge(l) => write!(f, "Invalid cell edge {} Å: it must be positive", l),
+            UnitCellError::DegenerateAngles(alpha, beta, gamma) => {
+                write!(f, "Invalid cell angles ({}, {}, {}): the box they give has no volume", alpha, beta, gamma)
+            }
+            UnitCellError::InvalidBond(i, j) => write!(f, "Invalid bond ({}, {})", i, j),
+        }
+    }
+}
+
+impl std::error::Error for UnitCellError {}
+
+impl From<UnitCellError> for PyErr {
+    fn from(err: UnitCellError) -> PyErr {
+        PyValueError::new_err(err.to_string())
+    }
+}
+
+/// A periodic simulation box, orthorhombic or triclinic, as described by a CRYST1 record.
+///
+/// The box vectors follow the PDB convention: `a` along x, `b` in the xy plane.
+#[pyclass]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct UnitCell {
+    #[pyo3(get)]
+    pub a: f64,
+    #[pyo3(get)]
+    pub b: f64,
+    #[pyo3(get)]
+    pub c: f64,
+    #[pyo3(get)]
+    pub alpha: f64,
+    #[pyo3(get)]
+    pub beta: f64,
+    #[pyo3(get)]
+    pub gamma: f64,
+    /// Box vectors as rows, so a cartesian point is its fractional coordinates times this matrix.
+    matrix: [[f64; 3]; 3],
+    inverse: [[f64; 3]; 3],
+}
+
+fn row_times(v: [f64; 3], m: &[[f64; 3]; 3]) -> [f64; 3] {
+    [
+        v[0] * m[0][0] + v[1] * m[1][0] + v[2] * m[2][0],
+        v[0] * m[0][1] + v[1] * m[1][1] + v[2] * m[2][1],
+        v[0] * m[0][2] + v[1] * m[1][2] + v[2] * m[2][2],
+    ]
+}
+
+fn invert(m: &[[f64; 3]; 3]) -> [[f64; 3]; 3] {
+    let det = m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+        - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+        + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]);
+    let inv_det = 1.0 / det;
+    [
+        [
+            (m[1][1] * m[2][2] - m[1][2] * m[2][1]) * inv_det,
+            (m[0][2] * m[2][1] - m[0][1] * m[2][2]) * inv_det,
+            (m[0][1] * m[1][2] - m[0][2] * m[1][1]) * inv_det,
+        ],
+        [
+            (m[1][2] * m[2][0] - m[1][0] * m[2][2]) * inv_det,
+            (m[0][0] * m[2][2] - m[0][2] * m[2][0]) * inv_det,
+            (m[0][2] * m[1][0] - m[0][0] * m[1][2]) * inv_det,
+        ],
+        [
+            (m[1][0] * m[2][1] - m[1][1] * m[2][0]) * inv_det,
+            (m[0][1] * m[2][0] - m[0][0] * m[2][1]) * inv_det,
+            (m[0][0] * m[1][1] - m[0][1] * m[1][0]) * inv_det,
+        ],
+    ]
+}
+
+// Exactly zero at right angles, so orthorhombic matrices carry no rounding noise.
+fn cos_degrees(angle: f64) -> f64 {
+    if angle == 90.0 { 0.0 } else { angle.to_radians().cos() }
+}
+
+fn to_array(p: (f64, f64, f64)) -> [f64; 3] {
+    [p.0, p.1, p.2]
+}
+
+fn to_tuple(p: [f64; 3]) -> (f64, f64, f64) {
+    (p[0], p[1], p[2])
+}
+
+#[pymethods]
+impl UnitCell {
+    /// Edge lengths in Å and angles in degrees.
+    #[new]
+    #[pyo3(signature = (a, b, c, alpha=90.0, beta=90.0, gamma=90.0))]
+    pub fn new(a: f64, b: f64, c: f64, alpha: f64, beta: f64, gamma: f64) -> Result<Self, UnitCellError> {
+        if let Some(&length) = [a, b, c].iter().find(|&&l| !(l > 0.0 && l.is_finite())) {
+            return Err(UnitCellError::DegenerateEdge(length));
+        }
+        let (cos_a, cos_b, cos_g) = (cos_degrees(alpha), cos_degrees(beta), cos_degrees(gamma));
+        // The squared volume of the cell with unit edges: zero or less for angles of 0 or 180
+        // degrees, and for any three that cannot meet at a corner
+        let volume = 1.0 - cos_a * cos_a - cos_b * cos_b - cos_g * cos_g + 2.0 * cos_a * cos_b * cos_g;
+        if !(volume > 1e-12 && [alpha, beta, gamma].iter().all(|angle| 0.0 < *angle && *angle < 180.0)) {
+            return Err(UnitCellError::DegenerateAngles(alpha, beta, gamma));
+        }
+        Ok(UnitCell::from_checked_parameters(a, b, c, alpha, beta, gamma))
+    }
+
+    #[staticmethod]
+    pub fn orthorhombic(a: f64, b: f64, c: f64) -> Result<Self, UnitCellError> {
+        UnitCell::new(a, b, c, 90.0, 90.0, 90.0)
+    }
+
+    pub fn is_orthorhombic(&self) -> bool {
+        [self.alpha, self.beta, self.gamma].iter().all(|angle| (angle - 90.0).abs() < 1e-6)
+    }
+
+    /// The box vectors a, b and c.
+    #[getter]
+    pub fn vectors(&self) -> [(f64, f64, f64); 3] {
+        [to_tuple(self.matrix[0]), to_tuple(self.matrix[1]), to_tuple(self.matrix[2])]
+    }
+
+    #[getter]
+    pub fn volume(&self) -> f64 {
+        self.matrix[0][0] * self.matrix[1][1] * self.matrix[2][2]
+    }
+
+    /// The shortest periodic image of the displacement `d`.
+    ///
+    /// Exact for orthorhombic boxes; for strongly skewed triclinic boxes it can miss a
+    /// shorter image once `d` exceeds half the smallest box width.
+    pub fn minimum_image(&self, d: (f64, f64, f64)) -> (f64, f64, f64) {
+        let mut f = row_times(to_array(d), &self.inverse);
+        for x in f.iter_mut() {
+            *x -= x.round();
+        }
+        to_tuple(row_times(f, &self.matrix))
+    }
+
+    pub fn distance(&self, p: (f64, f64, f64), q: (f64, f64, f64)) -> f64 {
+        let d = self.minimum_image((q.0 - p.0, q.1 - p.1, q.2 - p.2));
+        (d.0 * d.0 + d.1 * d.1 + d.2 * d.2).sqrt()
+    }
+
+    /// Move every point into the box.
+    pub fn wrap(&self, coords: Vec<(f64, f64, f64)>) -> Vec<(f64, f64, f64)> {
+        coords.into_iter().map(|p| self.wrap_point(p)).collect()
+    }
+
+    /// Make bonded molecules whole again, placing each atom at the periodic image
+    /// nearest the atom it is bonded to. The first atom of each molecule stays put.
+    pub fn unwrap(&self, coords: Vec<(f64, f64, f64)>, bonds: Vec<(usize, usize)>) -> Result<Vec<(f64, f64, f64)>, UnitCellError> {
+        let mut neighbours = vec![Vec::new(); coords.len()];
+        for &(i, j) in &bonds {
+            if i >= coords.len() || j >= coords.len() {
+                return Err(UnitCellError::InvalidBond(i, j));
+            }
+            neighbours[i].push(j);
+            neighbours[j].push(i);
+        }
+        let mut unwrapped = coords.clone();
+        let mut visited = vec![false; coords.len()];
+        for start in 0..coords.len() {
+            if visited[start] {
+                continue;
+            }
+            visited[start] = true;
+            let mut queue = VecDeque::from([start]);
+            while let Some(i) = queue.pop_front() {
+                for &j in &neighbours[i] {
+                    if visited[j] {
+                        continue;
+                    }
+                    visited[j] = true;
+                    let p = unwrapped[i];
+                    let d = self.minimum_image((coords[j].0 - p.0, coords[j].1 - p.1, coords[j].2 - p.2));
+                    unwrapped[j] = (p.0 + d.0, p.1 + d.1, p.2 + d.2);
+                    queue.push_back(j);
+                }
+            }
+        }
+        Ok(unwrapped)
+    }
+
+    /// The CRYST1 record for this cell, in space group P 1.
+    pub fn cryst1_record(&self) -> String {
+        format!(
+            "CRYST1{:>9.3}{:>9.3}{:>9.3}{:>7.2}{:>7.2}{:>7.2} {:<11}{:>4}",
+            self.a, self.b, self.c, self.alpha, self.beta, self.gamma, "P 1", 1
+        )
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "UnitCell(a={}, b={}, c={}, alpha={}, beta={}, gamma={})",
+            self.a, self.b, self.c, self.alpha, self.beta, self.gamma
+        )
+    }
+}
+
+impl UnitCell {
+    // The box vectors of edges and angles `new` has checked
+    fn from_checked_parameters(a: f64, b: f64, c: f64, alpha: f64, beta: f64, gamma: f64) -> Self {
+        let (cos_a, cos_b, cos_g) = (cos_degrees(alpha), cos_degrees(beta), cos_degrees(gamma));
+        let sin_g = gamma.to_radians().sin();
+        let cy = (cos_a - cos_b * cos_g) / sin_g;
+        let cz = (1.0 - cos_b * cos_b - cy * cy).max(0.0).sqrt();
+        let matrix = [
+            [a, 0.0, 0.0],
+            [b * cos_g, b * sin_g, 0.0],
+            [c * cos_b, c * cy, c * cz],
+        ];
+        UnitCell { a, b, c, alpha, beta, gamma, matrix, inverse: invert(&matrix) }
+    }
+
+    /// Fractional coordinates of a cartesian point.
+    pub fn fractional(&self, p: (f64, f64, f64)) -> [f64; 3] {
+        row_times(to_array(p), &self.inverse)
+    }
+
+    pub fn cartesian(&self, f: [f64; 3]) -> (f64, f64, f64) {
+        to_tuple(row_times(f, &self.matrix))
+    }
+
+    pub fn wrap_point(&self, p: (f64, f64, f64)) -> (f64, f64, f64) {
+        let mut f = self.fractional(p);
+        for x in f.iter_mut() {
+            *x -= x.floor();
+        }
+        self.cartesian(f)
+    }
+
+    /// The distance between opposite faces of the box along each box vector.
+    pub fn widths(&self) -> [f64; 3] {
+        // Each column of the inverse is normal to two box faces, with length 1 / width
+        let norm = |r: [f64; 3]| (r[0] * r[0] + r[1] * r[1] + r[2] * r[2]).sqrt();
+        let columns = |k: usize| [self.inverse[0][k], self.inverse[1][k], self.inverse[2][k]];
+        [1.0 / norm(columns(0)), 1.0 / norm(columns(1)), 1.0 / norm(columns(2))]
+    }
+
+    /// The same cell with every edge `factor` times as long; `factor` must be positive.
+    pub fn scaled(&self, factor: f64) -> Self {
+        UnitCell::from_checked_parameters(self.a * factor, self.b * factor, self.c * factor, self.alpha, self.beta, self.gamma)
+    }
+
+    /// The box vectors as rows.
+    pub fn matrix(&self) -> [[f64; 3]; 3] {
+        self.matrix
+    }
+
+    pub fn inverse(&self) -> [[f64; 3]; 3] {
+        self.inverse
+    }
+
+    /// Parse columns 7-54 of a CRYST1 record. The 1 Å cube that NMR entries carry
+    /// as a placeholder gives `None`.
+    pub fn from_cryst1(values: [f64; 6]) -> Result<Option<Self>, UnitCellError> {
+        let [a, b, c, alpha, beta, gamma] = values;
+        if a <= 1.0 && b <= 1.0 && c <= 1.0 {
+            return Ok(None);
+        }
+        UnitCell::new(a, b, c, alpha, beta, gamma).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn degenerate_cells_and_bonds_are_errors() {
+        assert_eq!(UnitCell::new(0.0, 10.0, 10.0, 90.0, 90.0, 90.0), Err(UnitCellError::DegenerateEdge(0.0)));
+        assert_eq!(UnitCell::new(10.0, 10.0, 10.0, 90.0, 90.0, 180.0), Err(UnitCellError::DegenerateAngles(90.0, 90.0, 180.0)));
+        assert_eq!(UnitCell::new(10.0, 10.0, 10.0, 0.0, 90.0, 90.0), Err(UnitCellError::DegenerateAngles(0.0, 90.0, 90.0)));
+        // Three angles of 120 degrees lie flat
+        assert!(UnitCell::new(10.0, 10.0, 10.0, 120.0, 120.0, 120.0).is_err());
+
+        let cell = UnitCell::new(10.0, 10.0, 10.0, 60.0, 60.0, 90.0).unwrap();
+        let unwrapped = cell.unwrap(vec![(0.5, 0.5, 0.5), (9.5, 0.5, 0.5)], vec![(0, 1)]).unwrap();
+        assert!((cell.distance(unwrapped[0], unwrapped[1]) - 1.0).abs() < 1e-9);
+        assert_eq!(cell.unwrap(vec![(0.5, 0.5, 0.5)], vec![(0, 1)]), Err(UnitCellError::InvalidBond(0, 1)));
+    }
+}
@@ -1,12 +1,11 @@
 use std::collections::{HashMap};
 use serde::{Deserialize, Serialize};
-use std::fs::File;
-use std::io::Read;
 
 use pyo3::prelude::*;
 use pyo3::types::PyDict;
 
-use crate::utilities::{get_data_path};
+use crate::utilities::element::periodic_table;
+use crate::utilities::{get_data_file_path, load_yaml, DataCache, DataError};
 
 // Define the atom properties as structs
 #[derive(Serialize, Deserialize, Debug)]
@@ -14,6 +13,9 @@ pub struct AtomProperties {
     color: (f32, f32, f32),
     radius: f32,
     valence: usize,
+    /// Mass in amu. Entries without one take their element's standard atomic weight.
+    #[serde(default)]
+    pub mass: Option<f64>,
 }
 
 impl ToPyObject for AtomProperties {
@@ -22,26 +24,85 @@ impl ToPyObject for AtomProperties {
         dict.set_item("color", self.color.to_object(py)).unwrap();
         dict.set_item("radius", self.radius.to_object(py)).unwrap();
         dict.set_item("valence", self.valence.to_object(py)).unwrap();
+        dict.set_item("mass", self.mass.to_object(py)).unwrap();
         dict.into()
     }
 }
 
 
 
-lazy_static::lazy_static! {
-    pub(crate) static ref ATOM_PROPERTIES: HashMap<String, AtomProperties> = load_atom_data();
+static ATOM_PROPERTIES: DataCache<HashMap<String, AtomProperties>> = DataCache::new(load_atom_data);
+
+/// `atom_properties.yml`, loaded on first use.
+pub(crate) fn atom_properties() -> Result<&'static HashMap<String, AtomProperties>, DataError> {
+    ATOM_PROPERTIES.get()
+}
+
+/// The mass of `symbol` in amu: its `atom_properties.yml` entry's where it has one, and
+/// otherwise its element's standard atomic weight from the periodic table.
+pub(crate) fn atom_mass(symbol: &str) -> Result<Option<f64>, DataError> {
+    match atom_properties()?.get(symbol).and_then(|p| p.mass) {
+        Some(mass) => Ok(Some(mass)),
+        None => Ok(periodic_table()?.get(symbol).map(|e| e.mass)),
+    }
+}
+
+pub(crate) fn get_atom_properties_path() -> Option<String> {
+    get_data_file_path("atom_properties.yml")
+}
+
+/// Loads `atom_properties.yml`, filling in the mass of entries without one from the periodic table.
+pub(crate) fn load_atom_data() -> Result<HashMap<String, AtomProperties>, DataError> {
+    let mut atom_data: HashMap<String, AtomProperties> =
+        load_yaml("atom_properties.yml", include_str!("../../data/atom_properties.yml"))?;
+    let table = periodic_table()?;
+    for (symbol, properties) in atom_data.iter_mut() {
+        properties.mass = properties.mass.or_else(|| table.get(symbol).map(|e| e.mass));
+    }
+    Ok(atom_data)
 }
-pub(crate) fn get_atom_properties_path() -> String {
-    let data_path = get_data_path();
-    format!("{}/atom_properties.yml", data_path)
+/// Element symbols interned to small integer IDs, so per-pair lookups can index a table
+/// instead of formatting and hashing strings.
+pub(crate) struct ElementIds {
+    /// The ID of each atom's element.
+    pub ids: Vec<usize>,
+    /// The symbol for each ID.
+    pub symbols: Vec<String>,
 }
 
-pub(crate) fn load_atom_data() -> HashMap<String, AtomProperties> {
-    let path = get_atom_properties_path();
-    println!("Loading atom data from: {}", path);
-    let mut file = File::open(path).unwrap();
-    let mut contents = String::new();
-    file.read_to_string(&mut contents).unwrap();
-    let data: HashMap<String, AtomProperties> = serde_yaml::from_str(&contents).unwrap();
-    data
-}
\ No newline at end of file
+impl ElementIds {
+    pub fn new(atom_types: &[String]) -> Self {
+        let mut index: HashMap<&str, usize> = HashMap::new();
+        let mut symbols = Vec::new();
+        let ids = atom_types
+            .iter()
+            .map(|t| {
+                *index.entry(t.as_str()).or_insert_with(|| {
+                    symbols.push(t.clone());
+                    symbols.len() - 1
+                })
+            })
+            .collect();
+        ElementIds { ids, symbols }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn masses_fall_back_to_the_periodic_table() {
+        for (symbol, mass) in [("C", 12.011), ("P", 30.974), ("Mg", 24.305), ("Zn", 65.38), ("Fe", 55.845), ("Se", 78.971)] {
+            assert!((atom_mass(symbol).unwrap().unwrap() - mass).abs() < 0.01, "{}", symbol);
+        }
+        assert_eq!(atom_mass("EP").unwrap(), Some(0.0));
+        assert_eq!(atom_mass("Xx").unwrap(), None);
+    }
+
+    #[test]
+    fn entries_without_a_mass_still_parse() {
+        let properties: AtomProperties = serde_yaml::from_str("{color: [0.5, 0.5, 0.5], radius: 1.1, valence: 5}").unwrap();
+        assert_eq!(properties.mass, None);
+    }
+}
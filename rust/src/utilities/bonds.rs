@@ -1,11 +1,9 @@
 use std::collections::{HashMap};
-use std::fs::File;
-use std::io::Read;
 use serde::{Deserialize, Serialize};
 use pyo3::prelude::*;
 use pyo3::types::PyDict;
 
-use crate::utilities::{get_data_path};
+use crate::utilities::{get_data_file_path, load_yaml, DataCache, DataError};
 
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -23,30 +21,62 @@ impl ToPyObject for BondDistances {
     }
 }
 
-lazy_static::lazy_static! {
-    pub(crate) static ref BOND_DISTANCES: HashMap<String, (f64, f64)> = load_bond_data();
-    pub(crate) static ref AVG_BOND_DISTANCES: HashMap<String, f64> = {
-        let mut avg_bond_distances = HashMap::new();
-        for (k, (min, max)) in BOND_DISTANCES.iter() {
-            avg_bond_distances.insert(k.clone(), (min + max) / 2.0);
-        }
-        avg_bond_distances
-    };
+/// `bond_distances.yml`, with what is derived from it.
+pub(crate) struct BondTable {
+    /// The shortest and longest length of each `A-B` bond in Å.
+    pub distances: HashMap<String, (f64, f64)>,
+    /// The midpoint of each range.
+    pub averages: HashMap<String, f64>,
+    /// The longest bond in the table, and so the furthest apart two atoms can be and still bond.
+    pub max_distance: f64,
+}
+
+static BOND_TABLE: DataCache<BondTable> = DataCache::new(|| {
+    load_bond_data().map(|distances| {
+        let averages = distances.iter().map(|(k, (min, max))| (k.clone(), (min + max) / 2.0)).collect();
+        let max_distance = distances.values().map(|(_, max)| *max).fold(0.0, f64::max);
+        BondTable { distances, averages, max_distance }
+    })
+});
+
+/// `bond_distances.yml`, loaded on first use.
+pub(crate) fn bond_table() -> Result<&'static BondTable, DataError> {
+    BOND_TABLE.get()
+}
+
+/// Look up an `A-B` element pair in a table keyed either way round.
+pub(crate) fn pair_entry<'a, T>(table: &'a HashMap<String, T>, a: &str, b: &str) -> Option<&'a T> {
+    table.get(&format!("{}-{}", a, b)).or_else(|| table.get(&format!("{}-{}", b, a)))
 }
 
-pub(crate) fn get_bond_distances_path() -> String {
-    let data_path = get_data_path();
-    format!("{}/bond_distances.yml", data_path)
+/// A symmetric table over interned element IDs, filled once per element pair.
+pub(crate) struct PairTable<T> {
+    size: usize,
+    values: Vec<Option<T>>,
 }
 
+impl<T: Copy> PairTable<T> {
+    pub fn new(symbols: &[String], lookup: impl Fn(&str, &str) -> Option<T>) -> Self {
+        let size = symbols.len();
+        let mut values = Vec::with_capacity(size * size);
+        for a in symbols {
+            for b in symbols {
+                values.push(lookup(a, b));
+            }
+        }
+        PairTable { size, values }
+    }
+
+    pub fn get(&self, a: usize, b: usize) -> Option<T> {
+        self.values[a * self.size + b]
+    }
+}
 
-pub(crate) fn load_bond_data() -> HashMap<String, (f64, f64)> {
-    let path = get_bond_distances_path();
-    println!("Loading bond data from: {}", path);
-    let mut file = File::open(path).unwrap();
-    let mut contents = String::new();
-    file.read_to_string(&mut contents).unwrap();
-    let data: BondDistances = serde_yaml::from_str(&contents).unwrap();
-    data.bond_distances
+pub(crate) fn get_bond_distances_path() -> Option<String> {
+    get_data_file_path("bond_distances.yml")
 }
 
+pub(crate) fn load_bond_data() -> Result<HashMap<String, (f64, f64)>, DataError> {
+    let data: BondDistances = load_yaml("bond_distances.yml", include_str!("../../data/bond_distances.yml"))?;
+    Ok(data.bond_distances)
+}
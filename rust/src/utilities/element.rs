@@ -0,0 +1,119 @@
+use std::collections::{BTreeMap, HashMap};
+use serde::Deserialize;
+
+use pyo3::prelude::*;
+
+use crate::utilities::{get_data_file_path, load_yaml, DataCache, DataError};
+
+/// An element of the periodic table, as `elements.yml` describes it.
+#[pyclass(frozen)]
+#[derive(Deserialize, Debug, Clone)]
+pub struct Element {
+    #[pyo3(get)]
+    pub symbol: String,
+    #[pyo3(get)]
+    pub name: String,
+    #[pyo3(get)]
+    pub atomic_number: u32,
+    /// Standard atomic weight in amu.
+    #[pyo3(get)]
+    pub mass: f64,
+    /// Single-bond covalent radius in Å.
+    #[pyo3(get)]
+    pub covalent_radius: f64,
+    /// Van der Waals radius in Å, if one has been determined.
+    #[pyo3(get)]
+    pub vdw_radius: Option<f64>,
+    /// Ionic radii in Å, keyed by the ion's charge.
+    #[pyo3(get)]
+    pub ionic_radii: BTreeMap<i32, f64>,
+    /// Pauling electronegativity.
+    #[pyo3(get)]
+    pub electronegativity: Option<f64>,
+    /// Common valences, the most usual first.
+    #[pyo3(get)]
+    pub valences: Vec<u32>,
+    /// CPK color as RGB in [0, 1].
+    #[pyo3(get)]
+    pub color: (f32, f32, f32),
+}
+
+#[pymethods]
+impl Element {
+    fn __repr__(&self) -> String {
+        format!("Element(symbol='{}', atomic_number={})", self.symbol, self.atomic_number)
+    }
+}
+
+#[derive(Deserialize)]
+struct ElementsFile {
+    elements: Vec<Element>,
+}
+
+/// An element as Python names it, by symbol or by atomic number.
+#[derive(FromPyObject)]
+pub(crate) enum ElementKey {
+    Number(u32),
+    Symbol(String),
+}
+
+/// Every element, by atomic number and by symbol.
+#[derive(Debug)]
+pub struct PeriodicTable {
+    elements: Vec<Element>,
+    symbols: HashMap<String, usize>,
+}
+
+impl PeriodicTable {
+    pub fn get(&self, symbol: &str) -> Option<&Element> {
+        self.symbols.get(symbol).map(|&i| &self.elements[i])
+    }
+
+    pub fn by_number(&self, atomic_number: u32) -> Option<&Element> {
+        self.elements.iter().find(|e| e.atomic_number == atomic_number)
+    }
+
+    pub(crate) fn lookup(&self, key: &ElementKey) -> Option<&Element> {
+        match key {
+            ElementKey::Number(n) => self.by_number(*n),
+            ElementKey::Symbol(s) => self.get(s),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Element> {
+        self.elements.iter()
+    }
+}
+
+static PERIODIC_TABLE: DataCache<PeriodicTable> = DataCache::new(load_element_data);
+
+/// `elements.yml`, loaded on first use.
+pub(crate) fn periodic_table() -> Result<&'static PeriodicTable, DataError> {
+    PERIODIC_TABLE.get()
+}
+
+pub(crate) fn get_elements_path() -> Option<String> {
+    get_data_file_path("elements.yml")
+}
+
+pub(crate) fn load_element_data() -> Result<PeriodicTable, DataError> {
+    let file: ElementsFile = load_yaml("elements.yml", include_str!("../../data/elements.yml"))?;
+    let symbols = file.elements.iter().enumerate().map(|(i, e)| (e.symbol.clone(), i)).collect();
+    Ok(PeriodicTable { elements: file.elements, symbols })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn table_covers_every_element_once() {
+        let table = periodic_table().unwrap();
+        let numbers: Vec<u32> = table.iter().map(|e| e.atomic_number).collect();
+        assert_eq!(numbers, (1..=118).collect::<Vec<_>>());
+        assert_eq!(table.symbols.len(), 118);
+        assert_eq!(table.get("No").map(|e| e.atomic_number), Some(102));
+        assert_eq!(table.lookup(&ElementKey::Number(26)).map(|e| e.symbol.as_str()), Some("Fe"));
+        assert!(table.iter().all(|e| e.mass > 0.0 && e.covalent_radius > 0.0));
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::utilities::{get_data_file_path, load_yaml, DataCache, DataError};
+
+/// How unlike Lennard-Jones pairs combine their sigmas. Epsilons always take the geometric mean.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mixing {
+    LorentzBerthelot,
+    Geometric,
+}
+
+/// Lennard-Jones sigma in Å and epsilon in kcal/mol, and charge in e.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct NonbondedParams {
+    pub sigma: f64,
+    pub epsilon: f64,
+    pub charge: f64,
+}
+
+/// How a bond is keyed in `forcefield.yml`, from its perceived order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BondOrder {
+    Single,
+    Double,
+    Triple,
+    /// Aromatic, or a single bond conjugated with a neighbouring double bond.
+    Delocalized,
+}
+
+impl BondOrder {
+    /// The separator of the bond's elements in keys, e.g. `C=O`.
+    pub fn symbol(self) -> &'static str {
+        match self {
+            BondOrder::Single => "-",
+            BondOrder::Double => "=",
+            BondOrder::Triple => "#",
+            BondOrder::Delocalized => ":",
+        }
+    }
+}
+
+/// A harmonic bond. Without `r0` the bond is held at its starting length.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct BondParams {
+    pub k: f64,
+    pub r0: Option<f64>,
+}
+
+/// A harmonic angle in degrees. Without `theta0` the angle takes the reference angle for the
+/// hybridization of its centre, or failing that is held at its starting value.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct AngleParams {
+    pub k: f64,
+    pub theta0: Option<f64>,
+}
+
+/// A periodic torsion in degrees. Without `phi0` one of its minima sits at the starting dihedral.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct TorsionParams {
+    pub k: f64,
+    pub n: u32,
+    pub phi0: Option<f64>,
+}
+
+/// Parameters keyed by element, e.g. `Na`, `C-H`, `C=O`, `H-C-H` and `X-C:C-X`, where `X` is any
+/// element and bonds are separated by the symbol of their [`BondOrder`], and partial charges
+/// keyed by residue and then atom name.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ForceField {
+    pub mixing: Mixing,
+    pub nonbonded: HashMap<String, NonbondedParams>,
+    pub bonds: HashMap<String, BondParams>,
+    /// Reference angles in degrees keyed by the centre's hybridization: `sp`, `sp2` or `sp3`.
+    #[serde(default)]
+    pub hybridization_angles: HashMap<String, f64>,
+    pub angles: HashMap<String, AngleParams>,
+    pub torsions: HashMap<String, TorsionParams>,
+    #[serde(default)]
+    pub charges: HashMap<String, HashMap<String, f64>>,
+}
+
+// `elements` joined by `separators`, e.g. `X-C:C-X`.
+fn key(elements: &[&str], separators: &[&str]) -> String {
+    let mut key = elements[0].to_string();
+    for (element, separator) in elements[1..].iter().zip(separators) {
+        key.push_str(separator);
+        key.push_str(element);
+    }
+    key
+}
+
+/// Look a key up either way round, trying each element pattern in turn with the elements
+/// joined by `separators`.
+fn lookup<T: Copy>(table: &HashMap<String, T>, patterns: &[&[&str]], separators: &[&str]) -> Option<T> {
+    let reversed_separators: Vec<&str> = separators.iter().rev().copied().collect();
+    patterns.iter().find_map(|elements| {
+        let reversed: Vec<&str> = elements.iter().rev().copied().collect();
+        table.get(&key(elements, separators)).or_else(|| table.get(&key(&reversed, &reversed_separators))).copied()
+    })
+}
+
+impl ForceField {
+    pub fn nonbonded(&self, element: &str) -> Option<NonbondedParams> {
+        self.nonbonded.get(element).or_else(|| self.nonbonded.get("X")).copied()
+    }
+
+    pub fn bond(&self, a: &str, b: &str, order: BondOrder) -> Option<BondParams> {
+        lookup(&self.bonds, &[&[a, b], &["X", "X"]], &[order.symbol()])
+    }
+
+    /// Parameters for the angle a-b-c centred on `b`.
+    pub fn angle(&self, a: &str, b: &str, c: &str) -> Option<AngleParams> {
+        lookup(&self.angles, &[&[a, b, c], &["X", b, c], &[a, b, "X"], &["X", b, "X"], &["X", "X", "X"]], &["-", "-"])
+    }
+
+    /// Parameters for the torsion about the b-c bond of the given `order`.
+    pub fn torsion(&self, a: &str, b: &str, c: &str, d: &str, order: BondOrder) -> Option<TorsionParams> {
+        let separators = ["-", order.symbol(), "-"];
+        lookup(&self.torsions, &[&[a, b, c, d], &["X", b, c, "X"], &["X", "X", "X", "X"]], &separators)
+    }
+}
+
+static FORCE_FIELD: DataCache<ForceField> = DataCache::new(load_force_field);
+
+/// `forcefield.yml`, loaded on first use.
+pub(crate) fn force_field() -> Result<&'static ForceField, DataError> {
+    FORCE_FIELD.get()
+}
+
+pub(crate) fn get_force_field_path() -> Option<String> {
+    get_data_file_path("forcefield.yml")
+}
+
+pub(crate) fn load_force_field() -> Result<ForceField, DataError> {
+    load_yaml("forcefield.yml", include_str!("../../data/forcefield.yml"))
+}
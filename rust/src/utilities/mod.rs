@@ -1,59 +1,186 @@
+use std::borrow::Cow;
 use std::env;
+use std::fmt;
+use std::sync::OnceLock;
+use pyo3::exceptions::{PyKeyError, PyValueError};
 use pyo3::prelude::*;
 use pyo3::types::PyDict;
+use serde::de::DeserializeOwned;
 
 pub mod atom;
+pub mod element;
 pub mod shader;
 pub mod bonds;
+pub mod forcefield;
+pub mod residues;
+pub mod water;
 
 
 use bonds::{load_bond_data, get_bond_distances_path};
 use atom::{load_atom_data, get_atom_properties_path};
+use element::{get_elements_path, periodic_table, Element, ElementKey};
+use forcefield::get_force_field_path;
+use residues::{get_residues_path, residue_templates};
+
+/// The directory `AMPHI_DATA_PATH` names, if it is set. Data files are read from there
+/// instead of the copies built into the library.
+pub(crate) fn get_data_path() -> Option<String> {
+    env::var("AMPHI_DATA_PATH").ok()
+}
 
-pub(crate) fn get_data_path() -> String {
-    // append bond_distances.yml to the data path
-     env::var("AMPHI_DATA_PATH").unwrap_or_else(|_| "rust/data".to_string())
+/// The path a data file is read from, or `None` when the built-in copy is used.
+pub(crate) fn get_data_file_path(name: &str) -> Option<String> {
+    get_data_path().map(|data_path| format!("{}/{}", data_path, name))
 }
 
+pyo3::create_exception!(rustquantic, DataParseError, PyValueError, "A data file could not be parsed.");
+
+#[derive(Debug, Clone)]
+pub enum DataError {
+    Io { path: String, message: String },
+    Parse { source: String, message: String },
+}
 
+impl fmt::Display for DataError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DataError::Io { path, message } => write!(f, "Failed to read {}: {}", path, message),
+            DataError::Parse { source, message } => write!(f, "Failed to parse {}: {}", source, message),
+        }
+    }
+}
 
+impl std::error::Error for DataError {}
+
+impl From<DataError> for PyErr {
+    fn from(err: DataError) -> PyErr {
+        match err {
+            DataError::Io { .. } => pyo3::exceptions::PyIOError::new_err(err.to_string()),
+            DataError::Parse { .. } => DataParseError::new_err(err.to_string()),
+        }
+    }
+}
+
+/// The data file `name`, read under `AMPHI_DATA_PATH` when it is set and otherwise the
+/// `embedded` copy from the library's own `data` directory.
+pub(crate) fn read_data_file(name: &str, embedded: &'static str) -> Result<Cow<'static, str>, DataError> {
+    match get_data_file_path(name) {
+        Some(path) => {
+            log::info!("Loading {} from: {}", name, path);
+            std::fs::read_to_string(&path)
+                .map(Cow::Owned)
+                .map_err(|e| DataError::Io { path, message: e.to_string() })
+        }
+        None => Ok(Cow::Borrowed(embedded)),
+    }
+}
+
+/// A data table loaded on first use. A load that fails is not kept, so the next use
+/// tries again, and fixing a broken file under `AMPHI_DATA_PATH` needs no restart.
+pub(crate) struct DataCache<T> {
+    value: OnceLock<T>,
+    load: fn() -> Result<T, DataError>,
+}
+
+impl<T> DataCache<T> {
+    pub const fn new(load: fn() -> Result<T, DataError>) -> Self {
+        DataCache { value: OnceLock::new(), load }
+    }
+
+    pub fn get(&self) -> Result<&T, DataError> {
+        if let Some(value) = self.value.get() {
+            return Ok(value);
+        }
+        let value = (self.load)()?;
+        Ok(self.value.get_or_init(|| value))
+    }
+}
+
+/// Parse the YAML data file `name` as `read_data_file` finds it.
+pub(crate) fn load_yaml<T: DeserializeOwned>(name: &str, embedded: &'static str) -> Result<T, DataError> {
+    let contents = read_data_file(name, embedded)?;
+    serde_yaml::from_str(&contents).map_err(|e| DataError::Parse {
+        source: get_data_file_path(name).unwrap_or_else(|| format!("built-in {}", name)),
+        message: e.to_string(),
+    })
+}
 
 
 #[pymodule]
 pub(crate) fn utilities(_py: Python, m: Bound<PyModule>) -> PyResult<()> {
     #[pyfn(m, name = "get_data_path")]
-    fn get_data_path_py(_py: Python) -> String {
+    fn get_data_path_py(_py: Python) -> Option<String> {
         get_data_path()
     }
 
     #[pyfn(m, name = "get_bond_distances_path")]
-    fn get_bond_distances_path_py(_py: Python) -> String {
+    fn get_bond_distances_path_py(_py: Python) -> Option<String> {
         get_bond_distances_path()
     }
 
     #[pyfn(m, name = "get_atom_properties_path")]
-    fn get_atom_properties_path_py(_py: Python) -> String {
+    fn get_atom_properties_path_py(_py: Python) -> Option<String> {
         get_atom_properties_path()
     }
 
+    #[pyfn(m, name = "get_force_field_path")]
+    fn get_force_field_path_py(_py: Python) -> Option<String> {
+        get_force_field_path()
+    }
+
+    #[pyfn(m, name = "get_residues_path")]
+    fn get_residues_path_py(_py: Python) -> Option<String> {
+        get_residues_path()
+    }
+
+    /// The chemical name of every residue with a bonding template, by residue name.
+    #[pyfn(m, name = "load_residues")]
+    fn load_residues_py(_py: Python) -> PyResult<Py<PyDict>> {
+        let dict = PyDict::new_bound(_py);
+        for (code, template) in residue_templates()?.iter() {
+            dict.set_item(code, &template.name)?;
+        }
+        Ok(dict.into())
+    }
+
     #[pyfn(m, name = "load_bond_distances")]
-    fn load_bond_data_py(_py: Python) -> Py<PyDict> {
-        let bond_data = load_bond_data();
+    fn load_bond_data_py(_py: Python) -> PyResult<Py<PyDict>> {
+        let bond_data = load_bond_data()?;
         let dict = PyDict::new_bound(_py);
         for (key, value) in bond_data.iter() {
-            dict.set_item(key, value).unwrap();
+            dict.set_item(key, value)?;
         }
-        dict.into()
+        Ok(dict.into())
+    }
+
+    #[pyfn(m, name = "get_elements_path")]
+    fn get_elements_path_py(_py: Python) -> Option<String> {
+        get_elements_path()
+    }
+
+    /// The element with a symbol such as `"Fe"`, or an atomic number such as `26`.
+    #[pyfn(m, name = "get_element")]
+    fn get_element_py(_py: Python, key: ElementKey) -> PyResult<Element> {
+        periodic_table()?.lookup(&key).cloned().ok_or_else(|| match key {
+            ElementKey::Number(n) => PyKeyError::new_err(format!("No element with atomic number {}", n)),
+            ElementKey::Symbol(s) => PyKeyError::new_err(format!("No element with symbol '{}'", s)),
+        })
+    }
+
+    /// Every element, in order of atomic number.
+    #[pyfn(m, name = "load_elements")]
+    fn load_elements_py(_py: Python) -> PyResult<Vec<Element>> {
+        Ok(periodic_table()?.iter().cloned().collect())
     }
 
     #[pyfn(m, name = "load_atom_properties")]
-    fn load_atom_data_py(_py: Python) -> Py<PyDict> {
-        let atom_data = load_atom_data();
+    fn load_atom_data_py(_py: Python) -> PyResult<Py<PyDict>> {
+        let atom_data = load_atom_data()?;
         let dict = PyDict::new_bound(_py);
         for (key, value) in atom_data.iter() {
-            dict.set_item(key, value.to_object(_py)).unwrap();
+            dict.set_item(key, value.to_object(_py))?;
         }
-        dict.into()
+        Ok(dict.into())
     }
 
 
@@ -61,6 +188,25 @@ pub(crate) fn utilities(_py: Python, m: Bound<PyModule>) -> PyResult<()> {
     m.add_wrapped(wrap_pyfunction!(get_data_path_py))?;
     m.add_wrapped(wrap_pyfunction!(get_bond_distances_path_py))?;
     m.add_wrapped(wrap_pyfunction!(get_atom_properties_path_py))?;
+    m.add_class::<Element>()?;
 
     Ok(())
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn failed_loads_are_retried() {
+        static ATTEMPTS: AtomicUsize = AtomicUsize::new(0);
+        static TABLE: DataCache<usize> = DataCache::new(|| match ATTEMPTS.fetch_add(1, Ordering::SeqCst) {
+            0 => Err(DataError::Io { path: "table.yml".to_string(), message: "missing".to_string() }),
+            n => Ok(n),
+        });
+        assert!(TABLE.get().is_err());
+        assert_eq!(TABLE.get().unwrap(), &1);
+        assert_eq!(TABLE.get().unwrap(), &1);
+        assert_eq!(ATTEMPTS.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+use serde::Deserialize;
+
+use crate::utilities::{get_data_file_path, load_yaml, DataCache, DataError};
+
+/// How a polymer residue joins the next one in its chain.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Linking {
+    /// A peptide bond from this residue's `C` to the next one's `N`.
+    Peptide,
+    /// A phosphodiester bond from this residue's `O3'` to the next one's `P`.
+    Nucleic,
+}
+
+impl Linking {
+    /// The atom of this residue and the atom of the next that the link joins.
+    pub fn atoms(self) -> (&'static str, &'static str) {
+        match self {
+            Linking::Peptide => ("C", "N"),
+            Linking::Nucleic => ("O3'", "P"),
+        }
+    }
+}
+
+/// A bond of a template, between two of its atoms by name.
+#[derive(Debug, Clone)]
+pub struct TemplateBond {
+    pub atoms: (String, String),
+    pub order: u8,
+    pub aromatic: bool,
+}
+
+/// A residue as `residues.yml` describes it, after the Chemical Component Dictionary.
+#[derive(Debug, Clone)]
+pub struct ResidueTemplate {
+    pub name: String,
+    pub linking: Option<Linking>,
+    pub atoms: Vec<String>,
+    pub bonds: Vec<TemplateBond>,
+}
+
+#[derive(Deserialize)]
+struct ResidueEntry {
+    name: String,
+    #[serde(rename = "type")]
+    kind: String,
+    atoms: Vec<String>,
+    bonds: Vec<(String, String, String, String)>,
+}
+
+static RESIDUE_TEMPLATES: DataCache<HashMap<String, ResidueTemplate>> = DataCache::new(load_residue_data);
+
+/// `residues.yml`, keyed by residue name, loaded on first use.
+pub(crate) fn residue_templates() -> Result<&'static HashMap<String, ResidueTemplate>, DataError> {
+    RESIDUE_TEMPLATES.get()
+}
+
+pub(crate) fn get_residues_path() -> Option<String> {
+    get_data_file_path("residues.yml")
+}
+
+pub(crate) fn load_residue_data() -> Result<HashMap<String, ResidueTemplate>, DataError> {
+    let entries: HashMap<String, ResidueEntry> = load_yaml("residues.yml", include_str!("../../data/residues.yml"))?;
+    let invalid = |message: String| DataError::Parse {
+        source: get_residues_path().unwrap_or_else(|| "built-in residues.yml".to_string()),
+        message,
+    };
+
+    let mut templates = HashMap::new();
+    for (code, entry) in entries {
+        let linking = match entry.kind.as_str() {
+            "PEPTIDE LINKING" | "L-PEPTIDE LINKING" | "D-PEPTIDE LINKING" => Some(Linking::Peptide),
+            "DNA LINKING" | "RNA LINKING" => Some(Linking::Nucleic),
+            _ => None,
+        };
+        let mut bonds = Vec::with_capacity(entry.bonds.len());
+        for (a, b, order, aromatic) in entry.bonds {
+            let order = match order.as_str() {
+                "SING" => 1,
+                "DOUB" => 2,
+                "TRIP" => 3,
+                _ => return Err(invalid(format!("{}: unknown bond order '{}'", code, order))),
+            };
+            if !entry.atoms.contains(&a) || !entry.atoms.contains(&b) {
+                return Err(invalid(format!("{}: bond {}-{} names an atom the residue lacks", code, a, b)));
+            }
+            bonds.push(TemplateBond { atoms: (a, b), order, aromatic: aromatic == "Y" });
+        }
+        templates.insert(code, ResidueTemplate { name: entry.name, linking, atoms: entry.atoms, bonds });
+    }
+    Ok(templates)
+}
@@ -1,22 +1,21 @@
+use crate::utilities::{read_data_file, DataCache, DataError};
 
-use crate::utilities::get_data_path;
-
-pub fn get_shader_path() -> String {
-    let data_path = get_data_path();
-    format!("{}/shaders", data_path)
+/// The WGSL source of the relax, minimize and simulate kernels.
+pub(crate) struct Shaders {
+    pub relax: String,
+    pub minimize: String,
+    pub simulate: String,
 }
 
-lazy_static::lazy_static! {
-    pub(crate) static ref MINIMIZE_SHADER: String = {
-        let path = format!("{}/minimize.wgsl", get_shader_path());
-        std::fs::read_to_string(path).unwrap()
-    };
-    pub(crate) static ref SIMULATE_SHADER: String = {
-        let path = format!("{}/simulate.wgsl", get_shader_path());
-        std::fs::read_to_string(path).unwrap()
-    };
-    pub(crate) static ref RELAX_SHADER: String = {
-        let path = format!("{}/relax.wgsl", get_shader_path());
-        std::fs::read_to_string(path).unwrap()
-    };
-}
\ No newline at end of file
+static SHADERS: DataCache<Shaders> = DataCache::new(|| {
+    Ok(Shaders {
+        relax: read_data_file("shaders/relax.wgsl", include_str!("../../data/shaders/relax.wgsl"))?.into_owned(),
+        minimize: read_data_file("shaders/minimize.wgsl", include_str!("../../data/shaders/minimize.wgsl"))?.into_owned(),
+        simulate: read_data_file("shaders/simulate.wgsl", include_str!("../../data/shaders/simulate.wgsl"))?.into_owned(),
+    })
+});
+
+/// The shaders, loaded on first use.
+pub(crate) fn shaders() -> Result<&'static Shaders, DataError> {
+    SHADERS.get()
+}
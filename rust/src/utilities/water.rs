@@ -0,0 +1,36 @@
+use serde::{Deserialize, Serialize};
+
+use crate::utilities::{load_yaml, DataError};
+
+/// An atom or massless charge site of a water model.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WaterSite {
+    /// The PDB atom name, e.g. `H1`.
+    pub name: String,
+    pub element: String,
+}
+
+/// A periodic box of pre-equilibrated rigid water, from `data/water/<model>.yml`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WaterBox {
+    pub model: String,
+    pub residue: String,
+    pub sites: Vec<WaterSite>,
+    /// Bonds between sites, by their index in `sites`.
+    pub bonds: Vec<(usize, usize)>,
+    /// Edge lengths of the orthorhombic box in Å.
+    #[serde(rename = "box")]
+    pub size: [f64; 3],
+    /// The sites of each molecule in Å, in the order of `sites`.
+    pub molecules: Vec<Vec<[f64; 3]>>,
+}
+
+/// The box for `model`, one of `tip3p`, `spce` and `tip4p`.
+pub(crate) fn load_water_box(model: &str) -> Result<WaterBox, DataError> {
+    let embedded = match model {
+        "tip3p" => include_str!("../../data/water/tip3p.yml"),
+        "spce" => include_str!("../../data/water/spce.yml"),
+        _ => include_str!("../../data/water/tip4p.yml"),
+    };
+    load_yaml(&format!("water/{}.yml", model), embedded)
+}